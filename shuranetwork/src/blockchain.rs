@@ -2,10 +2,29 @@ use tokio::sync::Mutex;
 
 use blockchain_core::Chain;
 
+pub mod address;
 pub mod blockchain_core;
 pub mod blockchain_app;
 pub mod blockchain_tui;
 pub mod blockchain_rest;
+pub mod chain_error;
+pub mod chain_spec;
+pub mod events;
+pub mod explorer;
+pub mod export;
+pub mod light_client;
+#[cfg(test)]
+pub mod memory_store;
+pub mod merkle;
+pub mod metrics;
 pub mod peer_network;
+pub mod peer_registry;
+pub mod script;
+pub mod secure_transport;
+pub mod seen_cache;
+pub mod storage;
+#[cfg(test)]
+pub mod test_harness;
+pub mod verify;
 
-type SharedChain = std::sync::Arc<Mutex<Chain>>;
\ No newline at end of file
+pub type SharedChain = std::sync::Arc<Mutex<Chain>>;
\ No newline at end of file
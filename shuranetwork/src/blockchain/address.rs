@@ -0,0 +1,45 @@
+use secp256k1::PublicKey;
+use signing::Address;
+
+use super::chain_error::ChainError;
+
+// address of a public key, in the checksummed format of the signing crate
+pub fn derive_address(public_key: &PublicKey) -> String {
+    Address::from_public_key(public_key).to_string()
+}
+
+// spending from a script address needs the condition it hashes and a
+// witness satisfying it, see `script`
+pub fn is_script_address(address: &str) -> bool {
+    address.parse::<Address>().is_ok_and(|address| address.is_script())
+}
+
+// refuses anything but a valid address. a mistyped one fails its checksum,
+// and a name would hold coins that no key can spend
+pub fn check_address(address: &str) -> Result<(), ChainError> {
+    if !Address::looks_like_address(address) {
+        return Err(ChainError::Validation(format!("{address:?} is not an address")));
+    }
+    address.parse::<Address>().map_err(|e| ChainError::Validation(e.to_string()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catches_mistyped_addresses() {
+        let address = Address::from_hash([7; 20]).to_string();
+        assert!(check_address(&address).is_ok());
+        // names owned by no key are refused, whatever they look like
+        assert!(matches!(check_address("alcie"), Err(ChainError::Validation(_))));
+        assert!(!is_script_address(&address));
+        assert!(is_script_address(&Address::from_script_hash([7; 20]).to_string()));
+
+        let typo = address.replacen('q', "p", 1);
+        assert!(matches!(check_address(&typo), Err(ChainError::Validation(_))));
+        assert!(check_address(&address[..address.len() - 1]).is_err());
+        assert!(check_address(" ").is_err());
+    }
+}
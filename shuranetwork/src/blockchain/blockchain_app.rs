@@ -1,9 +1,45 @@
-use crate::blockchain::blockchain_core::Chain;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use local_ip_address::local_ip;
+use tracing::{debug, info, warn, Instrument};
+use crate::blockchain::SharedChain;
+use crate::blockchain::blockchain_core::{Chain, ChainConfig};
+use crate::blockchain::chain_error::ChainError;
+use crate::blockchain::chain_spec::ChainSpec;
+use crate::blockchain::peer_network::{Message, MessageReceiver, MessageSender, NodeConfig};
 use crate::blockchain::blockchain_tui;
 use crate::blockchain::blockchain_rest;
 use crate::utils::get_value;
 
-pub async fn blockchain_app() {
+pub struct AppConfig {
+    pub node: NodeConfig,
+    pub spec: ChainSpec,
+    // keep the bodies of only this many of the latest blocks
+    pub prune: Option<u32>,
+    // interface the REST server binds to
+    pub rest_bind_addr: IpAddr,
+    // address peers use to reach our REST server. defaults to the REST bind
+    // address, or the local ip when binding to all interfaces
+    pub advertise_addr: Option<IpAddr>,
+}
+
+impl AppConfig {
+    fn advertise_ip(&self) -> IpAddr {
+        match self.advertise_addr {
+            Some(addr) => addr,
+            None if self.rest_bind_addr.is_unspecified() => {
+                local_ip().expect("Failed to get the local ip. Use --advertise to set it")
+            }
+            None => self.rest_bind_addr,
+        }
+    }
+}
+
+pub async fn blockchain_app(config: AppConfig) {
 
     // port assigning
     let port_node = get_value("Enter port number for node: ");
@@ -11,41 +47,110 @@ pub async fn blockchain_app() {
     let port_node = port_node.parse::<u16>().unwrap();
     let port_server    = port_server.parse::<u16>().unwrap();
 
+    let rest_addr = SocketAddr::new(config.rest_bind_addr, port_server);
+    let advertise_addr = SocketAddr::new(config.advertise_ip(), port_server);
+
     // blockchain initialization
-    let chain = Chain::new(port_node, port_server).await;
-    let msg_incoming_rx = chain.msg_incoming_rx.clone();
+    let chain_config = ChainConfig { spec: config.spec, prune: config.prune, ..ChainConfig::default() };
+    let chain = Chain::new(port_node, advertise_addr.to_string(), config.node, chain_config).await;
+    let msg_incoming_rx = chain.node.take_receiver().await.expect("Incoming messages already taken");
     let msg_outgoing_tx = chain.msg_outgoing_tx.clone();
 
+    let span = chain.node.span();
     let chain = std::sync::Arc::new(tokio::sync::Mutex::new(chain));
 
 
     let chain_clone = chain.clone();
     tokio::spawn(async move {
-        blockchain_rest::blockchain_app_run(chain_clone, port_server).await.unwrap();
-    });
+        blockchain_rest::blockchain_app_run(chain_clone, rest_addr).await.unwrap();
+    }.instrument(span.clone()));
 
     let chain_clone = chain.clone();
-    tokio::spawn(async move {
-        // let mut chain = chain_clone.lock().await;
-        let reciever = msg_incoming_rx;
-        while let Ok(msg) = reciever.recv() {
-            println!("{msg:?}");
-            if let Some(block) = &msg.block {
-                let mut chain = chain_clone.lock().await;
-                chain.add_block(block.clone()).await;
-                println!("block recieved")
-            }
-            if let Some(transaction) = &msg.transaction {
-                let mut chain = chain_clone.lock().await;
-                chain.add_transaction(transaction.clone()).await;
-                println!("transaction recieved")
-            }
-            if let Err(e) = msg_outgoing_tx.send(msg) {
-                eprintln!("Cannot transmit the message to internal reciever: {e}")
+    tokio::spawn(run_gossip(chain_clone, msg_incoming_rx, msg_outgoing_tx).instrument(span.clone()));
+
+    let chain_clone = chain.clone();
+    blockchain_tui::blockchain_app_run(chain_clone).await.run_menu().instrument(span).await;
+}
+
+// how often orphan blocks from the same peer may start a sync
+const CATCH_UP_INTERVAL: Duration = Duration::from_secs(10);
+
+// applies the blocks and transactions recieved from peers and relays the
+// ones that were accepted, so invalid data stops at the first honest node
+pub async fn run_gossip(chain: SharedChain, mut reciever: MessageReceiver, msg_outgoing_tx: MessageSender) {
+    let mut last_catch_up: HashMap<String, Instant> = HashMap::new();
+    let catching_up = Arc::new(AtomicBool::new(false));
+    while let Some(msg) = reciever.recv().await {
+        debug!(from = %msg.uuid, kind = msg.kind(), "Gossip message recieved");
+        let mut accepted = true;
+        if let Some(block) = &msg.block {
+            let result = chain.lock().await.add_block(block.clone()).await;
+            accepted &= match result {
+                Ok(()) => true,
+                // the block may belong to a longer chain we have not seen yet.
+                // the sync runs beside the loop, one at a time, and relays the
+                // block if the chain it switched to holds it
+                Err(ChainError::Orphan(e)) => {
+                    last_catch_up.retain(|_, at| at.elapsed() < CATCH_UP_INTERVAL);
+                    if last_catch_up.contains_key(&msg.uuid) || catching_up.swap(true, Ordering::AcqRel) {
+                        debug!(error = %e, "Orphan block from gossip. Already caught up recently");
+                    } else {
+                        debug!(error = %e, "Orphan block from gossip. Catching up with the peers");
+                        last_catch_up.insert(msg.uuid.clone(), Instant::now());
+                        tokio::spawn(catch_up_and_relay(chain.clone(), msg.clone(), msg_outgoing_tx.clone(), catching_up.clone()));
+                    }
+                    false
+                }
+                Err(e) => {
+                    warn!(error = %e, "Rejected block from gossip");
+                    false
+                }
+            };
+        }
+        if let Some(transaction) = &msg.transaction {
+            let mut chain = chain.lock().await;
+            if let Err(e) = chain.add_transaction(transaction.clone()).await {
+                warn!(error = %e, "Rejected transaction from gossip");
+                accepted = false;
             }
         }
-    });
+        if !accepted {
+            continue;
+        }
+        if let Err(e) = msg_outgoing_tx.send(msg).await {
+            warn!(error = %e, "Cannot transmit the message to internal reciever")
+        }
+    }
+}
 
-    let chain_clone = chain.clone();
-    blockchain_tui::blockchain_app_run(chain_clone, port_node).await.run_menu().await;
+async fn catch_up_and_relay(chain: SharedChain, msg: Message, msg_outgoing_tx: MessageSender, catching_up: Arc<AtomicBool>) {
+    if let Err(e) = catch_up(&chain).await {
+        debug!(error = %e, "Nothing to catch up");
+    }
+    catching_up.store(false, Ordering::Release);
+    let held = match &msg.block {
+        Some(block) => chain.lock().await.get_header(&Chain::hash(&block.header)).is_ok(),
+        None => false,
+    };
+    if held {
+        if let Err(e) = msg_outgoing_tx.send(msg).await {
+            warn!(error = %e, "Cannot transmit the message to internal reciever")
+        }
+    }
+}
+
+// switch to a longer chain of a peer. the chain is only locked to read the
+// peers and to apply the result, never while waiting on the network
+pub async fn catch_up(chain: &SharedChain) -> Result<bool, ChainError> {
+    let (peers, (base, recent)) = {
+        let chain = chain.lock().await;
+        let peers = chain.node.peer_server_addr.lock().await.clone();
+        (peers, chain.recent_hashes()?)
+    };
+    let (from, blocks) = Chain::fetch_longest_chain(&peers, base, &recent).await?;
+    let replaced = chain.lock().await.replace_chain(from, blocks).await?;
+    if replaced {
+        info!("Caught up with a longer chain");
+    }
+    Ok(replaced)
 }
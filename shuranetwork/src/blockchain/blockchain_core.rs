@@ -1,24 +1,30 @@
-use crossbeam_channel::Receiver;
-use crossbeam_channel::Sender;
 use serde_derive::{Serialize, Deserialize};
-use rocksdb::Options;
+use secp256k1::{ecdsa::Signature, Message as SigningMessage, PublicKey, Secp256k1, SecretKey};
 use sha2::{Digest, Sha256};
 use uuid::Uuid;
-use core::str;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
 use crate::template;
 use crate::utils::get_value;
-use rocksdb::DB;
-use std::process::Command;
-use std::fs::File;
-use std::io::copy;
-use std::fs;
 use std::path::Path;
 use chrono::prelude::*;
-use super::peer_network::{Message, Node};
+use tracing::{debug, info, warn};
+use super::peer_network::{Message, MessageSender, Node, NodeConfig};
+use super::events::ChainEvent;
+use super::address::{check_address, derive_address, is_script_address};
+use super::chain_error::ChainError;
+use super::chain_spec::ChainSpec;
+use super::script::{Condition, KeySignature, ScriptContext, Spend};
+use super::storage::{ChainStore, RocksStore};
+use super::merkle::{merkle_proof, merkle_root, TransactionProof};
+use super::metrics::METRICS;
 use super::blockchain_rest::Len;
-use super::blockchain_rest::Msg;
+
+// sender of the mining reward transactions
+pub const MINT_ADDRESS: &str = "Root";
+
+// where the node keeps the chain, relative to the working directory
+pub const DB_PATH: &str = "amanah.db";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
@@ -26,6 +32,139 @@ pub struct Transaction {
     receiver: String,
     amount: f32,
     transaction_id: String,
+    // hex encoded compressed public key and compact ecdsa signature over
+    // `signing_hash`. required when the sender is a key address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    // condition and witness, required when the sender is a script address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    spend: Option<Spend>,
+}
+
+impl Transaction {
+    pub fn new(sender: String, receiver: String, amount: f32) -> Transaction {
+        Transaction {
+            sender,
+            receiver,
+            amount,
+            transaction_id: Uuid::new_v4().to_string(),
+            public_key: None,
+            signature: None,
+            spend: None,
+        }
+    }
+
+    // a transfer from the key address of `secret`, signed with it
+    pub fn new_signed(secret: &SecretKey, receiver: String, amount: f32) -> Transaction {
+        let secp = Secp256k1::signing_only();
+        let public = PublicKey::from_secret_key(&secp, secret);
+        let mut transaction = Transaction::new(derive_address(&public), receiver, amount);
+        let digest = SigningMessage::from_digest(transaction.signing_hash());
+        transaction.public_key = Some(hex::encode(public.serialize()));
+        transaction.signature = Some(hex::encode(secp.sign_ecdsa(&digest, secret).serialize_compact()));
+        transaction
+    }
+
+    // a transfer out of the script address of `condition`. the witness is
+    // filled in afterwards with `sign_witness` and `reveal_preimage`, possibly
+    // by several parties
+    pub fn new_script_spend(condition: Condition, receiver: String, amount: f32) -> Transaction {
+        let mut transaction = Transaction::new(condition.address(), receiver, amount);
+        transaction.spend = Some(Spend { condition, witness: Default::default() });
+        transaction
+    }
+
+    pub fn sign_witness(&mut self, secret: &SecretKey) {
+        let secp = Secp256k1::signing_only();
+        let digest = SigningMessage::from_digest(self.signing_hash());
+        let signature = KeySignature {
+            public_key: hex::encode(PublicKey::from_secret_key(&secp, secret).serialize()),
+            signature: hex::encode(secp.sign_ecdsa(&digest, secret).serialize_compact()),
+        };
+        if let Some(spend) = &mut self.spend {
+            spend.witness.signatures.push(signature);
+        }
+    }
+
+    pub fn reveal_preimage(&mut self, preimage: &[u8]) {
+        if let Some(spend) = &mut self.spend {
+            spend.witness.preimages.push(hex::encode(preimage));
+        }
+    }
+
+    // sha256 over everything but the signature
+    pub fn signing_hash(&self) -> [u8; 32] {
+        let fields = (&self.sender, &self.receiver, self.amount, &self.transaction_id);
+        Sha256::digest(serde_json::to_vec(&fields).expect("transaction serializes")).into()
+    }
+
+    // both ends are addresses, except the mint sending the rewards, which is
+    // checked with the block. spending from a key address needs a signature
+    // by that key, and from a script address a witness satisfying its
+    // condition in the block of `context`. the error comes with the reason
+    // label used for the rejected transactions metric
+    pub fn verify(&self, context: &ScriptContext) -> Result<(), (&'static str, ChainError)> {
+        if self.sender != MINT_ADDRESS {
+            check_address(&self.sender).map_err(|e| ("invalid_address", e))?;
+        }
+        check_address(&self.receiver).map_err(|e| ("invalid_address", e))?;
+        let invalid = |label, reason: &str| (label, ChainError::Validation(format!("transaction {}: {reason}", self.transaction_id)));
+        if !self.has_valid_amount() {
+            return Err(invalid("invalid_amount", &format!("cannot transfer {}", self.amount)));
+        }
+        if is_script_address(&self.sender) {
+            let Some(spend) = &self.spend else {
+                return Err(invalid("missing_condition", "spending from a script address without its condition"));
+            };
+            if spend.condition.address() != self.sender {
+                return Err(invalid("wrong_condition", "condition does not match the sender"));
+            }
+            let digest = SigningMessage::from_digest(self.signing_hash());
+            return spend.condition.evaluate(&digest, &spend.witness, context).map_err(|e| invalid("unsatisfied_condition", &e));
+        }
+        if self.sender == MINT_ADDRESS {
+            return Ok(());
+        }
+        let (Some(public_key), Some(signature)) = (&self.public_key, &self.signature) else {
+            return Err(invalid("missing_signature", "spending from a key address without a signature"));
+        };
+        let public_key = hex::decode(public_key).ok()
+            .and_then(|bytes| PublicKey::from_slice(&bytes).ok())
+            .ok_or_else(|| invalid("invalid_public_key", "malformed public key"))?;
+        if derive_address(&public_key) != self.sender {
+            return Err(invalid("wrong_public_key", "public key does not match the sender"));
+        }
+        let signature = hex::decode(signature).ok()
+            .and_then(|bytes| Signature::from_compact(&bytes).ok())
+            .ok_or_else(|| invalid("invalid_signature", "malformed signature"))?;
+        let digest = SigningMessage::from_digest(self.signing_hash());
+        Secp256k1::verification_only().verify_ecdsa(&digest, &signature, &public_key)
+            .map_err(|_| invalid("invalid_signature", "bad signature"))
+    }
+
+    // transfers move a positive amount. only the mint may move nothing, a
+    // reward that halved down to 0
+    fn has_valid_amount(&self) -> bool {
+        self.amount.is_finite() && (self.amount > 0.0 || (self.amount == 0.0 && self.sender == MINT_ADDRESS))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.transaction_id
+    }
+
+    pub fn sender(&self) -> &str {
+        &self.sender
+    }
+
+    pub fn receiver(&self) -> &str {
+        &self.receiver
+    }
+
+    pub fn amount(&self) -> f32 {
+        self.amount
+    }
 }
 
 #[derive(Serialize, Debug, Clone, Deserialize)]
@@ -37,6 +176,29 @@ pub struct Blockheader {
     difficulty: u32,
 }
 
+impl Blockheader {
+    // unix time in milliseconds
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn nonce(&self) -> u32 {
+        self.nonce
+    }
+
+    pub fn pre_hash(&self) -> &str {
+        &self.pre_hash
+    }
+
+    pub fn merkle(&self) -> &str {
+        &self.merkle
+    }
+
+    pub fn difficulty(&self) -> u32 {
+        self.difficulty
+    }
+}
+
 #[derive(Serialize, Debug, Clone, Deserialize)]
 pub struct Block {
     pub header: Blockheader,
@@ -44,24 +206,201 @@ pub struct Block {
     pub transactions: HashMap<String, Transaction>,
 }
 
+impl Block {
+    // the first block of the network described by `spec`. every node derives
+    // the same block, so it is neither mined nor sent around. it points at the
+    // spec hash, so networks differing in any parameter never share a block
+    pub fn genesis(spec: &ChainSpec) -> Block {
+        let transactions: HashMap<String, Transaction> = spec.premine.iter()
+            .map(|(address, amount)| Transaction {
+                sender: MINT_ADDRESS.to_string(),
+                receiver: address.clone(),
+                amount: *amount,
+                transaction_id: format!("genesis-{address}"),
+                public_key: None,
+                signature: None,
+                spend: None,
+            })
+            .map(|transaction| (transaction.transaction_id.clone(), transaction))
+            .collect();
+        let mut block = Block {
+            header: Blockheader {
+                timestamp: spec.genesis_timestamp,
+                nonce: 0,
+                pre_hash: spec.hash(),
+                merkle: String::new(),
+                difficulty: spec.difficulty,
+            },
+            count: transactions.len() as u32,
+            transactions,
+        };
+        block.header.merkle = Chain::get_merkle(block.sorted_transactions());
+        block
+    }
+
+    // transactions in merkle order
+    pub fn sorted_transactions(&self) -> Vec<&Transaction> {
+        let mut transactions: Vec<_> = self.transactions.values().collect();
+        transactions.sort_by(|a, b| a.transaction_id.cmp(&b.transaction_id));
+        transactions
+    }
+}
+
+// balances and confirmed transaction ids a block is applied to. reads fall
+// through to the database, if there is one, and the changes stay in memory
+#[derive(Default)]
+struct Ledger<'a> {
+    db: Option<&'a dyn ChainStore>,
+    balances: HashMap<String, f64>,
+    confirmed: HashSet<String>,
+    // confirmed in the database by a block that was reverted
+    reverted: HashSet<String>,
+}
+
+impl<'a> Ledger<'a> {
+    fn on(db: &'a dyn ChainStore) -> Self {
+        Ledger { db: Some(db), ..Ledger::default() }
+    }
+
+    fn balance(&self, address: &str) -> Result<f64, ChainError> {
+        match (self.balances.get(address), self.db) {
+            (Some(balance), _) => Ok(*balance),
+            (None, Some(db)) => db.balance(address),
+            (None, None) => Ok(0.0),
+        }
+    }
+
+    fn is_confirmed(&self, transaction_id: &str) -> Result<bool, ChainError> {
+        if self.confirmed.contains(transaction_id) {
+            return Ok(true);
+        }
+        if self.reverted.contains(transaction_id) {
+            return Ok(false);
+        }
+        match self.db {
+            Some(db) => Ok(db.transaction_block(transaction_id)?.is_some()),
+            None => Ok(false),
+        }
+    }
+
+    // the reward is applied first, then the transfers in merkle order, so a
+    // miner can spend its reward in the block that mints it
+    fn apply_block(&mut self, block: &Block) -> Result<(), (&'static str, ChainError)> {
+        let (mints, transfers): (Vec<_>, Vec<_>) = block.sorted_transactions().into_iter()
+            .partition(|transaction| transaction.sender == MINT_ADDRESS);
+        for transaction in mints.into_iter().chain(transfers) {
+            self.apply(transaction)?;
+        }
+        Ok(())
+    }
+
+    // undoes a block of the chain in the database, the last one first, so a
+    // fork can be applied on top of the block it forks from
+    fn revert_block(&mut self, block: &Block) -> Result<(), ChainError> {
+        for transaction in block.transactions.values() {
+            let amount = transaction.amount as f64;
+            if transaction.sender != MINT_ADDRESS {
+                let balance = self.balance(&transaction.sender)?;
+                self.balances.insert(transaction.sender.clone(), balance + amount);
+            }
+            let balance = self.balance(&transaction.receiver)?;
+            self.balances.insert(transaction.receiver.clone(), balance - amount);
+            self.confirmed.remove(&transaction.transaction_id);
+            self.reverted.insert(transaction.transaction_id.clone());
+        }
+        Ok(())
+    }
+
+    // nothing changes if the transaction is refused
+    fn apply(&mut self, transaction: &Transaction) -> Result<(), (&'static str, ChainError)> {
+        let storage = |e| ("storage", e);
+        let id = &transaction.transaction_id;
+        // a negative amount would move coins from the receiver to the sender
+        if !transaction.has_valid_amount() {
+            return Err(("invalid_amount", ChainError::Validation(format!("transaction {id} transfers {}", transaction.amount))));
+        }
+        if self.is_confirmed(id).map_err(storage)? {
+            return Err(("replayed", ChainError::Validation(format!("transaction {id} is already confirmed"))));
+        }
+        let amount = transaction.amount as f64;
+        if transaction.sender != MINT_ADDRESS {
+            let balance = self.balance(&transaction.sender).map_err(storage)?;
+            if balance < amount {
+                let reason = format!("transaction {id} spends {amount} of the {balance} held by {}", transaction.sender);
+                return Err(("insufficient_balance", ChainError::Validation(reason)));
+            }
+            self.balances.insert(transaction.sender.clone(), balance - amount);
+        }
+        let balance = self.balance(&transaction.receiver).map_err(storage)?;
+        self.balances.insert(transaction.receiver.clone(), balance + amount);
+        self.confirmed.insert(id.clone());
+        Ok(())
+    }
+}
+
+// how many of our last blocks are compared with a peer's chain to find where
+// it forks. a deeper fork is downloaded from the genesis block
+pub const FORK_SEARCH_DEPTH: u32 = 1000;
+
+// fewest blocks a pruned node keeps the bodies of. reorgs deeper than the
+// kept blocks cannot be applied
+pub const MIN_KEPT_BLOCKS: u32 = 16;
+
+// a block may not be stamped before the median of this many blocks before it
+pub const MEDIAN_TIME_SPAN: u32 = 11;
+// nor more than this many milliseconds ahead of our clock. time locks open
+// by the block timestamp, so a miner must not be able to move it forward
+pub const MAX_FUTURE_DRIFT: i64 = 2 * 60 * 60 * 1000;
+
+// median of the timestamps of the blocks before a new one, the earliest
+// timestamp it may have
+fn median_time(recent: &[i64]) -> i64 {
+    let mut recent = recent.to_vec();
+    recent.sort_unstable();
+    recent.get(recent.len() / 2).copied().unwrap_or(i64::MIN)
+}
+
+// chain parameters that are not stored in the db
+#[derive(Debug, Clone, Default)]
+pub struct ChainConfig {
+    pub spec: ChainSpec,
+    // asked for when the first block is mined if empty
+    pub miner_addr: String,
+    // keep the bodies of only this many of the latest blocks
+    pub prune: Option<u32>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncState {
+    // started from the local database or a new genesis block
+    Local,
+    // downloaded the chain from a peer at startup
+    Synced,
+    // a peer sync was attempted and failed
+    Failed,
+}
+
 pub struct Chain {
-    pub db: Option<DB>,
+    pub db: Option<Box<dyn ChainStore>>,
     height: u32,
     curr_trans: HashMap<String, Transaction>,
-    difficulty: u32,
     miner_addr: String,
-    reward: f32,
+    spec: ChainSpec,
+    genesis_hash: String,
+    prune: Option<u32>,
     pub uuid: Uuid,
     pub node: Node,
-    pub msg_incoming_rx: Receiver<Message>,
-    pub msg_outgoing_tx: Sender<Message>
+    pub msg_outgoing_tx: MessageSender,
+    pub sync_state: SyncState,
 }
 
 // can only create one instances of the struct
 impl Chain {
-    pub async fn new(port: u16, server_port: u16) -> Chain {
+    // `server_addr` is the REST address advertised to peers
+    pub async fn new(port: u16, server_addr: String, config: NodeConfig, chain_config: ChainConfig) -> Chain {
 
-        let node = Node::new(port,format!("127.0.0.1:{server_port}")).await;
+        let mut node = Node::new(port, server_addr, config).await;
 
         node.server_listen().await;
 
@@ -72,14 +411,14 @@ impl Chain {
 
         for peer in peers.lock().await.iter() {
             node.add_peer(peer.to_string()).await;
-            println!("peers = {}",peer);
+            debug!(%peer, "Peer added");
         }
 
-        let chain = Self::start_chain(node).await;
+        let chain = Self::start_chain(node, chain_config).await;
         chain
     }
 
-    async fn start_chain(node: Node) -> Chain {
+    async fn start_chain(node: Node, config: ChainConfig) -> Chain {
         let mut init_page = template::MenuBuilder::new();
         let node_clone = node.clone();
         init_page.add("1", "Sync chain", move || {
@@ -104,36 +443,49 @@ impl Chain {
         init_page.run_menu().await;
 
         // open the db and check the height
-        let db_path = "amanah.db";
-        let mut db_opts = Options::default();
-        db_opts.create_if_missing(true);
-        let db = DB::open(&db_opts, db_path).unwrap();
+        let db = Self::open_db().expect("Failed to open the chain db");
+        let chain = Chain::open(node.clone(), db, config).expect("Failed to read the chain db");
+
+        if chain.height <= 1 && !node.peer_server_addr.lock().await.is_empty() {
+            info!("Syncing the chain");
+            return Self::sync_chain(chain).await;
+        }
+        chain
+    }
 
+    // builds a chain on top of an existing store without any prompts or
+    // network sync. an empty store gets the genesis block of the spec
+    pub fn open(node: Node, db: Box<dyn ChainStore>, config: ChainConfig) -> Result<Chain, ChainError> {
+        let height = db.height()?;
+        let genesis = Block::genesis(&config.spec);
+        let genesis_hash = Chain::hash(&genesis.header);
         let mut chain = Chain {
             db: Some(db),
-            height: 0,
+            height,
             curr_trans: HashMap::new(),
-            difficulty: 2,
-            miner_addr: "".to_string(),
-            reward: 100.0,
+            miner_addr: config.miner_addr,
+            spec: config.spec,
+            genesis_hash,
+            prune: config.prune,
             uuid: node.get_id(),
-            node: node.clone(),
-            msg_incoming_rx: node.msg_incoming_rx.clone(),
-            msg_outgoing_tx: node.msg_outgoing_tx.clone()
+            msg_outgoing_tx: node.msg_outgoing_tx.clone(),
+            node,
+            sync_state: SyncState::Local,
         };
 
-        let height = chain.get_height().await;
-        if height <= 0 {
-            if node.peer_server_addr.lock().await.len() <= 0 {
-                chain.generate_new_block().await;
-            } else {
-                println!("Syncing the chain....");
-                return Self::sync_chain(chain).await;
-            }
-        } else {
-            chain.height = height;
+        if height == 0 {
+            let genesis_hash = chain.genesis_hash.clone();
+            chain.store_block(&genesis, &genesis_hash)?;
+            info!(%genesis_hash, network_id = %chain.spec.network_id(), "Created the genesis block");
+        } else if chain.db()?.get_hash(0)?.as_deref() != Some(chain.genesis_hash.as_str()) {
+            return Err(ChainError::Validation(format!(
+                "the database holds a chain of another network than {}. remove it or load its chain spec",
+                chain.spec.name,
+            )));
         }
-        chain
+        // a full database opened with pruning on is pruned right away
+        chain.prune_bodies()?;
+        Ok(chain)
     }
 
     async fn get_peers(peers: std::sync::Arc<tokio::sync::Mutex<Vec<String>>>) {
@@ -159,67 +511,127 @@ impl Chain {
         peer_page.run_menu().await;
     }
 
-    pub async fn copy_db_backup(&mut self) {
+    fn open_db() -> Result<Box<dyn ChainStore>, ChainError> {
+        Ok(Box::new(RocksStore::open(DB_PATH)?))
+    }
+
+    fn db(&self) -> Result<&dyn ChainStore, ChainError> {
+        self.db.as_deref().ok_or_else(|| ChainError::Storage("database is not open".to_string()))
+    }
+
+    fn db_mut(&mut self) -> Result<&mut Box<dyn ChainStore>, ChainError> {
+        self.db.as_mut().ok_or_else(|| ChainError::Storage("database is not open".to_string()))
+    }
+
+    pub async fn copy_db_backup(&mut self) -> Result<(), ChainError> {
+        self.db()?.flush()?;
         self.db = None;
-        Self::copy_dir_all(Path::new("amanah.db"), Path::new("backup/")).unwrap();
-        let db_path = "amanah.db";
-        let mut db_opts = Options::default();
-        db_opts.create_if_missing(true);
-        let db = DB::open(&db_opts, db_path).unwrap();
-        self.db = Some(db);
+        let copied = Self::copy_dir_all(Path::new(DB_PATH), Path::new("backup/"));
+        // reopen even if the copy failed
+        self.db = Some(Self::open_db()?);
+        copied
     }
 
+    // a failed sync leaves the local chain untouched
     async fn sync_chain(mut chain: Chain) -> Chain {
-        // open the db and check the height
+        match Self::try_sync(&mut chain).await {
+            Ok(()) => chain.sync_state = SyncState::Synced,
+            Err(e) => {
+                warn!(error = %e, "Sync failed");
+                chain.sync_state = SyncState::Failed;
+            }
+        }
+        chain
+    }
 
-        let mut height = chain.height;
-        let mut max_addr = String::new();
-        for addr in chain.node.peer_server_addr.lock().await.iter() {
-            let response = reqwest::get(format!("http://{addr}/len"))
-                .await
-                .unwrap()
-                .json::<Len>()
-                .await
-                .unwrap();
-            println!("{addr}: {:?} => len =  {:?}", response.uuid, response.len);
+    async fn try_sync(chain: &mut Chain) -> Result<(), ChainError> {
+        let peers = chain.node.peer_server_addr.lock().await.clone();
+        let (base, recent) = chain.recent_hashes()?;
+        let (from, blocks) = Self::fetch_longest_chain(&peers, base, &recent).await?;
+        chain.replace_chain(from, blocks).await?;
+        Ok(())
+    }
+
+    // download the chain of the peer with the most blocks if it has more than
+    // us, from the first block that is not ours. `recent` are our hashes from
+    // `base` on, see `recent_hashes`. returns the height of the first block
+    // downloaded. does not touch any chain, so callers need not hold a lock
+    pub async fn fetch_longest_chain(peers: &[String], base: u32, recent: &[String]) -> Result<(u32, Vec<Block>), ChainError> {
+        let mut height = base + recent.len() as u32;
+        let mut max_addr = None;
+        for addr in peers {
+            // a peer that is down is skipped
+            let response = match Self::fetch_len(addr).await {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!(%addr, error = %e, "Couldn't get the chain height of the peer");
+                    continue;
+                }
+            };
+            info!(%addr, peer_id = %response.uuid, height = response.len, pruned_height = response.pruned_height, "Peer chain height");
+            // the blocks after `base` may be needed, which a pruned peer may
+            // no longer have
+            if response.pruned_height > base {
+                continue;
+            }
             if height < response.len {
                 height = response.len;
-                max_addr = addr.clone();
+                max_addr = Some(addr.clone());
             }
         }
 
-        println!("max block heigh = {height}");
-
-        // archive the db
-        let response = reqwest::get(format!("http://{max_addr}/archive_db"))
-            .await
-            .unwrap()
-            .json::<Msg>()
-            .await
-            .unwrap();
-
-        if response.status == 400 {
-            println!("Sync failed");
-            return chain;
+        info!(height, "Max block height");
+        let max_addr = max_addr.ok_or_else(|| ChainError::Network("no unpruned peer has a longer chain".to_string()))?;
+
+        // every block is validated before it replaces the local chain. the
+        // advertised height is not trusted to size anything
+        let from = Self::fork_height(&max_addr, base, recent).await?;
+        let mut blocks = vec![];
+        while from + (blocks.len() as u32) < height {
+            let next = from + blocks.len() as u32;
+            let page = reqwest::get(format!("http://{max_addr}/blocks?from={next}&to={height}"))
+                .await?
+                .error_for_status()?
+                .json::<Vec<Block>>()
+                .await?;
+            if page.is_empty() {
+                return Err(ChainError::Network(format!("{max_addr} stopped sending blocks at {next}")));
+            }
+            blocks.extend(page);
         }
+        blocks.truncate((height - from) as usize);
+        info!(peer = %max_addr, from, blocks = blocks.len(), "Downloaded the chain");
+        Ok((from, blocks))
+    }
 
-        chain.db = None; // drop current db
-        let (db, height) = Self::download_and_extract(max_addr).await;
-        chain.height = height;
-        chain.db = Some(db); // drop current db
-        chain
+    // height of the first block of the peer at `addr` that is not ours, or 0
+    // if it forks before `base`
+    async fn fork_height(addr: &str, base: u32, recent: &[String]) -> Result<u32, ChainError> {
+        let to = base + recent.len() as u32;
+        let headers = reqwest::get(format!("http://{addr}/headers?from={base}&to={to}"))
+            .await?
+            .error_for_status()?
+            .json::<Vec<Blockheader>>()
+            .await?;
+        // a header commits to the ones before it, so the chains agree up to
+        // the last hash they share
+        let shared = headers.iter().zip(recent).rposition(|(header, hash)| Chain::hash(header) == *hash);
+        Ok(shared.map_or(0, |index| base + index as u32 + 1))
     }
 
+    async fn fetch_len(addr: &str) -> Result<Len, ChainError> {
+        Ok(reqwest::get(format!("http://{addr}/len")).await?.json::<Len>().await?)
+    }
 
-    fn copy_dir_all(src: &Path, dst: &Path) -> Result<(), &'static str> {
+    fn copy_dir_all(src: &Path, dst: &Path) -> Result<(), ChainError> {
         use std::fs;
         if src.is_dir() {
             // Create the destination directory if it doesn't exist
-            fs::create_dir_all(dst).unwrap();
+            fs::create_dir_all(dst)?;
 
             // Iterate over the contents of the source directory
-            for entry in fs::read_dir(src).unwrap() {
-                let entry = entry.unwrap();
+            for entry in fs::read_dir(src)? {
+                let entry = entry?;
                 let src_path = entry.path();
                 let dst_path = dst.join(entry.file_name());
 
@@ -229,133 +641,377 @@ impl Chain {
                 } else {
                     // Copy files
                     if let Err(e) = fs::copy(&src_path, &dst_path) {
-                        eprintln!("Erro while copying : {e}")
+                        warn!(error = %e, "Error while copying")
                     }
                 }
             }
         } else {
             // If the source is not a directory, just copy the file
             if let Err(e) = fs::copy(src, dst) {
-                eprintln!("Erro while copying : {e}")
+                warn!(error = %e, "Error while copying")
             }
         }
 
         Ok(())
     }
 
-   async fn download_and_extract(addr: String) -> (DB, u32) {
-        // let current_dir = env::current_dir().unwrap();
-        // let file_dir = format!("{}\\amanah.db\\",current_dir.display());
-        // println!("{:?}",current_dir);
+    // adds a transaction from a peer to the mempool. duplicates are ignored
+    pub async fn add_transaction(&mut self, transaction: Transaction) -> Result<(), ChainError> {
+        if self.curr_trans.contains_key(&transaction.transaction_id) {
+            METRICS.reject_transaction("duplicate");
+            return Ok(());
+        }
+        if let Err((reason, e)) = self.check_pending(&transaction) {
+            METRICS.reject_transaction(reason);
+            return Err(e);
+        }
+        self.curr_trans.insert(transaction.transaction_id.clone(), transaction.clone());
+        self.node.publish(ChainEvent::Transaction { transaction });
+        Ok(())
+    }
 
-        // delete the current db 
-        if let Err(e) = fs::remove_file("tmp/db.zip") {
-            eprintln!("Couldn't able to remove db : {e}");
+    // a pending transaction has to be valid in the next block already, so
+    // timelocked spends wait outside the mempool. the sender has to cover it
+    // with its confirmed balance, less what it already spends in the mempool
+    fn check_pending(&self, transaction: &Transaction) -> Result<(), (&'static str, ChainError)> {
+        if transaction.sender == MINT_ADDRESS {
+            return Err(("mint", ChainError::Validation(format!("transaction {} sends from the mint", transaction.transaction_id))));
         }
+        let context = ScriptContext { height: self.height, timestamp: Utc::now().timestamp_millis() };
+        transaction.verify(&context)?;
 
-        // create tmp dir
-        if let Err(e) = fs::create_dir("tmp") {
-            eprintln!("Couldn't able to create tmp directory: {e}");
+        let mut ledger = Ledger::on(self.db().map_err(|e| ("storage", e))?);
+        for pending in self.curr_trans.values().filter(|pending| pending.sender == transaction.sender) {
+            let spent = ledger.balance(&pending.sender).map_err(|e| ("storage", e))? - pending.amount as f64;
+            ledger.balances.insert(pending.sender.clone(), spent);
         }
-        {
-            // URL of the file
-            println!("Downloaded started.....");
-            let url = format!("http://{}/static/db.zip",addr);
+        ledger.apply(transaction)
+    }
+
+    // adds a transaction created on this node to the mempool and sends it to the peers
+    pub async fn submit_transaction(&mut self, transaction: Transaction) -> Result<(), ChainError> {
+        if self.curr_trans.contains_key(&transaction.transaction_id) {
+            return Err(ChainError::Validation(format!("transaction {} is already pending", transaction.transaction_id)));
+        }
+        self.add_transaction(transaction.clone()).await?;
+
+        let trans_hash = Chain::hash(&transaction);
+        if let Err(e) = self.node.msg_outgoing_tx.send(Message {
+            uuid: self.uuid.to_string(),
+            block: None,
+            transaction: Some(transaction),
+            message_hash: trans_hash,
+        }).await {
+            warn!(error = %e, "Cannot broadcast the transaction")
+        };
+        Ok(())
+    }
 
-            // Send GET request
-            let response = reqwest::get(url).await.unwrap();
+    pub async fn add_block(&mut self, block: Block) -> Result<(), ChainError> {
+        let started = std::time::Instant::now();
 
-            // Open a file to write the downloaded content
-            let mut file = File::create("tmp/db.zip").unwrap();
+        let block_hash = Chain::hash(&block.header);
+        debug!(%block_hash, transactions = block.count, "Adding block");
 
-            // Copy the content from the response to the file
-            let content = response.bytes().await.unwrap();
-            copy(&mut content.as_ref(), &mut file).unwrap();
-            std::mem::drop(file);
+        // blocks come back from the peers we relayed them to
+        if self.db()?.get_header(&block_hash)?.is_some() {
+            debug!(%block_hash, "Block already in the chain");
+            return Ok(());
         }
 
-        // delete the tmp/amanah.db
-        if let Err(e) = fs::remove_dir_all("tmp/backup") {
-            eprintln!("Couldn't able to remove the db: {e}");
+        let tip = self.last_hash().await?;
+        let recent = self.recent_timestamps()?;
+        let valid = Self::validate_block(&block, &block_hash, &tip, self.height, &self.spec, &recent)
+            .and_then(|()| Ledger::on(self.db().map_err(|e| ("storage", e))?).apply_block(&block));
+        if let Err((reason, e)) = valid {
+            warn!(%block_hash, error = %e, "Invalid block");
+            METRICS.reject_block(reason);
+            return Err(e);
         }
 
-        // extract the downloaded file
-        use std::io::{self, Write};
-        let output = Command::new("7z")
-            .args(["x", "tmp\\db.zip", "-otmp\\"])
-            .output().expect("Failed to execute the zip command");
+        if let Err(e) = self.store_block(&block, &block_hash) {
+            METRICS.reject_block("storage");
+            return Err(e);
+        }
 
-        io::stdout().write_all(&output.stdout).unwrap();
-        io::stderr().write_all(&output.stderr).unwrap();
+        for (id,_) in &block.transactions {
+            self.curr_trans.remove(id);
+        }
+        info!(%block_hash, height = self.height - 1, mempool = self.curr_trans.len(), "Block added");
+
+        METRICS.observe_block_processing(started.elapsed());
+        self.node.publish(ChainEvent::Block { height: self.height - 1, hash: block_hash, block });
+        Ok(())
+    }
 
-        // // delete the zip
-        if let Err(e) = fs::remove_file("tmp/db.zip") {
-            eprintln!("Couldn't able to remove the zip: {e}");
+    // checks a block at `height` against the hash of the block before it, the
+    // timestamps of the blocks before it and the rules of `spec`. the error
+    // comes with the reason label used for the rejected blocks metric
+    fn validate_block(block: &Block, block_hash: &str, pre_hash: &str, height: u32, spec: &ChainSpec, recent: &[i64]) -> Result<(), (&'static str, ChainError)> {
+        Chain::validate_header(&block.header, block_hash, pre_hash, spec.difficulty)?;
+        Chain::validate_timestamp(block.header.timestamp, block_hash, recent)?;
+        if block.count as usize != block.transactions.len() || block.transactions.is_empty() {
+            return Err(("invalid_count", ChainError::Validation(format!("block {block_hash} has a wrong transaction count"))));
         }
+        // the mempool is pruned by these keys, the ledger and storage go by the ids
+        if let Some((key, transaction)) = block.transactions.iter().find(|(key, t)| **key != t.transaction_id) {
+            let reason = format!("block {block_hash} files transaction {} under {key}", transaction.transaction_id);
+            return Err(("mismatched_id", ChainError::Validation(reason)));
+        }
+        if block.header.merkle != Chain::get_merkle(block.sorted_transactions()) {
+            return Err(("invalid_merkle", ChainError::Validation(format!("block {block_hash} has a wrong merkle root"))));
+        }
+        Chain::validate_reward(block, block_hash, height, spec)?;
+        let context = ScriptContext { height, timestamp: block.header.timestamp };
+        for transaction in block.transactions.values() {
+            transaction.verify(&context)?;
+        }
+        Ok(())
+    }
 
-        // delete the amanah db
-        if let Err(e) = fs::remove_dir_all("amanah.db") {
-            eprintln!("Couldn't able to remove the db: {e}");
+    fn validate_timestamp(timestamp: i64, block_hash: &str, recent: &[i64]) -> Result<(), (&'static str, ChainError)> {
+        let invalid = |reason: String| ("invalid_timestamp", ChainError::Validation(format!("block {block_hash} {reason}")));
+        let median = median_time(recent);
+        if timestamp < median {
+            return Err(invalid(format!("is stamped {timestamp}, before the median {median} of the blocks before it")));
+        }
+        let latest = Utc::now().timestamp_millis() + MAX_FUTURE_DRIFT;
+        if timestamp > latest {
+            return Err(invalid(format!("is stamped {timestamp}, in the future")));
         }
+        Ok(())
+    }
 
-        // create amanah.db dir
-        if let Err(e) = fs::create_dir("amanah.db") {
-            eprintln!("Couldn't able to create amanah.db directory: {}", e);
+    // timestamps of the last `MEDIAN_TIME_SPAN` blocks, oldest first. the
+    // headers outlive pruning
+    fn recent_timestamps(&self) -> Result<Vec<i64>, ChainError> {
+        let db = self.db()?;
+        let mut recent = vec![];
+        for height in self.height.saturating_sub(MEDIAN_TIME_SPAN)..self.height {
+            let hash = db.get_hash(height)?.ok_or_else(|| ChainError::NotFound(format!("block at height {height}")))?;
+            let header = db.get_header(&hash)?.ok_or_else(|| ChainError::NotFound(format!("header of {hash}")))?;
+            recent.push(header.timestamp);
         }
+        Ok(recent)
+    }
 
-        Self::copy_dir_all(Path::new("tmp/backup"), Path::new("amanah.db")).unwrap();
-        let db_path = "amanah.db";
-        let db = DB::open_default(&db_path).unwrap();
-        let height = match db.get("height") { // do not convert to bytes
-            Ok(Some(height)) => {
-                let digits: [u8; 4] = height.try_into().unwrap();
-                u32::from_be_bytes(digits)
-            },
-            _ => 0
+    // a block mints exactly once, the reward of its height, to the miner
+    fn validate_reward(block: &Block, block_hash: &str, height: u32, spec: &ChainSpec) -> Result<(), (&'static str, ChainError)> {
+        let invalid = |reason: String| ("invalid_reward", ChainError::Validation(format!("block {block_hash} {reason}")));
+        let mints: Vec<_> = block.transactions.values().filter(|t| t.sender == MINT_ADDRESS).collect();
+        let [reward] = mints[..] else {
+            return Err(invalid(format!("has {} reward transactions instead of one", mints.len())));
         };
-        println!("Height = {height}");
-        println!("File downloaded successfully!");
-        (db, height)
-    } 
+        let expected = spec.reward_at(height);
+        if reward.amount != expected {
+            return Err(invalid(format!("mints {} instead of {expected}", reward.amount)));
+        }
+        if reward.receiver == MINT_ADDRESS {
+            return Err(invalid("pays its reward to nobody".to_string()));
+        }
+        Ok(())
+    }
 
-    pub async fn add_transaction(&mut self, transaction: Transaction) {
-        self.curr_trans.insert(transaction.transaction_id.clone(), transaction);
+    // the checks a light client can make without the block body. the
+    // difficulty is the one of the network, not the one the header claims
+    // the work is checked first, so only a block that cost its miner the
+    // work of the network is treated as an orphan worth syncing for
+    pub fn validate_header(header: &Blockheader, block_hash: &str, pre_hash: &str, difficulty: u32) -> Result<(), (&'static str, ChainError)> {
+        if header.difficulty != difficulty {
+            return Err(("invalid_difficulty", ChainError::Validation(format!("block {block_hash} has difficulty {}, not {difficulty}", header.difficulty))));
+        }
+        if !Chain::meets_difficulty(block_hash, difficulty) {
+            return Err(("invalid_pow", ChainError::Validation(format!("block {block_hash} does not meet its difficulty"))));
+        }
+        if header.pre_hash != pre_hash {
+            return Err(("invalid_pre_hash", ChainError::Orphan(format!("block {block_hash} does not extend {pre_hash}"))));
+        }
+        Ok(())
     }
 
-    pub async fn add_block(&mut self, block: Block) -> bool {
-        println!("{:#?} going to add..", &block);
+    // write the block at the current height and advance the tip
+    fn store_block(&mut self, block: &Block, block_hash: &str) -> Result<(), ChainError> {
+        let height = self.height;
+        self.db_mut()?.commit_block(height, block_hash, block)?;
+        self.height += 1;
+        self.prune_bodies()
+    }
 
-        let block_hash = Chain::hash(&block.header);
+    // drop the bodies that fell out of the kept window
+    fn prune_bodies(&mut self) -> Result<(), ChainError> {
+        let Some(keep) = self.prune else {
+            return Ok(());
+        };
+        let below = self.height.saturating_sub(keep);
+        let pruned = self.db_mut()?.prune(below)?;
+        if pruned > 0 {
+            debug!(pruned, below, "Pruned block bodies");
+        }
+        Ok(())
+    }
+
+    // lowest height whose block body is still stored
+    pub fn pruned_height(&self) -> u32 {
+        self.db().and_then(|db| db.pruned_height()).unwrap_or(0)
+    }
+
+    // headers `from` up to (excluding) `to`. pruned blocks have them too
+    pub fn get_headers(&self, from: u32, to: u32) -> Result<Vec<Blockheader>, ChainError> {
+        let db = self.db()?;
+        let mut headers = vec![];
+        for index in from..to.min(self.height) {
+            let hash = db.get_hash(index)?.ok_or_else(|| ChainError::NotFound(format!("block at index {index}")))?;
+            headers.push(self.get_header(&hash)?);
+        }
+        Ok(headers)
+    }
+
+    pub fn get_header(&self, hash: &str) -> Result<Blockheader, ChainError> {
+        self.db()?.get_header(hash)?.ok_or_else(|| ChainError::NotFound(format!("header {hash}")))
+    }
 
-        if block.header.pre_hash !=  self.last_hash().await.unwrap() {
-            println!("Invalid block. Failed to add the block");
-            return false;
+    // confirmed transactions from or to `address`, with the hash of their
+    // block. those in pruned blocks are left out
+    pub fn address_transactions(&self, address: &str) -> Result<Vec<(String, Transaction)>, ChainError> {
+        let db = self.db()?;
+        let mut transactions = vec![];
+        for id in db.address_transactions(address)? {
+            let Some(block_hash) = db.transaction_block(&id)? else {
+                continue;
+            };
+            let transaction = db.get_block(&block_hash)?.and_then(|mut block| block.transactions.remove(&id));
+            if let Some(transaction) = transaction {
+                transactions.push((block_hash, transaction));
+            }
         }
+        Ok(transactions)
+    }
 
-        if self.db.as_mut().unwrap().put(block_hash.as_bytes(), serde_json::to_string(&block).unwrap().as_bytes()).is_err() {
-            return false;
+    // proof that a confirmed transaction is in its block. needs the body of
+    // the block, so it fails for pruned blocks
+    pub fn transaction_proof(&self, transaction_id: &str) -> Result<TransactionProof, ChainError> {
+        let db = self.db()?;
+        let block_hash = db.transaction_block(transaction_id)?
+            .ok_or_else(|| ChainError::NotFound(format!("transaction {transaction_id}")))?;
+        let block = db.get_block(&block_hash)?
+            .ok_or_else(|| ChainError::NotFound(format!("body of the pruned block {block_hash}")))?;
+        let transactions = block.sorted_transactions();
+        let index = transactions.iter().position(|t| t.id() == transaction_id)
+            .ok_or_else(|| ChainError::Storage(format!("transaction {transaction_id} is indexed in the wrong block")))?;
+        let proof = merkle_proof(transactions.iter().map(Chain::hash).collect(), index)
+            .ok_or_else(|| ChainError::Storage(format!("no merkle path to transaction {transaction_id}")))?;
+        Ok(TransactionProof { block_hash, transaction: transactions[index].clone(), proof })
+    }
+
+    // proofs of the confirmed transactions sending from or to `address`
+    pub fn address_history(&self, address: &str) -> Result<Vec<TransactionProof>, ChainError> {
+        self.db()?.address_transactions(address)?.iter()
+            .map(|id| self.transaction_proof(id))
+            .collect()
+    }
+
+    pub async fn get_blocks(&mut self, from: u32, to: u32) -> Result<Vec<Block>, ChainError> {
+        let mut blocks = vec![];
+        for index in from..to.min(self.height) {
+            blocks.push(self.get_block_by_index(index).await?);
         }
+        Ok(blocks)
+    }
 
-        if self.db.as_mut().unwrap().put(self.height.to_be_bytes(), block_hash.as_bytes()).is_err() {
-            return false;
+    // switch to `blocks` if they make a valid chain longer than ours. the first
+    // is at height `from` and extends our block before it, or is our genesis
+    // block. our blocks after the fork are replaced and their transactions go
+    // back to the mempool. returns false if our chain was kept
+    pub async fn replace_chain(&mut self, from: u32, blocks: Vec<Block>) -> Result<bool, ChainError> {
+        if from > self.height {
+            return Err(ChainError::Validation(format!("blocks from {from} leave a gap after our {} blocks", self.height)));
         }
-        
-        if self.db.as_mut().unwrap().put("height", (self.height + 1).to_be_bytes()).is_err() {
-            return false;
+        if blocks.is_empty() || from + blocks.len() as u32 <= self.height {
+            return Ok(false);
         }
 
-        self.height += 1;
-        self.db.as_mut().unwrap().flush().expect("Failed to add the data to the db");
+        let hashes: Vec<String> = blocks.iter().map(|block| Chain::hash(&block.header)).collect();
+        // the genesis block is not validated, it has to be ours
+        if from == 0 && hashes[0] != self.genesis_hash {
+            return Err(ChainError::Validation(format!("chain starts at {}, not at our genesis block", hashes[0])));
+        }
+        let mut fork = from.max(1);
+        while fork < self.height && self.get_hash_by_index(fork).await? == hashes[(fork - from) as usize] {
+            fork += 1;
+        }
+        // the bodies needed to undo the blocks are gone
+        if fork < self.pruned_height() {
+            return Err(ChainError::Validation(format!("the chain forks at {fork}, below our pruned height {}", self.pruned_height())));
+        }
 
-        println!("removing the added transactions.....");
-        println!("transactions already added: {:?}", block.transactions);
-        println!("transactions to be removed: {:?}", self.curr_trans);
-        for (id,_) in &block.transactions {
-            self.curr_trans.remove(id);
+        let old_height = self.height;
+        let orphaned = self.get_blocks(fork, old_height).await?;
+        {
+            let db = self.db()?;
+            let mut pre_hash = db.get_hash(fork - 1)?.ok_or_else(|| ChainError::NotFound(format!("block at height {}", fork - 1)))?;
+            let mut recent = vec![];
+            for height in fork.saturating_sub(MEDIAN_TIME_SPAN)..fork {
+                let hash = db.get_hash(height)?.ok_or_else(|| ChainError::NotFound(format!("block at height {height}")))?;
+                recent.push(db.get_header(&hash)?.ok_or_else(|| ChainError::NotFound(format!("header of {hash}")))?.timestamp);
+            }
+            // our balances and transactions at the fork, with the candidate
+            // blocks applied on top
+            let mut ledger = Ledger::on(db);
+            for block in orphaned.iter().rev() {
+                ledger.revert_block(block)?;
+            }
+            for (index, (block, block_hash)) in blocks.iter().zip(&hashes).enumerate().skip((fork - from) as usize) {
+                let window = &recent[recent.len().saturating_sub(MEDIAN_TIME_SPAN as usize)..];
+                // a broken link inside the candidate makes the whole chain invalid
+                Self::validate_block(block, block_hash, &pre_hash, from + index as u32, &self.spec, window)
+                    .and_then(|()| ledger.apply_block(block))
+                    .map_err(|(_, e)| match e {
+                        ChainError::Orphan(e) => ChainError::Validation(e),
+                        e => e,
+                    })?;
+                recent.push(block.header.timestamp);
+                pre_hash = block_hash.clone();
+            }
+        }
+
+        self.db_mut()?.truncate(fork)?;
+        self.height = fork;
+        for (block, block_hash) in blocks.iter().zip(&hashes).skip((fork - from) as usize) {
+            self.store_block(block, block_hash)?;
+            for id in block.transactions.keys() {
+                self.curr_trans.remove(id);
+            }
+        }
+
+        for block in orphaned {
+            for transaction in block.transactions.into_values() {
+                if transaction.sender != MINT_ADDRESS && !blocks.iter().any(|b| b.transactions.contains_key(&transaction.transaction_id)) {
+                    self.curr_trans.insert(transaction.transaction_id.clone(), transaction);
+                }
+            }
         }
-        println!("balance transaction: {:?}", self.curr_trans);
 
-        true
+        info!(old_height, new_height = self.height, fork, "Switched to a longer chain");
+        if fork < old_height {
+            self.node.publish(ChainEvent::Reorg { old_height, new_height: self.height });
+        } else {
+            for (index, (block, hash)) in blocks.into_iter().zip(hashes).enumerate().skip((fork - from) as usize) {
+                self.node.publish(ChainEvent::Block { height: from + index as u32, hash, block });
+            }
+        }
+        Ok(true)
+    }
+
+    // hashes of our last `FORK_SEARCH_DEPTH` blocks, oldest first, and the
+    // height of the first. a sync compares them with the chain of a peer
+    pub fn recent_hashes(&self) -> Result<(u32, Vec<String>), ChainError> {
+        let db = self.db()?;
+        let base = self.height.saturating_sub(FORK_SEARCH_DEPTH);
+        let mut hashes = vec![];
+        for height in base..self.height {
+            hashes.push(db.get_hash(height)?.ok_or_else(|| ChainError::NotFound(format!("block at height {height}")))?);
+        }
+        Ok((base, hashes))
     }
 
     pub async fn add_peer(&self, peer_addr: String) {
@@ -363,132 +1019,116 @@ impl Chain {
     }
 
     pub async fn get_height(&mut self) -> u32 {
-        match self.db.as_mut().expect("DB not found").get("height") { // do not convert to bytes
-            Ok(Some(height)) => {
-                let digits: [u8; 4] = height.try_into().unwrap();
-                u32::from_be_bytes(digits)
-            },
-            _ => 0
-        }
+        self.db().expect("DB not found").height().unwrap_or(0)
     }
 
     pub async fn reveal_chain(&mut self) {
         for i in 0..self.height {
-            let block = self.get_block_by_index(i).await.unwrap();
-            println!("Block hash: {}",Chain::hash(&block.header));
-            println!("{:#?}", block)
+            match self.get_block_by_index(i).await {
+                Ok(block) => {
+                    println!("Block hash: {}",Chain::hash(&block.header));
+                    println!("{:#?}", block)
+                }
+                Err(e) => {
+                    println!("Block {i}: {e}");
+                    break;
+                }
+            }
         }
     }
 
-    pub fn new_transaction(&mut self, sender: String, receiver: String, amount: f32) -> bool {
-        let transaction_id = Uuid::new_v4();
-        let transaction = Transaction {
-            sender,
-            receiver,
-            amount,
-            transaction_id: transaction_id.to_string(),
-        };
-        let trans_hash = Chain::hash(&transaction);
-        self.curr_trans.insert(transaction_id.to_string(), transaction.clone());
+    // signs a transfer from the key address of `secret` and submits it
+    pub async fn new_transaction(&mut self, secret: &SecretKey, receiver: String, amount: f32) -> bool {
+        match self.submit_transaction(Transaction::new_signed(secret, receiver, amount)).await {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(error = %e, "Transaction refused");
+                false
+            }
+        }
+    }
 
-        if let Err(e) = self.node.msg_outgoing_tx.send(Message {
-            uuid: self.uuid.to_string(),
-            block: None,
-            transaction: Some(transaction),
-            message_hash: trans_hash,
-        }) {
-            println!("Cannot broadcast the block due to {e}: ")
-        };
-        true
+    pub fn balance(&self, address: &str) -> Result<f64, ChainError> {
+        self.db()?.balance(address)
     }
 
-    pub async fn last_hash(&mut self) -> Result<String, &str> {
+    pub async fn last_hash(&mut self) -> Result<String, ChainError> {
         if self.height == 0 {
-            return Ok(String::from_utf8(vec![48; 64]).unwrap());
-        }
-        if let Ok(hash) = self.get_hash_by_index(self.height - 1).await {
-            Ok(hash)
-        } else {
-            Err("Hash not found for current index")
+            return Ok("0".repeat(64));
         }
+        self.get_hash_by_index(self.height - 1).await
     }
 
-    pub async fn get_block_by_index(&mut self, index: u32) -> Result<Block, &str> {
-        if let Ok(hash) = self.get_hash_by_index(index).await {
-            match self.get_block_by_hash(hash).await {
-                Ok(block) => Ok(block),
-                Err(_) => Err("Block not found"),
-            }
-        } else {
-            Err("Block not found")
-        }
+    pub async fn get_block_by_index(&mut self, index: u32) -> Result<Block, ChainError> {
+        let hash = self.get_hash_by_index(index).await?;
+        self.get_block_by_hash(hash).await
     }
 
-    pub async fn get_hash_by_index(&mut self, index: u32) -> Result<String, &str>{
-        match self.db.as_mut().expect("DB not found").get(index.to_be_bytes()) {
-            Ok(Some(hash)) => {
-                Ok(String::from_utf8(hash).unwrap())
-            },
-            Ok(None) => {
-                Err("Block not found")
-            },
-            Err(_) => Err("operational error occured")
-        }
+    pub async fn get_hash_by_index(&mut self, index: u32) -> Result<String, ChainError> {
+        self.db()?.get_hash(index)?.ok_or_else(|| ChainError::NotFound(format!("block at index {index}")))
     }
 
-    pub async fn get_block_by_hash(&mut self, hash: String) -> Result<Block, &'static str> {
-        match self.db.as_mut().expect("DB not found").get(hash.as_bytes()) {
-            Ok(Some(block)) => {
-                Ok(serde_json::from_str(str::from_utf8(&block).unwrap()).unwrap())
-            },
-            Ok(None) => {
-                Err("Block not found")
-            },
-            Err(_) => Err("operational error occured")
-        }
+    pub async fn get_block_by_hash(&mut self, hash: String) -> Result<Block, ChainError> {
+        self.db()?.get_block(&hash)?.ok_or_else(|| ChainError::NotFound(format!("block {hash}")))
     }
 
-    pub fn update_difficulty(&mut self, difficulty: u32) -> bool {
-        self.difficulty = difficulty;
-        true
+    pub fn height(&self) -> u32 {
+        self.height
     }
 
-    pub fn update_miner_address(&mut self, miner_address: String) {
-        self.miner_addr = miner_address;
+    pub fn mempool_size(&self) -> usize {
+        self.curr_trans.len()
     }
 
-    pub fn update_reward(&mut self, reward: f32) -> bool {
-        self.reward = reward;
-        true
+    // pending transactions, by id
+    pub fn mempool(&self) -> Vec<Transaction> {
+        let mut transactions: Vec<_> = self.curr_trans.values().cloned().collect();
+        transactions.sort_by(|a, b| a.transaction_id.cmp(&b.transaction_id));
+        transactions
     }
 
-    pub async fn generate_new_block(&mut self) -> bool {
-        println!("current transactons = {:?}", self.curr_trans);
-        if !(self.height == 0) && self.curr_trans.is_empty() {
-            println!("No transaction to add!!");
-            return false;
+    pub fn difficulty(&self) -> u32 {
+        self.spec.difficulty
+    }
+
+    pub fn network_id(&self) -> String {
+        self.spec.network_id()
+    }
+
+    pub fn update_miner_address(&mut self, miner_address: String) -> Result<(), ChainError> {
+        check_address(&miner_address)?;
+        self.miner_addr = miner_address;
+        Ok(())
+    }
+
+    pub async fn generate_new_block(&mut self) -> Result<(), ChainError> {
+        debug!(mempool = self.curr_trans.len(), "Generating a new block");
+        if self.curr_trans.is_empty() {
+            return Err(ChainError::Validation("no transaction to add".to_string()));
         }
 
-        self.miner_addr = if self.miner_addr.is_empty() {
-            get_value("Enter the miner address: ")
-        } else {
-            self.miner_addr.clone()
-        };
+        if self.miner_addr.is_empty() {
+            self.update_miner_address(get_value("Enter the miner address: "))?;
+        }
 
+        // a clock behind the chain still has to stamp a valid block
         let header = Blockheader {
-            timestamp: Utc::now().timestamp_millis(),
+            timestamp: Utc::now().timestamp_millis().max(median_time(&self.recent_timestamps()?)),
             nonce: 0,
-            pre_hash: self.last_hash().await.unwrap(),
+            pre_hash: self.last_hash().await?,
             merkle: String::new(),
-            difficulty: self.difficulty,
+            difficulty: self.spec.difficulty,
         };
 
         let transaction_id = Uuid::new_v4();
         let reward_trans = Transaction {
-            sender: String::from("Root"),
+            sender: MINT_ADDRESS.to_string(),
             receiver: self.miner_addr.clone(),
-            amount: self.reward,
-            transaction_id: transaction_id.to_string()
+            amount: self.spec.reward_at(self.height),
+            transaction_id: transaction_id.to_string(),
+            public_key: None,
+            signature: None,
+            spend: None,
         };
 
         let mut block = Block {
@@ -497,90 +1137,71 @@ impl Chain {
             transactions: HashMap::new(),
         };
 
-        block.transactions.insert(reward_trans.transaction_id.clone(), reward_trans);
-        for (id, transactions) in self.curr_trans.clone().into_iter() {
-            block.transactions.insert(id.clone(), transactions);
+        // transactions from peers were checked against their clock and the ones
+        // put back by a reorg not at all. they stay pending until valid. the
+        // ledger takes them in the order peers apply the block in
+        let mut ledger = Ledger::on(self.db()?);
+        ledger.apply(&reward_trans).map_err(|(_, e)| e)?;
+        let context = ScriptContext { height: self.height, timestamp: header.timestamp };
+        let mut pending: Vec<_> = self.curr_trans.values().collect();
+        pending.sort_by(|a, b| a.transaction_id.cmp(&b.transaction_id));
+        for transaction in pending {
+            match transaction.verify(&context).and_then(|()| ledger.apply(transaction)) {
+                Ok(()) => { block.transactions.insert(transaction.transaction_id.clone(), transaction.clone()); }
+                Err((_, e)) => debug!(transaction = %transaction.transaction_id, error = %e, "Transaction left out of the block"),
+            }
         }
-
-        self.curr_trans = HashMap::new();
+        block.transactions.insert(reward_trans.transaction_id.clone(), reward_trans);
 
         block.count = block.transactions.len() as u32;
 
-        let transaction_vec: Vec<_> = block.transactions.values().collect();
-        block.header.merkle = Chain::get_merkle(transaction_vec);
+        block.header.merkle = Chain::get_merkle(block.sorted_transactions());
         Chain::proof_of_work(&mut block.header);
 
-        println!("{:#?}", &block);
-
         let block_hash = Chain::hash(&block.header);
+        info!(%block_hash, height = self.height, nonce = block.header.nonce, transactions = block.count, "Block mined");
 
-        if self.db.as_mut().unwrap().put(block_hash.as_bytes(), serde_json::to_string(&block).unwrap().as_bytes()).is_err() {
-            return false;
-        }
-
-        if self.db.as_mut().unwrap().put(self.height.to_be_bytes(), block_hash.as_bytes()).is_err() {
-            return false;
-        }
-        
-        if self.db.as_mut().unwrap().put("height", (self.height + 1).to_be_bytes()).is_err() {
-            return false;
+        // the mempool is only cleared once the block is stored
+        self.store_block(&block, &block_hash)?;
+        for id in block.transactions.keys() {
+            self.curr_trans.remove(id);
         }
-
-        self.height += 1;
-        self.db.as_mut().unwrap().flush().expect("Failed to add the data to the db");
+        self.node.publish(ChainEvent::Block { height: self.height - 1, hash: block_hash.clone(), block: block.clone() });
         if let Err(e) = self.node.msg_outgoing_tx.send(Message {
             uuid: self.uuid.to_string(),
             // msg_id: 0,
             block: Some(block),
             transaction: None,
-            message_hash: block_hash,
-        }) {
-            println!("Cannot broadcast the block due to {e}: ")
+            message_hash: block_hash.clone(),
+        }).await {
+            warn!(%block_hash, error = %e, "Cannot broadcast the block")
         };
-        true
+        Ok(())
     }
 
     fn get_merkle(curr_trans: Vec<&Transaction>) -> String {
-        let mut merkle = Vec::new();
-
-        for t in &curr_trans {
-            let hash = Chain::hash(t);
-            merkle.push(hash);
-        }
-
-        if merkle.len() % 2 == 1 {
-            let last = merkle.last().cloned().unwrap();
-            merkle.push(last);
-        }
-
-        while merkle.len() > 1 {
-            let mut h1 = merkle.remove(0);
-            let mut h2 = merkle.remove(0);
-            h1.push_str(&mut h2);
-            let nh = Chain::hash(&h1);
-            merkle.push(nh);
-        }
-        merkle.pop().unwrap()
+        merkle_root(curr_trans.iter().map(Chain::hash).collect())
     }
 
     pub fn proof_of_work(header: &mut Blockheader) {
+        let started = std::time::Instant::now();
+        let first_nonce = header.nonce;
         loop {
             let hash = Chain::hash(header);
-            let slice = &hash[..header.difficulty as usize];
-            match slice.parse::<u32>() {
-                Ok(val) => {
-                    if val != 0 {
-                        header.nonce += 1;
-                    } else {
-                        println!("Block hash: {}", hash);
-                        break;
-                    }
-                }
-                Err(_) => {
-                    header.nonce += 1;
-                    continue;
-                }
-            };
+            if Chain::meets_difficulty(&hash, header.difficulty) {
+                debug!(%hash, nonce = header.nonce, "Proof of work found");
+                METRICS.set_hashrate((header.nonce - first_nonce) as u64 + 1, started.elapsed());
+                break;
+            }
+            header.nonce += 1;
+        }
+    }
+
+    // the first `difficulty` characters of the hash must be zeros
+    pub fn meets_difficulty(hash: &str, difficulty: u32) -> bool {
+        match hash.get(..difficulty as usize) {
+            Some(prefix) => prefix.bytes().all(|b| b == b'0'),
+            None => false,
         }
     }
 
@@ -601,4 +1222,459 @@ impl Chain {
         }
         s
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::memory_store::MemoryStore;
+    use crate::blockchain::metrics::NodeGauges;
+    use crate::blockchain::seen_cache::SeenCache;
+    use crate::blockchain::test_harness::{self, test_address, test_key};
+
+    // the senders of the tests start with a balance to spend
+    fn test_spec() -> ChainSpec {
+        let premine = ["miner", "miner-a", "miner-b", "miner-c", "carol"].map(|name| (test_address(name), 100.0));
+        ChainSpec { difficulty: 1, premine: premine.into(), ..ChainSpec::default() }
+    }
+
+    async fn memory_chain(miner: &str) -> Chain {
+        test_harness::memory_chain(miner, test_spec()).await
+    }
+
+    async fn mine_with(chain: &mut Chain, sender: &str, receiver: &str, amount: f32) {
+        chain.new_transaction(&test_key(sender), test_address(receiver), amount).await;
+        chain.generate_new_block().await.unwrap();
+    }
+
+    async fn all_blocks(chain: &mut Chain) -> Vec<Block> {
+        let height = chain.height;
+        chain.get_blocks(0, height).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn mines_blocks_and_tracks_balances() {
+        let mut chain = memory_chain("miner").await;
+        mine_with(&mut chain, "miner", "alice", 30.0).await;
+
+        assert_eq!(chain.height, 2);
+        assert_eq!(chain.mempool_size(), 0);
+        let db = chain.db().unwrap();
+        assert_eq!(db.balance(&test_address("miner")).unwrap(), 170.0);
+        assert_eq!(db.balance(&test_address("alice")).unwrap(), 30.0);
+        assert_eq!(db.balance(MINT_ADDRESS).unwrap(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn refuses_to_mine_an_empty_block() {
+        let mut chain = memory_chain("miner").await;
+        assert!(matches!(chain.generate_new_block().await, Err(ChainError::Validation(_))));
+        assert_eq!(chain.height, 1);
+    }
+
+    #[tokio::test]
+    async fn accepts_valid_blocks_from_peers() {
+        let mut miner = memory_chain("miner").await;
+        mine_with(&mut miner, "miner", "alice", 10.0).await;
+        let blocks = all_blocks(&mut miner).await;
+
+        let mut peer = memory_chain("peer").await;
+        let pending = blocks[1].transactions.values().find(|t| t.sender() == test_address("miner")).unwrap().clone();
+        peer.add_transaction(pending).await.unwrap();
+
+        for block in blocks {
+            peer.add_block(block).await.unwrap();
+        }
+        assert_eq!(peer.height, 2);
+        assert_eq!(peer.last_hash().await.unwrap(), miner.last_hash().await.unwrap());
+        // transactions included in a received block leave the mempool
+        assert_eq!(peer.mempool_size(), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_blocks() {
+        let mut miner = memory_chain("miner").await;
+        mine_with(&mut miner, "miner", "alice", 10.0).await;
+        mine_with(&mut miner, "alice", "bob", 5.0).await;
+        let blocks = all_blocks(&mut miner).await;
+
+        let mut peer = memory_chain("peer").await;
+        // does not extend the genesis block
+        assert!(matches!(peer.add_block(blocks[2].clone()).await, Err(ChainError::Orphan(_))));
+        // without its work it is no orphan worth syncing for
+        let mut unworked = blocks[2].clone();
+        while Chain::meets_difficulty(&Chain::hash(&unworked.header), peer.spec.difficulty) {
+            unworked.header.nonce += 1;
+        }
+        assert!(matches!(peer.add_block(unworked).await, Err(ChainError::Validation(e)) if e.contains("does not meet")));
+
+        let mut wrong_merkle = blocks[1].clone();
+        let transaction = wrong_merkle.transactions.values_mut().find(|t| t.sender() == test_address("miner")).unwrap();
+        transaction.amount = 1000.0;
+        assert!(matches!(peer.add_block(wrong_merkle).await, Err(ChainError::Validation(_))));
+
+        let mut wrong_pow = blocks[1].clone();
+        wrong_pow.header.difficulty = 20;
+        assert!(matches!(peer.add_block(wrong_pow).await, Err(ChainError::Validation(_))));
+
+        let mut wrong_count = blocks[1].clone();
+        wrong_count.count += 1;
+        assert!(matches!(peer.add_block(wrong_count).await, Err(ChainError::Validation(_))));
+
+        // a transfer filed under another id would stay in the mempool
+        let mut wrong_key = blocks[1].clone();
+        let id = wrong_key.transactions.values().find(|t| t.sender() == test_address("miner")).unwrap().transaction_id.clone();
+        let transfer = wrong_key.transactions.remove(&id).unwrap();
+        wrong_key.transactions.insert(Uuid::new_v4().to_string(), transfer);
+        reseal(&mut wrong_key);
+        assert!(matches!(peer.add_block(wrong_key).await, Err(ChainError::Validation(e)) if e.contains(&format!("files transaction {id}"))));
+
+        assert_eq!(peer.height, 1);
+        peer.add_block(blocks[1].clone()).await.unwrap();
+        assert_eq!(peer.height, 2);
+    }
+
+    // recomputes the count, merkle root and proof of work of an edited block
+    fn reseal(block: &mut Block) {
+        block.count = block.transactions.len() as u32;
+        block.header.merkle = Chain::get_merkle(block.sorted_transactions());
+        Chain::proof_of_work(&mut block.header);
+    }
+
+    #[tokio::test]
+    async fn refuses_blocks_stamped_out_of_range() {
+        let tomorrow = Utc::now().timestamp_millis() + 24 * 60 * 60 * 1000;
+        let lock = Condition::AfterTime { timestamp: tomorrow };
+        let mut spec = test_spec();
+        spec.premine.insert(lock.address(), 50.0);
+        let mut miner = test_harness::memory_chain("miner", spec.clone()).await;
+        mine_with(&mut miner, "miner", "alice", 10.0).await;
+        let block = all_blocks(&mut miner).await.remove(1);
+        let mut peer = test_harness::memory_chain("peer", spec).await;
+
+        // dating the block forward would open the time lock
+        let mut early_spend = block.clone();
+        let spend = Transaction::new_script_spend(lock, test_address("mallory"), 50.0);
+        early_spend.transactions.insert(spend.transaction_id.clone(), spend);
+        reseal(&mut early_spend);
+        assert!(matches!(peer.add_block(early_spend.clone()).await, Err(ChainError::Validation(e)) if e.contains("locked until")));
+        early_spend.header.timestamp = tomorrow;
+        reseal(&mut early_spend);
+        assert!(matches!(peer.add_block(early_spend).await, Err(ChainError::Validation(e)) if e.contains("in the future")));
+
+        let mut backdated = block.clone();
+        backdated.header.timestamp = peer.spec.genesis_timestamp - 1;
+        reseal(&mut backdated);
+        assert!(matches!(peer.add_block(backdated.clone()).await, Err(ChainError::Validation(e)) if e.contains("before the median")));
+        let candidate = vec![all_blocks(&mut miner).await.remove(0), backdated];
+        assert!(matches!(peer.replace_chain(0, candidate).await, Err(ChainError::Validation(e)) if e.contains("before the median")));
+
+        assert_eq!(peer.height, 1);
+        peer.add_block(block).await.unwrap();
+    }
+
+    #[test]
+    fn ledger_refuses_negative_and_non_finite_amounts() {
+        let mut ledger = Ledger::default();
+        ledger.balances.insert(test_address("bob"), 50.0);
+        for amount in [-50.0, 0.0, f32::NAN, f32::INFINITY] {
+            let transfer = Transaction::new(test_address("alice"), test_address("bob"), amount);
+            assert!(matches!(ledger.apply(&transfer), Err(("invalid_amount", _))), "{amount}");
+            let context = ScriptContext { height: 1, timestamp: 0 };
+            assert!(matches!(transfer.verify(&context), Err(("invalid_amount", _))), "{amount}");
+        }
+        assert_eq!(ledger.balance(&test_address("alice")).unwrap(), 0.0);
+        assert_eq!(ledger.balance(&test_address("bob")).unwrap(), 50.0);
+
+        // a reward halved down to nothing is still a valid block
+        let empty_reward = Transaction::new(MINT_ADDRESS.to_string(), test_address("miner"), 0.0);
+        assert!(ledger.apply(&empty_reward).is_ok());
+    }
+
+    #[tokio::test]
+    async fn pending_transactions_must_be_covered() {
+        let mut chain = memory_chain("miner").await;
+        assert!(!chain.new_transaction(&test_key("alice"), test_address("bob"), 1.0).await);
+        let mint = Transaction::new(MINT_ADDRESS.to_string(), test_address("bob"), 1.0);
+        assert!(chain.submit_transaction(mint).await.is_err());
+        assert!(!chain.new_transaction(&test_key("miner"), test_address("bob"), 150.0).await);
+
+        // the pending transfers count against the balance
+        assert!(chain.new_transaction(&test_key("miner"), test_address("bob"), 60.0).await);
+        assert!(!chain.new_transaction(&test_key("miner"), test_address("bob"), 60.0).await);
+        let transfer = chain.curr_trans.values().next().unwrap().clone();
+        chain.generate_new_block().await.unwrap();
+        assert_eq!(chain.balance(&test_address("bob")).unwrap(), 60.0);
+
+        // a confirmed transaction cannot be submitted again
+        assert!(matches!(chain.add_transaction(transfer).await, Err(ChainError::Validation(e)) if e.contains("already confirmed")));
+        assert_eq!(chain.mempool_size(), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_replays_and_overspending_in_blocks() {
+        let mut miner = memory_chain("miner").await;
+        mine_with(&mut miner, "miner", "alice", 10.0).await;
+        mine_with(&mut miner, "alice", "bob", 5.0).await;
+        let blocks = all_blocks(&mut miner).await;
+        let mut peer = memory_chain("peer").await;
+
+        let mut overspent = blocks[1].clone();
+        let theft = Transaction::new_signed(&test_key("alice"), test_address("mallory"), 1000.0);
+        overspent.transactions.insert(theft.transaction_id.clone(), theft);
+        reseal(&mut overspent);
+        assert!(matches!(peer.add_block(overspent).await, Err(ChainError::Validation(e)) if e.contains("spends 1000")));
+
+        // block 2 carrying the transfer of block 1 a second time
+        let transfer = blocks[1].transactions.values().find(|t| t.sender() == test_address("miner")).unwrap().clone();
+        let mut replayed = blocks[2].clone();
+        replayed.transactions.insert(transfer.transaction_id.clone(), transfer);
+        reseal(&mut replayed);
+        let candidate = vec![blocks[0].clone(), blocks[1].clone(), replayed.clone()];
+        assert!(matches!(peer.replace_chain(0, candidate).await, Err(ChainError::Validation(e)) if e.contains("already confirmed")));
+        assert_eq!(peer.height, 1);
+
+        peer.add_block(blocks[1].clone()).await.unwrap();
+        assert!(matches!(peer.add_block(replayed).await, Err(ChainError::Validation(e)) if e.contains("already confirmed")));
+        assert_eq!(peer.balance(&test_address("alice")).unwrap(), 10.0);
+    }
+
+    #[tokio::test]
+    async fn enforces_the_spec_difficulty_and_reward() {
+        let mut miner = memory_chain("miner").await;
+        mine_with(&mut miner, "miner", "alice", 10.0).await;
+        let block = all_blocks(&mut miner).await.remove(1);
+        let mut peer = memory_chain("peer").await;
+
+        // any hash meets a difficulty of 0
+        let mut no_work = block.clone();
+        no_work.header.difficulty = 0;
+        reseal(&mut no_work);
+        assert!(matches!(peer.add_block(no_work).await, Err(ChainError::Validation(e)) if e.contains("difficulty 0")));
+
+        let mut inflated = block.clone();
+        inflated.transactions.values_mut().find(|t| t.sender == MINT_ADDRESS).unwrap().amount = 1000.0;
+        reseal(&mut inflated);
+        assert!(matches!(peer.add_block(inflated).await, Err(ChainError::Validation(e)) if e.contains("mints 1000")));
+
+        let mut second_mint = block.clone();
+        let extra = Transaction::new(MINT_ADDRESS.to_string(), test_address("mallory"), 100.0);
+        second_mint.transactions.insert(extra.transaction_id.clone(), extra);
+        reseal(&mut second_mint);
+        assert!(matches!(peer.add_block(second_mint).await, Err(ChainError::Validation(e)) if e.contains("2 reward transactions")));
+
+        assert_eq!(peer.height, 1);
+        peer.add_block(block).await.unwrap();
+        assert_eq!(peer.balance(&test_address("miner")).unwrap(), 190.0);
+    }
+
+    #[tokio::test]
+    async fn syncs_a_longer_chain() {
+        let mut miner = memory_chain("miner").await;
+        mine_with(&mut miner, "miner", "alice", 10.0).await;
+        mine_with(&mut miner, "alice", "bob", 5.0).await;
+
+        let mut peer = memory_chain("peer").await;
+        assert!(peer.replace_chain(0, all_blocks(&mut miner).await).await.unwrap());
+        assert_eq!(peer.height, 3);
+        assert_eq!(peer.last_hash().await.unwrap(), miner.last_hash().await.unwrap());
+        assert_eq!(peer.db().unwrap().balance(&test_address("bob")).unwrap(), 5.0);
+
+        // a chain that is not longer is ignored
+        let blocks = all_blocks(&mut peer).await;
+        assert!(!miner.replace_chain(0, blocks).await.unwrap());
+
+        // only the blocks after the shared ones need to be sent
+        mine_with(&mut miner, "bob", "carol", 1.0).await;
+        mine_with(&mut miner, "carol", "dave", 1.0).await;
+        let mut blocks = all_blocks(&mut miner).await;
+        assert!(matches!(peer.replace_chain(4, blocks[4..].to_vec()).await, Err(ChainError::Validation(e)) if e.contains("gap")));
+        assert!(peer.replace_chain(3, blocks.split_off(3)).await.unwrap());
+        assert_eq!(peer.height, 5);
+        assert_eq!(peer.last_hash().await.unwrap(), miner.last_hash().await.unwrap());
+        assert_eq!(peer.balance(&test_address("dave")).unwrap(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn reorgs_to_a_longer_fork() {
+        let mut a = memory_chain("miner-a").await;
+        let mut b = memory_chain("miner-b").await;
+
+        // the chains fork after the genesis block
+        mine_with(&mut a, "miner-a", "alice", 10.0).await;
+        mine_with(&mut a, "alice", "bob", 5.0).await;
+        mine_with(&mut b, "carol", "dave", 1.0).await;
+        let orphaned = b.last_hash().await.unwrap();
+
+        // the fork is undone before the blocks after the genesis block apply
+        let mut blocks = all_blocks(&mut a).await;
+        assert!(b.replace_chain(1, blocks.split_off(1)).await.unwrap());
+        assert_eq!(b.height, 3);
+        assert_eq!(b.last_hash().await.unwrap(), a.last_hash().await.unwrap());
+        assert!(matches!(b.get_block_by_hash(orphaned).await, Err(ChainError::NotFound(_))));
+
+        // the orphaned transfer is pending again, its reward is gone
+        assert_eq!(b.mempool_size(), 1);
+        let db = b.db().unwrap();
+        assert_eq!(db.balance(&test_address("miner-b")).unwrap(), 100.0);
+        assert_eq!(db.balance(&test_address("dave")).unwrap(), 0.0);
+        assert_eq!(db.balance(&test_address("bob")).unwrap(), 5.0);
+    }
+
+    #[tokio::test]
+    async fn rejects_an_invalid_chain() {
+        let mut miner = memory_chain("miner").await;
+        mine_with(&mut miner, "miner", "alice", 10.0).await;
+        mine_with(&mut miner, "alice", "bob", 5.0).await;
+
+        let mut blocks = all_blocks(&mut miner).await;
+        blocks[1].header.pre_hash = "0".repeat(64);
+
+        let mut peer = memory_chain("peer").await;
+        let tip = peer.last_hash().await.unwrap();
+        assert!(matches!(peer.replace_chain(0, blocks).await, Err(ChainError::Validation(_))));
+        assert_eq!(peer.height, 1);
+        assert_eq!(peer.last_hash().await.unwrap(), tip);
+    }
+
+    #[tokio::test]
+    async fn starts_from_the_spec_genesis_block() {
+        let mut spec = test_spec();
+        spec.premine.insert(test_address("alice"), 50.0);
+        let mut a = test_harness::memory_chain("miner-a", spec.clone()).await;
+        let mut b = test_harness::memory_chain("miner-b", spec.clone()).await;
+
+        assert_eq!(a.height, 1);
+        assert_eq!(a.last_hash().await.unwrap(), b.last_hash().await.unwrap());
+        assert_eq!(a.db().unwrap().balance(&test_address("alice")).unwrap(), 50.0);
+
+        // a chain of another network is refused, whatever its length
+        let mut other = test_harness::memory_chain("miner-c", ChainSpec { magic: 7, ..spec.clone() }).await;
+        mine_with(&mut other, "miner-c", "dave", 1.0).await;
+        mine_with(&mut other, "miner-c", "dave", 1.0).await;
+        assert!(matches!(b.replace_chain(0, all_blocks(&mut other).await).await, Err(ChainError::Validation(_))));
+        assert_eq!(b.height, 1);
+
+        // and so is a database written for another network
+        let db = a.db.take().unwrap();
+        let config = ChainConfig { spec: ChainSpec { magic: 7, ..spec }, ..ChainConfig::default() };
+        assert!(matches!(Chain::open(test_harness::offline_node(&config.spec).await, db, config), Err(ChainError::Validation(_))));
+    }
+
+    #[tokio::test]
+    async fn prunes_old_bodies_but_keeps_headers_and_balances() {
+        let config = ChainConfig { spec: test_spec(), miner_addr: test_address("miner"), prune: Some(2) };
+        let mut chain = Chain::open(test_harness::offline_node(&config.spec).await, Box::new(MemoryStore::default()), config).unwrap();
+        let mut archive = memory_chain("miner").await;
+        for chain in [&mut chain, &mut archive] {
+            for _ in 0..4 {
+                mine_with(chain, "miner", "alice", 1.0).await;
+            }
+        }
+        assert_eq!(chain.height, 5);
+        assert_eq!(chain.pruned_height(), 3);
+        assert_eq!(archive.pruned_height(), 0);
+
+        let first = chain.get_hash_by_index(1).await.unwrap();
+        assert!(chain.db().unwrap().get_header(&first).unwrap().is_some());
+        assert!(matches!(chain.get_block_by_index(1).await, Err(ChainError::NotFound(_))));
+        assert_eq!(chain.get_blocks(3, 5).await.unwrap().len(), 2);
+        assert_eq!(chain.balance(&test_address("alice")).unwrap(), 4.0);
+        assert_eq!(chain.balance(&test_address("miner")).unwrap(), 496.0);
+
+        // a fork below the kept blocks cannot be undone
+        let mut fork = memory_chain("miner-b").await;
+        for _ in 0..6 {
+            mine_with(&mut fork, "miner-b", "bob", 1.0).await;
+        }
+        assert!(matches!(chain.replace_chain(0, all_blocks(&mut fork).await).await, Err(ChainError::Validation(_))));
+        assert_eq!(chain.height, 5);
+        assert_eq!(chain.balance(&test_address("bob")).unwrap(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn requires_signatures_from_key_addresses() {
+        let secret = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let address = derive_address(&PublicKey::from_secret_key(&Secp256k1::new(), &secret));
+        let spec = ChainSpec { premine: [(address.clone(), 50.0)].into(), ..test_spec() };
+        let mut chain = test_harness::memory_chain("miner", spec).await;
+
+        let unsigned = Transaction::new(address.clone(), test_address("bob"), 10.0);
+        assert!(matches!(chain.submit_transaction(unsigned).await, Err(ChainError::Validation(_))));
+        // each failure is counted under its own reason
+        let gauges = NodeGauges { height: 0, mempool_size: 0, peers: 0, seen_cache: SeenCache::default().stats() };
+        assert!(METRICS.render(&gauges).contains("edblock_rejected_total{item=\"transaction\",reason=\"missing_signature\"}"));
+
+        let mut tampered = Transaction::new_signed(&secret, test_address("bob"), 10.0);
+        tampered.amount = 40.0;
+        assert!(matches!(chain.submit_transaction(tampered.clone()).await, Err(ChainError::Validation(_))));
+
+        let other = SecretKey::from_slice(&[8u8; 32]).unwrap();
+        let mut stolen = Transaction::new_signed(&other, test_address("bob"), 10.0);
+        stolen.sender = address.clone();
+        assert!(matches!(chain.submit_transaction(stolen).await, Err(ChainError::Validation(_))));
+
+        // a typo in a receiver address is caught by its checksum
+        let mistyped = address.replacen('q', "p", 1);
+        assert!(!chain.new_transaction(&secret, mistyped, 10.0).await);
+
+        // a name owns no key, so nothing can be spent from or sent to it
+        let context = ScriptContext { height: 1, timestamp: 0 };
+        let unowned = Transaction::new("carol".to_string(), test_address("bob"), 10.0);
+        assert!(matches!(unowned.verify(&context), Err(("invalid_address", _))));
+        let misspelt = Transaction::new_signed(&secret, "bbo".to_string(), 10.0);
+        assert!(matches!(misspelt.verify(&context), Err(("invalid_address", _))));
+        assert!(matches!(chain.submit_transaction(misspelt).await, Err(ChainError::Validation(_))));
+
+        chain.submit_transaction(Transaction::new_signed(&secret, test_address("bob"), 10.0)).await.unwrap();
+        chain.generate_new_block().await.unwrap();
+        assert_eq!(chain.balance(&address).unwrap(), 40.0);
+        assert_eq!(chain.balance(&test_address("bob")).unwrap(), 10.0);
+
+        // blocks carrying a forged transfer are refused as well
+        let mut block = chain.get_block_by_index(1).await.unwrap();
+        block.transactions.insert(tampered.transaction_id.clone(), tampered);
+        block.count += 1;
+        block.header.merkle = Chain::get_merkle(block.sorted_transactions());
+        Chain::proof_of_work(&mut block.header);
+        let hash = Chain::hash(&block.header);
+        assert!(matches!(Chain::validate_block(&block, &hash, &block.header.pre_hash, 1, &chain.spec, &[]), Err(("invalid_signature", _))));
+    }
+
+    #[tokio::test]
+    async fn script_spends_wait_for_their_timelock() {
+        let secret = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let owner = derive_address(&PublicKey::from_secret_key(&Secp256k1::new(), &secret));
+        let lock = Condition::All { conditions: vec![
+            Condition::AfterHeight { height: 3 },
+            Condition::Key { address: owner.clone() },
+        ] };
+        let mut spec = test_spec();
+        spec.premine.insert(lock.address(), 50.0);
+        let mut chain = test_harness::memory_chain("miner", spec).await;
+
+        let mut spend = Transaction::new_script_spend(lock.clone(), test_address("bob"), 20.0);
+        spend.sign_witness(&secret);
+        assert!(matches!(chain.submit_transaction(spend.clone()).await, Err(ChainError::Validation(_))));
+
+        // the right condition needs the right witness too
+        let mut unsigned = Transaction::new_script_spend(lock.clone(), test_address("bob"), 20.0);
+        mine_with(&mut chain, "miner", "alice", 1.0).await;
+        mine_with(&mut chain, "miner", "alice", 1.0).await;
+        assert!(chain.submit_transaction(unsigned.clone()).await.is_err());
+        unsigned.sign_witness(&SecretKey::from_slice(&[8u8; 32]).unwrap());
+        assert!(chain.submit_transaction(unsigned).await.is_err());
+
+        chain.submit_transaction(spend.clone()).await.unwrap();
+        chain.generate_new_block().await.unwrap();
+        assert_eq!(chain.balance(&lock.address()).unwrap(), 30.0);
+        assert_eq!(chain.balance(&test_address("bob")).unwrap(), 20.0);
+
+        // the same block one height earlier is refused by the peers
+        let block = chain.get_block_by_index(3).await.unwrap();
+        let hash = Chain::hash(&block.header);
+        assert!(Chain::validate_block(&block, &hash, &block.header.pre_hash, 3, &chain.spec, &[]).is_ok());
+        assert!(matches!(Chain::validate_block(&block, &hash, &block.header.pre_hash, 2, &chain.spec, &[]), Err(("unsatisfied_condition", _))));
+    }
+}
+
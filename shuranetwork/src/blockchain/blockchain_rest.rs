@@ -1,16 +1,28 @@
-use axum::{Extension, Router};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use std::{net::SocketAddr, process::Command};
+use axum::{extract::{Path, Query}, http::StatusCode, Extension, Router};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::Stream;
+use tokio::sync::broadcast::error::RecvError;
+use std::{net::{IpAddr, SocketAddr}, process::Command};
 use tower_http::{services::ServeDir, trace::TraceLayer};
 use std::env;
-use axum::{routing::get, Json};
+use axum::{routing::{delete, get, post}, Json};
+use uuid::Uuid;
 
 use super::SharedChain;
+use super::explorer;
+use super::blockchain_core::{Block, Blockheader, SyncState, Transaction};
+use super::chain_error::ChainError;
+use super::merkle::TransactionProof;
+use super::metrics::{NodeGauges, METRICS};
+use super::peer_registry::PeersSnapshot;
 
 #[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
 pub struct Len {
     pub uuid: String,
     pub len: u32,
+    // blocks below this height have no body on the node. 0 on full nodes
+    #[serde(default)]
+    pub pruned_height: u32,
 }
 
 #[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
@@ -19,18 +31,87 @@ pub struct Msg {
     pub status: u32,
 }
 
-pub async fn blockchain_app_run(chain: SharedChain, port: u16) -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-            format!("{}=debug, tower_http=debug", env!("CARGO_CRATE_NAME")).into()
-        }),
-    )
-    .with(tracing_subscriber::fmt::layer())
-    .init();
+// most blocks returned by one GET /blocks request
+pub const MAX_BLOCKS_PER_REQUEST: u32 = 500;
+// most headers returned by one GET /headers request
+pub const MAX_HEADERS_PER_REQUEST: u32 = 2000;
 
-    tokio::join!(
-        serve(api_end_points(chain), port),
-    );
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct BlockRange {
+    pub from: u32,
+    pub to: Option<u32>,
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct RecentBlocks {
+    pub count: Option<u32>,
+}
+
+#[derive(serde_derive::Serialize, Debug)]
+pub struct BlockSummary {
+    pub height: u32,
+    pub hash: String,
+    pub header: Blockheader,
+    // none for pruned blocks
+    pub transactions: Option<u32>,
+}
+
+#[derive(serde_derive::Serialize, Debug)]
+pub struct AddressTransaction {
+    pub block_hash: String,
+    pub transaction: Transaction,
+}
+
+#[derive(serde_derive::Serialize, Debug)]
+pub struct AddressInfo {
+    pub address: String,
+    pub balance: f64,
+    pub transactions: Vec<AddressTransaction>,
+}
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
+pub struct AddPeer {
+    pub addr: String,
+}
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
+pub struct BanPeer {
+    pub addr: IpAddr,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+// reason recorded for bans without one
+const OPERATOR_BAN_REASON: &str = "banned by the operator";
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
+pub struct Balance {
+    pub address: String,
+    pub balance: f64,
+}
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
+pub struct Submitted {
+    pub transaction_id: String,
+}
+
+#[derive(serde_derive::Serialize, Debug)]
+pub struct Status {
+    pub node_id: String,
+    pub network_id: String,
+    pub height: u32,
+    pub tip_hash: String,
+    pub mempool_size: usize,
+    pub difficulty: u32,
+    pub pruned_height: u32,
+    pub peers: usize,
+    pub uptime_secs: u64,
+    pub sync_state: SyncState,
+}
+
+pub async fn blockchain_app_run(chain: SharedChain, addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    serve(chain, listener).await;
     Ok(())
 }
 
@@ -38,20 +119,231 @@ fn api_end_points(chain: SharedChain) -> Router {
     Router::new()
     .route("/archive_db", get(make_archive))
     .route("/len", get(get_len))
+    .route("/blocks", get(get_blocks))
+    .route("/blocks/recent", get(get_recent_blocks))
+    .route("/blocks/:hash", get(get_block))
+    .route("/addresses/:address", get(get_address))
+    .route("/mempool", get(get_mempool))
+    .route("/headers", get(get_headers))
+    .route("/proofs/transactions/:id", get(get_transaction_proof))
+    .route("/proofs/addresses/:address", get(get_address_proofs))
+    .route("/peers", get(get_peers).post(add_peer))
+    .route("/peers/:id", delete(remove_peer))
+    .route("/peers/bans", post(ban_peer))
+    .route("/peers/bans/:addr", delete(unban_peer))
+    .route("/transactions", post(submit_transaction))
+    .route("/balance/:address", get(get_balance))
+    .route("/status", get(get_status))
+    .route("/events", get(events))
+    .route("/metrics", get(get_metrics))
     .nest_service("/static", ServeDir::new("static"))
+    .merge(explorer::routes())
     .layer(Extension(chain))
 }
 
 async fn get_len(Extension(chain): Extension<SharedChain>) -> Json<Len> {
     let mut chain = chain.lock().await;
-    Json(Len {uuid: chain.node.get_id().to_string(), len: chain.get_height().await})
+    Json(Len {uuid: chain.node.get_id().to_string(), len: chain.get_height().await, pruned_height: chain.pruned_height()})
+}
+
+async fn get_peers(Extension(chain): Extension<SharedChain>) -> Json<PeersSnapshot> {
+    let registry = chain.lock().await.node.registry.clone();
+    let snapshot = registry.lock().await.snapshot();
+    Json(snapshot)
+}
+
+// blocks `from` up to (excluding) `to`, capped at MAX_BLOCKS_PER_REQUEST.
+// 410 if a pruned node no longer has the bodies
+async fn get_blocks(Extension(chain): Extension<SharedChain>, Query(range): Query<BlockRange>) -> Result<Json<Vec<Block>>, (StatusCode, String)> {
+    let mut chain = chain.lock().await;
+    if range.from < chain.pruned_height() {
+        return Err((StatusCode::GONE, format!("blocks below {} are pruned", chain.pruned_height())));
+    }
+    let to = range.to.unwrap_or(u32::MAX).min(range.from.saturating_add(MAX_BLOCKS_PER_REQUEST));
+    chain.get_blocks(range.from, to).await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+// the latest `count` blocks, newest first
+async fn get_recent_blocks(Extension(chain): Extension<SharedChain>, Query(recent): Query<RecentBlocks>) -> Result<Json<Vec<BlockSummary>>, (StatusCode, String)> {
+    let mut chain = chain.lock().await;
+    let count = recent.count.unwrap_or(20).min(MAX_BLOCKS_PER_REQUEST);
+    let (height, pruned_height) = (chain.height(), chain.pruned_height());
+    let mut blocks = vec![];
+    for index in (height.saturating_sub(count)..height).rev() {
+        let hash = chain.get_hash_by_index(index).await.map_err(lookup_error)?;
+        let header = chain.get_header(&hash).map_err(lookup_error)?;
+        let transactions = match index >= pruned_height {
+            true => Some(chain.get_block_by_hash(hash.clone()).await.map_err(lookup_error)?.count),
+            false => None,
+        };
+        blocks.push(BlockSummary { height: index, hash, header, transactions });
+    }
+    Ok(Json(blocks))
+}
+
+async fn get_block(Extension(chain): Extension<SharedChain>, Path(hash): Path<String>) -> Result<Json<Block>, (StatusCode, String)> {
+    chain.lock().await.get_block_by_hash(hash).await.map(Json).map_err(lookup_error)
+}
+
+// balance and confirmed transactions of an address
+async fn get_address(Extension(chain): Extension<SharedChain>, Path(address): Path<String>) -> Result<Json<AddressInfo>, (StatusCode, String)> {
+    let chain = chain.lock().await;
+    let balance = chain.balance(&address).map_err(lookup_error)?;
+    let transactions = chain.address_transactions(&address).map_err(lookup_error)?.into_iter()
+        .map(|(block_hash, transaction)| AddressTransaction { block_hash, transaction })
+        .collect();
+    Ok(Json(AddressInfo { address, balance, transactions }))
+}
+
+async fn get_mempool(Extension(chain): Extension<SharedChain>) -> Json<Vec<Transaction>> {
+    Json(chain.lock().await.mempool())
+}
+
+// headers `from` up to (excluding) `to`, capped at MAX_HEADERS_PER_REQUEST.
+// served by pruned nodes too
+async fn get_headers(Extension(chain): Extension<SharedChain>, Query(range): Query<BlockRange>) -> Result<Json<Vec<Blockheader>>, (StatusCode, String)> {
+    let chain = chain.lock().await;
+    let to = range.to.unwrap_or(u32::MAX).min(range.from.saturating_add(MAX_HEADERS_PER_REQUEST));
+    chain.get_headers(range.from, to)
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+// merkle proof of a confirmed transaction, for light clients
+async fn get_transaction_proof(Extension(chain): Extension<SharedChain>, Path(id): Path<String>) -> Result<Json<TransactionProof>, (StatusCode, String)> {
+    let chain = chain.lock().await;
+    chain.transaction_proof(&id).map(Json).map_err(lookup_error)
+}
+
+// merkle proofs of every confirmed transaction of the address, enough for a
+// light client to work out the balance
+async fn get_address_proofs(Extension(chain): Extension<SharedChain>, Path(address): Path<String>) -> Result<Json<Vec<TransactionProof>>, (StatusCode, String)> {
+    let chain = chain.lock().await;
+    chain.address_history(&address).map(Json).map_err(lookup_error)
+}
+
+fn lookup_error(e: ChainError) -> (StatusCode, String) {
+    match e {
+        ChainError::NotFound(_) => (StatusCode::NOT_FOUND, e.to_string()),
+        e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+// confirmed balance, pending transactions are not counted
+async fn get_balance(Extension(chain): Extension<SharedChain>, Path(address): Path<String>) -> Result<Json<Balance>, (StatusCode, String)> {
+    let chain = chain.lock().await;
+    chain.balance(&address)
+        .map(|balance| Json(Balance { address, balance }))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+// adds a transaction to the mempool and broadcasts it. transactions from key
+// addresses must be signed
+async fn submit_transaction(Extension(chain): Extension<SharedChain>, Json(transaction): Json<Transaction>) -> Result<(StatusCode, Json<Submitted>), (StatusCode, String)> {
+    let transaction_id = transaction.id().to_string();
+    chain.lock().await.submit_transaction(transaction).await
+        .map(|()| (StatusCode::ACCEPTED, Json(Submitted { transaction_id })))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+// connects in the background, progress shows up in GET /peers
+async fn add_peer(Extension(chain): Extension<SharedChain>, Json(peer): Json<AddPeer>) -> (StatusCode, Json<Msg>) {
+    let node = chain.lock().await.node.clone();
+    let uuid = node.get_id().to_string();
+    if tokio::net::lookup_host(&peer.addr).await.is_err() {
+        return (StatusCode::BAD_REQUEST, Json(Msg {uuid, status: 400}));
+    }
+    node.add_peer(peer.addr).await;
+    (StatusCode::ACCEPTED, Json(Msg {uuid, status: 202}))
+}
+
+async fn remove_peer(Extension(chain): Extension<SharedChain>, Path(id): Path<Uuid>) -> (StatusCode, Json<Msg>) {
+    let node = chain.lock().await.node.clone();
+    let uuid = node.get_id().to_string();
+    if node.disconnect_peer(id).await {
+        (StatusCode::OK, Json(Msg {uuid, status: 200}))
+    } else {
+        (StatusCode::NOT_FOUND, Json(Msg {uuid, status: 404}))
+    }
+}
+
+// bans the address for the configured ban time and closes its connections
+async fn ban_peer(Extension(chain): Extension<SharedChain>, Json(ban): Json<BanPeer>) -> (StatusCode, Json<Msg>) {
+    let node = chain.lock().await.node.clone();
+    let uuid = node.get_id().to_string();
+    let reason = ban.reason.as_deref().unwrap_or(OPERATOR_BAN_REASON);
+    let disconnected = node.ban(ban.addr, reason).await;
+    tracing::info!(addr = %ban.addr, reason, disconnected, "Address banned");
+    (StatusCode::OK, Json(Msg {uuid, status: 200}))
+}
+
+async fn unban_peer(Extension(chain): Extension<SharedChain>, Path(addr): Path<IpAddr>) -> (StatusCode, Json<Msg>) {
+    let node = chain.lock().await.node.clone();
+    let uuid = node.get_id().to_string();
+    if node.registry.lock().await.unban(&addr) {
+        tracing::info!(%addr, "Address unbanned");
+        (StatusCode::OK, Json(Msg {uuid, status: 200}))
+    } else {
+        (StatusCode::NOT_FOUND, Json(Msg {uuid, status: 404}))
+    }
+}
+
+async fn get_status(Extension(chain): Extension<SharedChain>) -> Json<Status> {
+    let mut chain = chain.lock().await;
+    let height = chain.get_height().await;
+    let tip_hash = chain.last_hash().await.unwrap_or_default();
+    let peers = chain.node.registry.lock().await.len();
+    Json(Status {
+        node_id: chain.node.get_id().to_string(),
+        network_id: chain.network_id(),
+        height,
+        tip_hash,
+        mempool_size: chain.mempool_size(),
+        difficulty: chain.difficulty(),
+        pruned_height: chain.pruned_height(),
+        peers,
+        uptime_secs: chain.node.started_at.elapsed().as_secs(),
+        sync_state: chain.sync_state,
+    })
+}
+
+// prometheus text exposition format
+async fn get_metrics(Extension(chain): Extension<SharedChain>) -> ([(axum::http::HeaderName, &'static str); 1], String) {
+    let mut chain = chain.lock().await;
+    let gauges = NodeGauges {
+        height: chain.get_height().await,
+        mempool_size: chain.mempool_size(),
+        peers: chain.node.registry.lock().await.len(),
+        seen_cache: chain.node.msg_hashes.lock().await.stats(),
+    };
+    ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], METRICS.render(&gauges))
+}
+
+// server-sent events stream of new blocks, transactions, reorgs and peer changes
+async fn events(Extension(chain): Extension<SharedChain>) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let receiver = chain.lock().await.node.subscribe();
+    let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let sse = match receiver.recv().await {
+            Ok(event) => Event::default().event(event.name()).json_data(&event),
+            // a slow subscriber misses events instead of holding back the node
+            Err(RecvError::Lagged(skipped)) => Ok(Event::default().event("lagged").data(skipped.to_string())),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((sse, receiver))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
 }
 
 // make an archive of db and copy to static folder
 async fn make_archive(chain: Extension<SharedChain>) -> Json<Msg>{
 
     let mut chain = chain.lock().await;
-    chain.copy_db_backup().await;
+    if let Err(e) = chain.copy_db_backup().await {
+        tracing::warn!(error = %e, "Failed to back up the db");
+        return Json(Msg {uuid: chain.node.get_id().to_string(), status: 400});
+    }
     let current_dir = env::current_dir().unwrap();
     let file_dir = format!("{}\\backup\\",current_dir.display());
     if let Err(_) = Command::new("7z")
@@ -63,11 +355,10 @@ async fn make_archive(chain: Extension<SharedChain>) -> Json<Msg>{
     }
 }
 
-async fn serve(app: Router, port: u16) {
-    let addr = SocketAddr::from(([127,0,0,1], port));
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    tracing::debug!("listeneing on {}",listener.local_addr().unwrap());
-    axum::serve(listener, app.layer(TraceLayer::new_for_http()))
+// serve the REST api on an already bound listener
+pub async fn serve(chain: SharedChain, listener: tokio::net::TcpListener) {
+    tracing::info!(addr = %listener.local_addr().unwrap(), "REST server listening");
+    axum::serve(listener, api_end_points(chain).layer(TraceLayer::new_for_http()))
         .await
         .unwrap();
 }
\ No newline at end of file
@@ -1,17 +1,24 @@
+use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use local_ip_address::local_ip;
+use crate::blockchain::address::check_address;
 use crate::blockchain::blockchain_core::Chain;
 use crate::template::{self, MenuBuilder};
 use crate::utils::get_value;
+use crate::wallet;
 
 use super::SharedChain;
 
-pub async fn blockchain_app_run(chain: SharedChain, port: u16) -> MenuBuilder {
+pub async fn blockchain_app_run(chain: SharedChain) -> MenuBuilder {
+    let (listen_addr, server_addr) = {
+        let chain = chain.lock().await;
+        (chain.node.listen_addr(), chain.node.server_addr().to_string())
+    };
     let header = format!("
     Welcome to shuranetwork!!
-    IP ADDRESS: {}:{}
-    ", local_ip().expect("Failed to get the local ip. Internal Error"), port);
+    NODE ADDRESS: {}
+    SERVER ADDRESS: {}
+    ", listen_addr, server_addr);
 
     let mut blockchain_page = template::MenuBuilder::new();
     blockchain_page.set_header(header);
@@ -45,30 +52,7 @@ pub async fn blockchain_app_run(chain: SharedChain, port: u16) -> MenuBuilder {
     }});
 
     let chain_clone = chain.clone();
-    blockchain_page.add("3", "Change difficulty", {
-        move || {
-            let chain_clone = chain_clone.clone();
-            async move {
-                let chain = chain_clone.clone();
-                change_difficulty(chain).await;
-                true
-            }
-    }});
-
-    let chain_clone = chain.clone();
-    blockchain_page.add("4", "Change Reward", {
-        move || {
-            let chain_clone = chain_clone.clone();
-            async move {
-                let chain = chain_clone.clone();
-                change_reward(chain).await;
-                true
-            }
-        }
-    });
-
-    let chain_clone = chain.clone();
-    blockchain_page.add("5", "reveal chain", {
+    blockchain_page.add("3", "reveal chain", {
         move || {
             let chain_clone = chain_clone.clone();
             async move {
@@ -80,7 +64,7 @@ pub async fn blockchain_app_run(chain: SharedChain, port: u16) -> MenuBuilder {
     });
 
     let chain_clone = chain.clone();
-    blockchain_page.add("6", "Show height", {
+    blockchain_page.add("4", "Show height", {
         move || {
             let chain_clone = chain_clone.clone();
             async move {
@@ -92,7 +76,7 @@ pub async fn blockchain_app_run(chain: SharedChain, port: u16) -> MenuBuilder {
     });
 
     let chain_clone = chain.clone();
-    blockchain_page.add("7", "Show hash by index", {
+    blockchain_page.add("5", "Show hash by index", {
         move || {
             let chain_clone = chain_clone.clone();
             async move {
@@ -104,7 +88,7 @@ pub async fn blockchain_app_run(chain: SharedChain, port: u16) -> MenuBuilder {
     });
 
     let chain_clone = chain.clone();
-    blockchain_page.add("8", "Change miner address", {
+    blockchain_page.add("6", "Change miner address", {
         move || {
             let chain_clone = chain_clone.clone();
             async move {
@@ -116,7 +100,7 @@ pub async fn blockchain_app_run(chain: SharedChain, port: u16) -> MenuBuilder {
     });
 
     let chain_clone = chain.clone();
-    blockchain_page.add("9", "Add new peer address", {
+    blockchain_page.add("7", "Add new peer address", {
         let chain_clone = chain_clone.clone();
         move || {
             let chain_clone = chain_clone.clone();
@@ -130,7 +114,7 @@ pub async fn blockchain_app_run(chain: SharedChain, port: u16) -> MenuBuilder {
     });
 
     let chain_clone = chain.clone();
-    blockchain_page.add("10", "Show server peer addresses", {
+    blockchain_page.add("8", "Show server peer addresses", {
         let chain_clone = chain_clone.clone();
         move || {
             let chain_clone = chain_clone.clone();
@@ -144,6 +128,23 @@ pub async fn blockchain_app_run(chain: SharedChain, port: u16) -> MenuBuilder {
             }
         }
     });
+
+    let chain_clone = chain.clone();
+    blockchain_page.add("9", "Show message cache stats", {
+        let chain_clone = chain_clone.clone();
+        move || {
+            let chain_clone = chain_clone.clone();
+            async move {
+                let chain = chain_clone.clone();
+                let chain = chain.lock().await;
+                let stats = chain.node.msg_hashes.lock().await.stats();
+                println!("entries: {}", stats.entries);
+                println!("hits: {}, misses: {}, evictions: {}", stats.hits, stats.misses, stats.evictions);
+                println!("hit rate: {:.2}%", stats.hit_rate * 100.0);
+                true
+            }
+        }
+    });
     blockchain_page
 }
 
@@ -161,16 +162,28 @@ async fn show_hash_by_index(chain: Arc<Mutex<Chain>>) {
 
 async fn new_transaction(chain: Arc<Mutex<Chain>>) {
     let sender = get_value("Enter Sender Address: ");
+    // the transfer is signed with the key of the sender from the wallet
+    let key = match wallet::load_key(Path::new(wallet::DEFAULT_DIR), &sender, &wallet::passphrase("Passphrase: ")) {
+        Ok(key) => key,
+        Err(e) => {
+            println!("Transaction failed: {e}");
+            return;
+        }
+    };
     let reciever = get_value("Enter Reciever Address: ");
+    if let Err(e) = check_address(&reciever) {
+        println!("Transaction failed: {e}");
+        return;
+    }
     let amount = get_value("Enter the amount: ");
 
     let mut chain = chain.lock().await;
 
     let res = chain.new_transaction(
-        sender.to_string(),
+        &key.secret,
         reciever.to_string(),
         amount.parse().unwrap()
-    );
+    ).await;
 
     match res {
         true => println!("Transaction added"),
@@ -181,37 +194,17 @@ async fn new_transaction(chain: Arc<Mutex<Chain>>) {
 async fn mine_block(chain: Arc<Mutex<Chain>>) {
     println!("Generating block...");
     let mut chain = chain.lock().await;
-    let res = chain.generate_new_block().await;
-    match res {
-        true => println!("Block added successfully"),
-        false => println!("Block failed to add")
+    match chain.generate_new_block().await {
+        Ok(()) => println!("Block added successfully"),
+        Err(e) => println!("Block failed to add: {e}")
     }
 }
 
-async fn change_difficulty(chain: Arc<Mutex<Chain>>) {
-
-    let new_diff = get_value("Enter new difficulty: ");
-    
-    let mut chain = chain.lock().await;
-    let res = chain.update_difficulty(new_diff.trim().parse().unwrap());
-    match res {
-        true => println!("Updated Difficulty"),
-        false => println!("Failed to update the difficulty")
-    }
-}
-
-async fn change_reward(chain: Arc<Mutex<Chain>>) {
-    let new_reward = get_value("Enter new reward: ");
-
-    let mut chain = chain.lock().await;
-    let res = chain.update_reward(new_reward.trim().parse().unwrap());
-    match res {
-        true => println!("Updated reward"),
-        false => println!("Failed to update the reward")
-    }
-}
 async fn change_miner_address(chain: Arc<Mutex<Chain>>) {
     let miner_addr = get_value("Enter new miner address: ");
     let mut chain = chain.lock().await;
-    chain.update_miner_address(miner_addr);
+    match chain.update_miner_address(miner_addr) {
+        Ok(()) => println!("Updated miner address"),
+        Err(e) => println!("Failed to update the miner address: {e}"),
+    }
 }
\ No newline at end of file
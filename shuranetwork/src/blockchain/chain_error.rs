@@ -0,0 +1,56 @@
+use std::fmt;
+
+// failures surfaced by `Chain` instead of panicking
+#[derive(Debug)]
+pub enum ChainError {
+    // rocksdb or filesystem failure, or the database is not open
+    Storage(String),
+    // a block or hash that is not in the database
+    NotFound(String),
+    // a block or transaction that breaks the chain rules
+    Validation(String),
+    // a block whose parent is not our tip. it may belong to a longer chain
+    Orphan(String),
+    // a peer could not be reached or sent an unexpected response
+    Network(String),
+    Serialization(serde_json::Error),
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::Storage(e) => write!(f, "storage error: {e}"),
+            ChainError::NotFound(what) => write!(f, "{what} not found"),
+            ChainError::Validation(e) => write!(f, "invalid: {e}"),
+            ChainError::Orphan(e) => write!(f, "orphan: {e}"),
+            ChainError::Network(e) => write!(f, "network error: {e}"),
+            ChainError::Serialization(e) => write!(f, "serialization error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+impl From<rocksdb::Error> for ChainError {
+    fn from(e: rocksdb::Error) -> Self {
+        ChainError::Storage(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for ChainError {
+    fn from(e: std::io::Error) -> Self {
+        ChainError::Storage(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ChainError {
+    fn from(e: serde_json::Error) -> Self {
+        ChainError::Serialization(e)
+    }
+}
+
+impl From<reqwest::Error> for ChainError {
+    fn from(e: reqwest::Error) -> Self {
+        ChainError::Network(e.to_string())
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::address::check_address;
+use super::chain_error::ChainError;
+
+// parameters every node of a network must agree on. the genesis block is
+// derived from them, and their hash is the network id exchanged in the handshake
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ChainSpec {
+    pub name: String,
+    // tells apart networks that share every other parameter
+    pub magic: u32,
+    // unix time in milliseconds
+    pub genesis_timestamp: i64,
+    pub difficulty: u32,
+    pub reward: RewardSchedule,
+    // address -> amount credited by the genesis block
+    #[serde(default)]
+    pub premine: BTreeMap<String, f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RewardSchedule {
+    pub initial: f32,
+    // the reward halves every `halving_interval` blocks. 0 keeps it constant
+    #[serde(default)]
+    pub halving_interval: u32,
+}
+
+impl Default for ChainSpec {
+    fn default() -> Self {
+        ChainSpec {
+            name: String::from("edblock-dev"),
+            magic: 0xed_b1_0c_00,
+            genesis_timestamp: 1_704_067_200_000,
+            difficulty: 2,
+            reward: RewardSchedule { initial: 100.0, halving_interval: 0 },
+            premine: BTreeMap::new(),
+        }
+    }
+}
+
+impl ChainSpec {
+    pub fn load(path: &Path) -> Result<ChainSpec, ChainError> {
+        let spec: ChainSpec = serde_json::from_slice(&std::fs::read(path)?)?;
+        if spec.reward.initial < 0.0 || spec.premine.values().any(|amount| *amount < 0.0) {
+            return Err(ChainError::Validation(format!("{} has a negative amount", path.display())));
+        }
+        for address in spec.premine.keys() {
+            check_address(address)?;
+        }
+        Ok(spec)
+    }
+
+    // sha256 of the spec in hex
+    pub fn hash(&self) -> String {
+        let spec = serde_json::to_vec(self).expect("chain spec serializes");
+        hex::encode(Sha256::digest(spec))
+    }
+
+    // first 8 bytes of the spec hash, so it always has the same length
+    pub fn network_id(&self) -> String {
+        self.hash()[..16].to_string()
+    }
+
+    // block reward at `height` under the halving schedule
+    pub fn reward_at(&self, height: u32) -> f32 {
+        let initial = self.reward.initial;
+        if self.reward.halving_interval == 0 {
+            return initial;
+        }
+        let halvings = height / self.reward.halving_interval;
+        if halvings >= 32 {
+            return 0.0;
+        }
+        initial / (1u64 << halvings) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_id_follows_the_parameters() {
+        let spec = ChainSpec::default();
+        assert_eq!(spec.network_id(), ChainSpec::default().network_id());
+        assert_eq!(spec.network_id().len(), 16);
+
+        let other = ChainSpec { magic: spec.magic + 1, ..ChainSpec::default() };
+        assert_ne!(spec.network_id(), other.network_id());
+    }
+
+    #[test]
+    fn loads_the_example_spec() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("chain-spec.example.json");
+        let spec = ChainSpec::load(&path).unwrap();
+        assert_eq!(spec.premine.len(), 2);
+        assert_ne!(spec.network_id(), ChainSpec::default().network_id());
+    }
+
+    #[test]
+    fn reward_halves_on_schedule() {
+        let spec = ChainSpec { reward: RewardSchedule { initial: 100.0, halving_interval: 10 }, ..ChainSpec::default() };
+        assert_eq!(spec.reward_at(9), 100.0);
+        assert_eq!(spec.reward_at(10), 50.0);
+        assert_eq!(spec.reward_at(25), 25.0);
+        assert_eq!(spec.reward_at(10 * 40), 0.0);
+        assert_eq!(ChainSpec::default().reward_at(1_000_000), 100.0);
+    }
+}
@@ -0,0 +1,35 @@
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use super::blockchain_core::{Block, Transaction};
+use super::peer_registry::Direction;
+
+// events buffered per subscriber before a slow subscriber starts missing events
+pub const EVENT_CAPACITY: usize = 256;
+
+pub type EventSender = broadcast::Sender<ChainEvent>;
+pub type EventReceiver = broadcast::Receiver<ChainEvent>;
+
+// state changes of the node pushed to REST subscribers
+#[derive(Debug, Clone, serde_derive::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChainEvent {
+    Block { height: u32, hash: String, block: Block },
+    Transaction { transaction: Transaction },
+    // the local chain was replaced by a longer one downloaded from a peer
+    Reorg { old_height: u32, new_height: u32 },
+    PeerConnected { peer_id: Uuid, addr: String, direction: Direction },
+    PeerDisconnected { peer_id: Uuid },
+}
+
+impl ChainEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChainEvent::Block { .. } => "block",
+            ChainEvent::Transaction { .. } => "transaction",
+            ChainEvent::Reorg { .. } => "reorg",
+            ChainEvent::PeerConnected { .. } => "peer_connected",
+            ChainEvent::PeerDisconnected { .. } => "peer_disconnected",
+        }
+    }
+}
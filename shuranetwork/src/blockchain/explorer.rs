@@ -0,0 +1,20 @@
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{Html, Redirect};
+use axum::routing::get;
+use axum::Router;
+
+// the explorer is compiled into the binary, so it works from any directory
+// and always matches the API of the node serving it
+const INDEX: &str = include_str!("explorer/index.html");
+const SCRIPT: &str = include_str!("explorer/explorer.js");
+const STYLE: &str = include_str!("explorer/explorer.css");
+
+// a single page app driven by the JSON API: recent blocks, block detail,
+// address pages, the mempool and the peers
+pub fn routes() -> Router {
+    Router::new()
+    .route("/", get(|| async { Redirect::temporary("/explorer") }))
+    .route("/explorer", get(|| async { Html(INDEX) }))
+    .route("/explorer/explorer.js", get(|| async { ([(CONTENT_TYPE, "text/javascript; charset=utf-8")], SCRIPT) }))
+    .route("/explorer/explorer.css", get(|| async { ([(CONTENT_TYPE, "text/css; charset=utf-8")], STYLE) }))
+}
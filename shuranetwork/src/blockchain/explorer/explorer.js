@@ -0,0 +1,237 @@
+// chain explorer. every page is rendered from the JSON API of the node that
+// serves it. routes: #/, #/block/<hash>, #/address/<address>
+"use strict";
+
+const page = document.getElementById("page");
+const statusBar = document.getElementById("status");
+
+// addresses and ids come from the chain, so nothing is inserted as html
+function el(tag, attrs, ...children) {
+  const node = document.createElement(tag);
+  for (const [key, value] of Object.entries(attrs || {})) {
+    node.setAttribute(key, value);
+  }
+  for (const child of children) {
+    node.append(child instanceof Node ? child : String(child ?? ""));
+  }
+  return node;
+}
+
+function link(href, text, cls) {
+  return el("a", { href, class: cls || "hash" }, text);
+}
+
+function blockLink(hash) {
+  return link(`#/block/${encodeURIComponent(hash)}`, hash);
+}
+
+function addressLink(address) {
+  return link(`#/address/${encodeURIComponent(address)}`, address);
+}
+
+function table(headings, rows, empty) {
+  if (rows.length === 0) {
+    return el("div", { class: "empty" }, empty);
+  }
+  const head = el("tr", {}, ...headings.map((h) => el("th", {}, h)));
+  return el("table", {}, el("thead", {}, head), el("tbody", {}, ...rows));
+}
+
+function cell(content, cls) {
+  return el("td", cls ? { class: cls } : {}, content);
+}
+
+function time(millis) {
+  return new Date(millis).toLocaleString();
+}
+
+async function api(path) {
+  const response = await fetch(path);
+  if (!response.ok) {
+    const error = new Error(`${path}: ${response.status} ${await response.text()}`);
+    error.status = response.status;
+    throw error;
+  }
+  return response.json();
+}
+
+function show(...children) {
+  page.replaceChildren(...children);
+}
+
+function showError(error) {
+  show(el("div", { class: "error" }, error.message));
+}
+
+async function renderStatus() {
+  try {
+    const status = await api("/status");
+    const item = (label, value) => el("div", {}, label, el("b", {}, value));
+    statusBar.replaceChildren(
+      item("height", status.height),
+      item("difficulty", status.difficulty),
+      item("mempool", status.mempool_size),
+      item("peers", status.peers),
+      item("sync", status.sync_state),
+      item("pruned below", status.pruned_height),
+      item("network", status.network_id.slice(0, 16)),
+    );
+  } catch (error) {
+    statusBar.replaceChildren(el("div", { class: "error" }, error.message));
+  }
+}
+
+function transactionRows(transactions) {
+  return transactions.map((t) => el("tr", {},
+    cell(t.transaction_id, "hash"),
+    cell(addressLink(t.sender)),
+    cell(addressLink(t.receiver)),
+    cell(t.amount, "amount"),
+  ));
+}
+
+async function renderHome() {
+  const [blocks, mempool, peers] = await Promise.all([
+    api("/blocks/recent?count=20"),
+    api("/mempool"),
+    api("/peers"),
+  ]);
+  const blockRows = blocks.map((b) => el("tr", {},
+    cell(b.height),
+    cell(blockLink(b.hash)),
+    cell(time(b.header.timestamp)),
+    cell(b.transactions ?? "pruned", "amount"),
+  ));
+  const peerRows = peers.peers.map((p) => el("tr", {},
+    cell(p.id, "hash"),
+    cell(p.server_addr),
+    cell(p.direction),
+    cell(time(p.connected_at)),
+  ));
+  show(
+    el("h2", {}, "Recent blocks"),
+    table(["height", "hash", "time", "transactions"], blockRows, "no blocks"),
+    el("h2", {}, "Mempool"),
+    table(["id", "from", "to", "amount"], transactionRows(mempool), "no pending transactions"),
+    el("h2", {}, "Peers"),
+    table(["id", "REST address", "direction", "connected"], peerRows, "no peers"),
+  );
+}
+
+async function renderBlock(hash, highlight) {
+  const block = await api(`/blocks/${encodeURIComponent(hash)}`);
+  const header = block.header;
+  const field = (label, value) => el("tr", {}, el("th", {}, label), cell(value));
+  const transactions = Object.values(block.transactions)
+    .sort((a, b) => a.transaction_id.localeCompare(b.transaction_id));
+  const rows = transactionRows(transactions);
+  rows.forEach((row, i) => {
+    if (transactions[i].transaction_id === highlight) {
+      row.style.background = "#fff7d6";
+    }
+  });
+  show(
+    el("h2", {}, "Block"),
+    el("table", {}, el("tbody", {},
+      field("hash", el("span", { class: "hash" }, hash)),
+      field("previous", blockLink(header.pre_hash)),
+      field("time", time(header.timestamp)),
+      field("merkle root", el("span", { class: "hash" }, header.merkle)),
+      field("difficulty", header.difficulty),
+      field("nonce", header.nonce),
+    )),
+    el("h2", {}, `Transactions (${block.count})`),
+    table(["id", "from", "to", "amount"], rows, "no transactions"),
+  );
+}
+
+async function renderAddress(address) {
+  const info = await api(`/addresses/${encodeURIComponent(address)}`);
+  const rows = info.transactions.map(({ block_hash, transaction: t }) => {
+    const outgoing = t.sender === address && t.receiver !== address;
+    return el("tr", {},
+      cell(t.transaction_id, "hash"),
+      cell(addressLink(outgoing ? t.receiver : t.sender)),
+      cell(`${outgoing ? "-" : "+"}${t.amount}`, `amount ${outgoing ? "debit" : "credit"}`),
+      cell(blockLink(block_hash)),
+    );
+  });
+  show(
+    el("h2", {}, "Address"),
+    el("p", { class: "hash" }, address),
+    el("p", {}, "Balance ", el("b", {}, info.balance)),
+    el("h2", {}, "Confirmed transactions"),
+    table(["id", "counterparty", "amount", "block"], rows, "no confirmed transactions"),
+  );
+}
+
+async function route() {
+  const [, kind, value, extra] = location.hash.split("/");
+  try {
+    if (kind === "block" && value) {
+      await renderBlock(decodeURIComponent(value), extra && decodeURIComponent(extra));
+    } else if (kind === "address" && value) {
+      await renderAddress(decodeURIComponent(value));
+    } else {
+      await renderHome();
+    }
+  } catch (error) {
+    showError(error);
+  }
+  renderStatus();
+}
+
+// a block hash, then a transaction id, otherwise an address
+async function search(query) {
+  const value = encodeURIComponent(query);
+  try {
+    await api(`/blocks/${value}`);
+    location.hash = `#/block/${value}`;
+    return;
+  } catch (error) {
+    if (error.status !== 404) {
+      return showError(error);
+    }
+  }
+  try {
+    const proof = await api(`/proofs/transactions/${value}`);
+    location.hash = `#/block/${encodeURIComponent(proof.block_hash)}/${value}`;
+    return;
+  } catch (error) {
+    if (error.status !== 404) {
+      return showError(error);
+    }
+  }
+  location.hash = `#/address/${value}`;
+}
+
+document.getElementById("search").addEventListener("submit", (event) => {
+  event.preventDefault();
+  const query = document.getElementById("query").value.trim();
+  if (query) {
+    search(query);
+  }
+});
+
+window.addEventListener("hashchange", route);
+
+// the home page follows the node's events, coalesced to one render a second
+let pending = null;
+const events = new EventSource("/events");
+for (const name of ["block", "transaction", "reorg", "peer_connected", "peer_disconnected"]) {
+  events.addEventListener(name, () => {
+    if (pending) {
+      return;
+    }
+    pending = setTimeout(() => {
+      pending = null;
+      if (!location.hash || location.hash === "#/") {
+        route();
+      } else {
+        renderStatus();
+      }
+    }, 1000);
+  });
+}
+
+route();
@@ -0,0 +1,231 @@
+use std::io::{BufRead, Write};
+
+use serde_derive::{Deserialize, Serialize};
+
+use super::blockchain_core::{Block, Chain, Transaction};
+use super::chain_error::ChainError;
+use super::storage::ChainStore;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // one json object per line. block files can be imported again
+    Jsonl,
+    Csv,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Records {
+    Blocks,
+    Transactions,
+}
+
+// a line of a jsonl block file
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockRecord {
+    pub height: u32,
+    pub hash: String,
+    pub block: Block,
+}
+
+// a line of a jsonl transaction file
+#[derive(Serialize, Debug)]
+pub struct TransactionRecord<'a> {
+    pub height: u32,
+    pub block_hash: &'a str,
+    pub timestamp: i64,
+    pub transaction: &'a Transaction,
+}
+
+const BLOCK_COLUMNS: &str = "height,hash,timestamp,pre_hash,merkle,difficulty,nonce,transactions";
+const TRANSACTION_COLUMNS: &str = "height,block_hash,timestamp,transaction_id,sender,receiver,amount";
+
+// writes the blocks `from` up to (excluding) `to`, or their transactions, and
+// returns the number of records written. the bodies must not be pruned
+pub fn export(db: &dyn ChainStore, from: u32, to: u32, records: Records, format: Format, out: &mut dyn Write) -> Result<u32, ChainError> {
+    let to = to.min(db.height()?);
+    if from < to && from < db.pruned_height()? {
+        return Err(ChainError::Storage(format!("the bodies of the blocks below {} are pruned", db.pruned_height()?)));
+    }
+    if format == Format::Csv {
+        let columns = match records {
+            Records::Blocks => BLOCK_COLUMNS,
+            Records::Transactions => TRANSACTION_COLUMNS,
+        };
+        writeln!(out, "{columns}")?;
+    }
+
+    let mut written = 0;
+    for height in from..to {
+        let hash = db.get_hash(height)?.ok_or_else(|| ChainError::NotFound(format!("block at height {height}")))?;
+        let block = db.get_block(&hash)?.ok_or_else(|| ChainError::NotFound(format!("block {hash}")))?;
+        match records {
+            Records::Blocks => {
+                write_block(out, format, height, &hash, block)?;
+                written += 1;
+            }
+            Records::Transactions => {
+                for transaction in block.sorted_transactions() {
+                    write_transaction(out, format, height, &hash, block.header.timestamp(), transaction)?;
+                    written += 1;
+                }
+            }
+        }
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+fn write_block(out: &mut dyn Write, format: Format, height: u32, hash: &str, block: Block) -> Result<(), ChainError> {
+    match format {
+        Format::Jsonl => {
+            serde_json::to_writer(&mut *out, &BlockRecord { height, hash: hash.to_string(), block })?;
+            writeln!(out)?;
+        }
+        Format::Csv => {
+            let header = &block.header;
+            writeln!(out, "{height},{hash},{},{},{},{},{},{}",
+                header.timestamp(), header.pre_hash(), header.merkle(), header.difficulty(), header.nonce(), block.count)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_transaction(out: &mut dyn Write, format: Format, height: u32, block_hash: &str, timestamp: i64, transaction: &Transaction) -> Result<(), ChainError> {
+    match format {
+        Format::Jsonl => {
+            serde_json::to_writer(&mut *out, &TransactionRecord { height, block_hash, timestamp, transaction })?;
+            writeln!(out)?;
+        }
+        Format::Csv => {
+            writeln!(out, "{height},{block_hash},{timestamp},{},{},{},{}",
+                csv_field(transaction.id()), csv_field(transaction.sender()), csv_field(transaction.receiver()), transaction.amount())?;
+        }
+    }
+    Ok(())
+}
+
+// account names are free-form, so they may need quoting
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub added: u32,
+    // already in the chain
+    pub skipped: u32,
+}
+
+// replays a jsonl block file through the same validation as blocks from
+// peers. stops at the first bad line, the blocks before it stay imported
+pub async fn import(chain: &mut Chain, input: impl BufRead) -> Result<ImportReport, ChainError> {
+    let mut report = ImportReport::default();
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let at_line = |e: ChainError| ChainError::Validation(format!("line {}: {e}", index + 1));
+        let record: BlockRecord = serde_json::from_str(&line).map_err(|e| at_line(e.into()))?;
+        let hash = Chain::hash(&record.block.header);
+        if hash != record.hash {
+            return Err(at_line(ChainError::Validation(format!("block {} hashes to {hash}", record.hash))));
+        }
+        if record.height < chain.height() {
+            if chain.get_hash_by_index(record.height).await? != hash {
+                return Err(at_line(ChainError::Orphan(format!("block {hash} is not the one at height {} in the chain", record.height))));
+            }
+            report.skipped += 1;
+            continue;
+        }
+        if record.height != chain.height() {
+            return Err(at_line(ChainError::Orphan(format!("block at height {} does not follow the tip at {}", record.height, chain.height()))));
+        }
+        chain.add_block(record.block).await.map_err(at_line)?;
+        report.added += 1;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::chain_spec::ChainSpec;
+    use crate::blockchain::test_harness::{self, test_address, test_key};
+
+    async fn memory_chain(miner: &str) -> Chain {
+        let spec = ChainSpec { difficulty: 1, premine: [(test_address("miner"), 100.0)].into(), ..ChainSpec::default() };
+        test_harness::memory_chain(miner, spec).await
+    }
+
+    async fn mined_chain() -> Chain {
+        let mut chain = memory_chain("miner").await;
+        for receiver in ["alice", "bob"] {
+            chain.new_transaction(&test_key("miner"), test_address(receiver), 10.0).await;
+            chain.generate_new_block().await.unwrap();
+        }
+        chain
+    }
+
+    fn exported(chain: &Chain, from: u32, records: Records, format: Format) -> String {
+        let mut out = vec![];
+        export(chain.db.as_deref().unwrap(), from, u32::MAX, records, format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn exports_blocks_and_transactions() {
+        let chain = mined_chain().await;
+
+        let blocks = exported(&chain, 1, Records::Blocks, Format::Csv);
+        let lines: Vec<_> = blocks.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], BLOCK_COLUMNS);
+        assert!(lines[1].starts_with("1,"));
+
+        let transactions = exported(&chain, 0, Records::Transactions, Format::Csv);
+        // the premine, then a reward and a transfer per block
+        assert_eq!(transactions.lines().count(), 1 + 5);
+        assert!(transactions.contains(&format!(",{},{},10\n", test_address("miner"), test_address("bob"))));
+        assert_eq!(csv_field("bob, the builder"), "\"bob, the builder\"");
+
+        let jsonl = exported(&chain, 0, Records::Transactions, Format::Jsonl);
+        let last: serde_json::Value = serde_json::from_str(jsonl.lines().last().unwrap()).unwrap();
+        assert_eq!(last["height"], 2);
+    }
+
+    #[tokio::test]
+    async fn imports_an_exported_chain() {
+        let mut source = mined_chain().await;
+        let file = exported(&source, 0, Records::Blocks, Format::Jsonl);
+
+        let mut target = memory_chain("other").await;
+        let report = import(&mut target, file.as_bytes()).await.unwrap();
+        assert_eq!(report, ImportReport { added: 2, skipped: 1 });
+        assert_eq!(target.last_hash().await.unwrap(), source.last_hash().await.unwrap());
+        assert_eq!(target.balance(&test_address("alice")).unwrap(), 10.0);
+
+        // importing again changes nothing
+        let report = import(&mut target, file.as_bytes()).await.unwrap();
+        assert_eq!(report, ImportReport { added: 0, skipped: 3 });
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_first_invalid_block() {
+        let source = mined_chain().await;
+        let file = exported(&source, 0, Records::Blocks, Format::Jsonl);
+        let mut lines: Vec<String> = file.lines().map(String::from).collect();
+        let mut record: serde_json::Value = serde_json::from_str(&lines[2]).unwrap();
+        record["block"]["count"] = 7.into();
+        lines[2] = record.to_string();
+
+        let mut target = memory_chain("other").await;
+        let result = import(&mut target, lines.join("\n").as_bytes()).await;
+        assert!(matches!(result, Err(ChainError::Validation(e)) if e.starts_with("line 3:")));
+        assert_eq!(target.height(), 2);
+    }
+}
@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{extract::{Path, Query}, http::StatusCode, routing::get, Extension, Json, Router};
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tower_http::trace::TraceLayer;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use super::blockchain_core::{Block, Blockheader, Chain, Transaction, FORK_SEARCH_DEPTH, MINT_ADDRESS};
+use super::blockchain_rest::{BlockRange, Len, Submitted, MAX_HEADERS_PER_REQUEST};
+use super::chain_error::ChainError;
+use super::chain_spec::ChainSpec;
+use super::merkle::TransactionProof;
+use super::storage::transaction_addresses;
+use crate::wallet::client::NodeClient;
+
+// headers from the genesis block of the spec up to the tip, each one checked
+// for its proof of work and its link to the one before
+pub struct HeaderChain {
+    headers: Vec<Blockheader>,
+    hashes: Vec<String>,
+    heights: HashMap<String, u32>,
+    // of the network. a header claiming another one is refused
+    difficulty: u32,
+}
+
+impl HeaderChain {
+    // the genesis block is derived from the spec, never downloaded
+    pub fn new(spec: &ChainSpec) -> HeaderChain {
+        let genesis = Block::genesis(spec).header;
+        let hash = Chain::hash(&genesis);
+        HeaderChain { headers: vec![genesis], hashes: vec![hash.clone()], heights: [(hash, 0)].into(), difficulty: spec.difficulty }
+    }
+
+    pub fn height(&self) -> u32 {
+        self.headers.len() as u32
+    }
+
+    pub fn tip(&self) -> &str {
+        &self.hashes[self.hashes.len() - 1]
+    }
+
+    pub fn headers(&self, from: u32, to: u32) -> &[Blockheader] {
+        let to = (to.min(self.height())) as usize;
+        &self.headers[(from as usize).min(to)..to]
+    }
+
+    // appends headers following the tip. nothing is appended if one is invalid
+    pub fn extend(&mut self, headers: &[Blockheader]) -> Result<(), ChainError> {
+        let mut pre_hash = self.tip().to_string();
+        let mut hashes = Vec::with_capacity(headers.len());
+        for header in headers {
+            let hash = Chain::hash(header);
+            Chain::validate_header(header, &hash, &pre_hash, self.difficulty).map_err(|(_, e)| e)?;
+            pre_hash = hash.clone();
+            hashes.push(hash);
+        }
+        for (header, hash) in headers.iter().zip(hashes) {
+            self.heights.insert(hash.clone(), self.height());
+            self.hashes.push(hash);
+            self.headers.push(header.clone());
+        }
+        Ok(())
+    }
+
+    // the first `height` headers, to put another branch on
+    fn truncated(&self, height: u32) -> HeaderChain {
+        let height = height as usize;
+        let hashes = self.hashes[..height].to_vec();
+        let heights = hashes.iter().enumerate().map(|(height, hash)| (hash.clone(), height as u32)).collect();
+        HeaderChain { headers: self.headers[..height].to_vec(), hashes, heights, difficulty: self.difficulty }
+    }
+
+    // height of the block holding the proven transaction. the path must lead
+    // to the merkle root of a header we validated
+    pub fn verify(&self, proof: &TransactionProof) -> Result<u32, ChainError> {
+        let height = *self.heights.get(&proof.block_hash)
+            .ok_or_else(|| ChainError::NotFound(format!("header {}", proof.block_hash)))?;
+        if proof.root() != self.headers[height as usize].merkle() {
+            return Err(ChainError::Validation(format!("transaction {} is not in block {}", proof.transaction.id(), proof.block_hash)));
+        }
+        Ok(height)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProvenTransaction {
+    pub transaction: Transaction,
+    pub block_hash: String,
+    pub height: u32,
+    pub confirmations: u32,
+}
+
+// a balance worked out from merkle proofs. a peer can leave transactions out
+// but cannot make them up, so the answers of every peer are merged
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProvenBalance {
+    pub address: String,
+    pub balance: f64,
+    // header height the proofs were checked against
+    pub height: u32,
+    pub transactions: usize,
+    // peers whose proofs were all valid
+    pub peers: usize,
+}
+
+// follows the chain through the headers served by full nodes and answers
+// queries with the proofs they send, without storing any block body
+pub struct LightClient {
+    peers: Vec<String>,
+    headers: Mutex<HeaderChain>,
+    http: reqwest::Client,
+    pub uuid: Uuid,
+}
+
+pub type SharedLightClient = Arc<LightClient>;
+
+impl LightClient {
+    pub fn new(spec: ChainSpec, peers: Vec<String>) -> LightClient {
+        let headers = Mutex::new(HeaderChain::new(&spec));
+        LightClient { peers, headers, http: reqwest::Client::new(), uuid: Uuid::new_v4() }
+    }
+
+    pub async fn height(&self) -> u32 {
+        self.headers.lock().await.height()
+    }
+
+    // catch up with the peer that has the most headers. a peer on another
+    // branch is followed only if its headers after the last one we share
+    // check out and make a longer chain. returns true if the tip moved. the headers are not locked while
+    // the peers are asked, so proofs are still answered meanwhile
+    pub async fn sync(&self) -> Result<bool, ChainError> {
+        let (mut height, tip) = {
+            let chain = self.headers.lock().await;
+            (chain.height(), chain.tip().to_string())
+        };
+        let from = height - 1;
+        let mut best = None;
+        for addr in &self.peers {
+            match self.fetch::<Len>(addr, "len").await {
+                Ok(len) if len.len > height => {
+                    height = len.len;
+                    best = Some(addr);
+                }
+                Ok(_) => {}
+                Err(e) => warn!(%addr, error = %e, "Couldn't get the chain height of the peer"),
+            }
+        }
+        let Some(addr) = best else {
+            return Ok(false);
+        };
+
+        // the tip is asked for again to see whether the peer builds on it
+        let headers = self.fetch_headers(addr, from, height).await?;
+        if headers.first().is_some_and(|header| Chain::hash(header) == tip) {
+            let mut chain = self.headers.lock().await;
+            // another sync moved the tip meanwhile
+            if chain.tip() != tip {
+                return Ok(false);
+            }
+            chain.extend(&headers[1..])?;
+            debug!(peer = %addr, height = chain.height(), "Headers synced");
+            return Ok(true);
+        }
+
+        // our last headers are compared with the peer's. a fork deeper than
+        // that is taken from the genesis block
+        let (base, recent) = {
+            let chain = self.headers.lock().await;
+            let base = chain.height().saturating_sub(FORK_SEARCH_DEPTH);
+            (base, chain.hashes[base as usize..].to_vec())
+        };
+        let theirs = self.fetch_headers(addr, base, base + recent.len() as u32).await?;
+        let shared = theirs.iter().zip(&recent).rposition(|(header, hash)| Chain::hash(header) == *hash);
+        let from = shared.map_or(1, |index| base + index as u32 + 1);
+        let headers = self.fetch_headers(addr, from, height).await?;
+
+        let mut chain = self.headers.lock().await;
+        // another sync moved away from the shared header meanwhile
+        let anchor = shared.map_or(&chain.hashes[0], |index| &recent[index]);
+        if chain.hashes.get(from as usize - 1) != Some(anchor) {
+            return Ok(false);
+        }
+        let mut fork = chain.truncated(from);
+        fork.extend(&headers)?;
+        if fork.height() <= chain.height() {
+            return Ok(false);
+        }
+        info!(peer = %addr, old_height = chain.height(), height = fork.height(), "Switched to a longer header chain");
+        *chain = fork;
+        Ok(true)
+    }
+
+    async fn fetch_headers(&self, addr: &str, from: u32, to: u32) -> Result<Vec<Blockheader>, ChainError> {
+        // `to` comes from the peer, so it sizes nothing up front
+        let mut headers = vec![];
+        while from + (headers.len() as u32) < to {
+            let start = from + headers.len() as u32;
+            let page: Vec<Blockheader> = self.fetch(addr, &format!("headers?from={start}&to={to}")).await?;
+            if page.is_empty() {
+                return Err(ChainError::Network(format!("{addr} stopped sending headers at {start}")));
+            }
+            headers.extend(page.into_iter().take((to - start) as usize));
+        }
+        Ok(headers)
+    }
+
+    async fn fetch<T: serde::de::DeserializeOwned>(&self, addr: &str, path: &str) -> Result<T, ChainError> {
+        Ok(self.http.get(format!("http://{addr}/{path}")).send().await?.error_for_status()?.json().await?)
+    }
+
+    // the first peer with a valid proof answers
+    pub async fn transaction(&self, transaction_id: &str) -> Result<ProvenTransaction, ChainError> {
+        if let Err(e) = self.sync().await {
+            warn!(error = %e, "Header sync failed");
+        }
+        for addr in &self.peers {
+            let proof: TransactionProof = match self.fetch(addr, &format!("proofs/transactions/{transaction_id}")).await {
+                Ok(proof) => proof,
+                Err(e) => {
+                    debug!(%addr, error = %e, "No proof from the peer");
+                    continue;
+                }
+            };
+            if proof.transaction.id() != transaction_id {
+                warn!(%addr, transaction_id, "Peer sent the proof of another transaction");
+                continue;
+            }
+            let chain = self.headers.lock().await;
+            match chain.verify(&proof) {
+                Ok(height) => {
+                    return Ok(ProvenTransaction {
+                        confirmations: chain.height() - height,
+                        transaction: proof.transaction,
+                        block_hash: proof.block_hash,
+                        height,
+                    });
+                }
+                Err(e) => warn!(%addr, error = %e, "Invalid transaction proof"),
+            }
+        }
+        Err(ChainError::NotFound(format!("proof of transaction {transaction_id}")))
+    }
+
+    // sums the proven transactions of the address. a peer with a single bad
+    // proof is ignored altogether
+    pub async fn balance(&self, address: &str) -> Result<ProvenBalance, ChainError> {
+        if let Err(e) = self.sync().await {
+            warn!(error = %e, "Header sync failed");
+        }
+        let mut transactions = HashMap::new();
+        let mut peers = 0;
+        for addr in &self.peers {
+            let proofs: Vec<TransactionProof> = match self.fetch(addr, &format!("proofs/addresses/{address}")).await {
+                Ok(proofs) => proofs,
+                Err(e) => {
+                    debug!(%addr, error = %e, "No address proofs from the peer");
+                    continue;
+                }
+            };
+            let chain = self.headers.lock().await;
+            let checked = proofs.iter().try_for_each(|proof| {
+                if !transaction_addresses(&proof.transaction).contains(&address) {
+                    return Err(ChainError::Validation(format!("transaction {} is not from or to {address}", proof.transaction.id())));
+                }
+                chain.verify(proof).map(|_| ())
+            });
+            if let Err(e) = checked {
+                warn!(%addr, error = %e, "Invalid address proofs");
+                continue;
+            }
+            peers += 1;
+            for proof in proofs {
+                transactions.insert(proof.transaction.id().to_string(), proof.transaction);
+            }
+        }
+        if peers == 0 {
+            return Err(ChainError::Network(format!("no peer proved the transactions of {address}")));
+        }
+
+        let mut balance = 0.0;
+        for transaction in transactions.values() {
+            let amount = transaction.amount() as f64;
+            if transaction.sender() == address && transaction.sender() != MINT_ADDRESS {
+                balance -= amount;
+            }
+            if transaction.receiver() == address {
+                balance += amount;
+            }
+        }
+        Ok(ProvenBalance {
+            address: address.to_string(),
+            balance,
+            height: self.height().await,
+            transactions: transactions.len(),
+            peers,
+        })
+    }
+
+    // light clients have no mempool, the first peer accepting it relays it
+    pub async fn submit(&self, transaction: &Transaction) -> Result<String, ChainError> {
+        let mut last = ChainError::Network("no peer to submit to".to_string());
+        for addr in &self.peers {
+            match NodeClient::new(addr).submit(transaction).await {
+                Ok(id) => return Ok(id),
+                // every full node would refuse it the same way
+                Err(e @ ChainError::Validation(_)) => return Err(e),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+}
+
+// syncs the headers every `interval` and serves the proof-backed queries
+pub async fn run(client: SharedLightClient, listener: tokio::net::TcpListener, interval: Duration) {
+    let syncing = client.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = syncing.sync().await {
+                warn!(error = %e, "Header sync failed");
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+    info!(addr = %listener.local_addr().unwrap(), "Light client REST server listening");
+    axum::serve(listener, api_end_points(client).layer(TraceLayer::new_for_http()))
+        .await
+        .unwrap();
+}
+
+// the query paths of a full node, so wallets work against either
+fn api_end_points(client: SharedLightClient) -> Router {
+    Router::new()
+    .route("/len", get(get_len))
+    .route("/headers", get(get_headers))
+    .route("/balance/:address", get(get_balance))
+    .route("/transactions", axum::routing::post(submit_transaction))
+    .route("/transactions/:id", get(get_transaction))
+    .layer(Extension(client))
+}
+
+// no block body is kept, so full nodes never download blocks from us
+async fn get_len(Extension(client): Extension<SharedLightClient>) -> Json<Len> {
+    let len = client.height().await;
+    Json(Len { uuid: client.uuid.to_string(), len, pruned_height: len })
+}
+
+async fn get_headers(Extension(client): Extension<SharedLightClient>, Query(range): Query<BlockRange>) -> Json<Vec<Blockheader>> {
+    let to = range.to.unwrap_or(u32::MAX).min(range.from.saturating_add(MAX_HEADERS_PER_REQUEST));
+    Json(client.headers.lock().await.headers(range.from, to).to_vec())
+}
+
+async fn get_balance(Extension(client): Extension<SharedLightClient>, Path(address): Path<String>) -> Result<Json<ProvenBalance>, (StatusCode, String)> {
+    client.balance(&address).await.map(Json).map_err(query_error)
+}
+
+async fn get_transaction(Extension(client): Extension<SharedLightClient>, Path(id): Path<String>) -> Result<Json<ProvenTransaction>, (StatusCode, String)> {
+    client.transaction(&id).await.map(Json).map_err(query_error)
+}
+
+async fn submit_transaction(Extension(client): Extension<SharedLightClient>, Json(transaction): Json<Transaction>) -> Result<(StatusCode, Json<Submitted>), (StatusCode, String)> {
+    client.submit(&transaction).await
+        .map(|transaction_id| (StatusCode::ACCEPTED, Json(Submitted { transaction_id })))
+        .map_err(query_error)
+}
+
+fn query_error(e: ChainError) -> (StatusCode, String) {
+    let status = match e {
+        ChainError::NotFound(_) => StatusCode::NOT_FOUND,
+        ChainError::Validation(_) => StatusCode::BAD_REQUEST,
+        ChainError::Network(_) => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::test_harness::{self, test_address, test_key};
+
+    fn test_spec() -> ChainSpec {
+        ChainSpec { difficulty: 1, premine: [(test_address("alice"), 50.0)].into(), ..ChainSpec::default() }
+    }
+
+    async fn full_chain(miner: &str, transfers: &[(&str, &str, f32)]) -> Chain {
+        let mut chain = test_harness::memory_chain(miner, test_spec()).await;
+        for (sender, receiver, amount) in transfers {
+            chain.new_transaction(&test_key(sender), test_address(receiver), *amount).await;
+            chain.generate_new_block().await.unwrap();
+        }
+        chain
+    }
+
+    fn header_chain(full: &Chain) -> HeaderChain {
+        let mut headers = HeaderChain::new(&test_spec());
+        headers.extend(full.get_headers(1, full.height()).unwrap().as_slice()).unwrap();
+        headers
+    }
+
+    // the header with its nonce moved until the hash misses the difficulty
+    fn without_pow(header: &Blockheader) -> Blockheader {
+        let mut value = serde_json::to_value(header).unwrap();
+        loop {
+            value["nonce"] = (value["nonce"].as_u64().unwrap() + 1).into();
+            let header: Blockheader = serde_json::from_value(value.clone()).unwrap();
+            if !Chain::meets_difficulty(&Chain::hash(&header), header_difficulty(&value)) {
+                return header;
+            }
+        }
+    }
+
+    fn header_difficulty(value: &serde_json::Value) -> u32 {
+        value["difficulty"].as_u64().unwrap() as u32
+    }
+
+    #[tokio::test]
+    async fn follows_the_headers_of_a_full_node() {
+        let mut full = full_chain("miner", &[("alice", "bob", 10.0), ("bob", "carol", 4.0)]).await;
+        let headers = header_chain(&full);
+        assert_eq!(headers.height(), 3);
+        assert_eq!(headers.tip(), full.last_hash().await.unwrap());
+
+        // another network has another genesis block
+        let mut foreign = HeaderChain::new(&ChainSpec { magic: 7, ..test_spec() });
+        assert!(matches!(foreign.extend(&full.get_headers(1, 3).unwrap()), Err(ChainError::Orphan(_))));
+
+        // headers must come in order and meet their difficulty
+        let mut headers = HeaderChain::new(&test_spec());
+        assert!(matches!(headers.extend(&full.get_headers(2, 3).unwrap()), Err(ChainError::Orphan(_))));
+        let mut weak = full.get_headers(1, 3).unwrap();
+        weak[0] = without_pow(&weak[0]);
+        assert!(matches!(headers.extend(&weak), Err(ChainError::Validation(_))));
+        assert_eq!(headers.height(), 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_headers_below_the_network_difficulty() {
+        let full = full_chain("miner", &[("alice", "bob", 10.0)]).await;
+        let headers = header_chain(&full);
+
+        // any hash meets a difficulty of 0, so a longer fork costs nothing
+        let mut pre_hash = headers.hashes[0].clone();
+        let mut cheap = vec![];
+        for nonce in 0..5 {
+            let header: Blockheader = serde_json::from_value(serde_json::json!({
+                "timestamp": test_spec().genesis_timestamp + 1, "nonce": nonce, "pre_hash": pre_hash, "merkle": "", "difficulty": 0,
+            })).unwrap();
+            pre_hash = Chain::hash(&header);
+            cheap.push(header);
+        }
+        let mut fork = HeaderChain::new(&test_spec());
+        assert!(matches!(fork.extend(&cheap), Err(ChainError::Validation(e)) if e.contains("difficulty 0")));
+        assert_eq!(fork.height(), 1);
+
+        // nor can a header claim less work on top of the tip
+        let mut weak = full.get_headers(1, 2).unwrap();
+        let mut value = serde_json::to_value(&weak[0]).unwrap();
+        value["difficulty"] = 0.into();
+        weak[0] = serde_json::from_value(value).unwrap();
+        let mut fresh = HeaderChain::new(&test_spec());
+        assert!(matches!(fresh.extend(&weak), Err(ChainError::Validation(e)) if e.contains("difficulty 0")));
+        assert_eq!(headers.height(), 2);
+    }
+
+    #[tokio::test]
+    async fn verifies_transactions_against_the_headers() {
+        let full = full_chain("miner", &[("alice", "bob", 10.0), ("bob", "carol", 4.0)]).await;
+        let headers = header_chain(&full);
+
+        let history = full.address_history(&test_address("bob")).unwrap();
+        assert_eq!(history.len(), 2);
+        for proof in &history {
+            assert!(headers.verify(proof).is_ok());
+        }
+
+        // another transaction does not hash to the same merkle root
+        let mut forged = history[0].clone();
+        forged.transaction = history[1].transaction.clone();
+        assert!(matches!(headers.verify(&forged), Err(ChainError::Validation(_))));
+
+        // nor does a real transaction claimed for another block
+        let mut moved = history[0].clone();
+        moved.block_hash = headers.hashes[0].clone();
+        assert!(matches!(headers.verify(&moved), Err(ChainError::Validation(_))));
+
+        // blocks we have no header of prove nothing
+        let other = full_chain("miner-b", &[("alice", "bob", 10.0)]).await;
+        let proof = other.address_history(&test_address("bob")).unwrap().remove(0);
+        assert!(matches!(headers.verify(&proof), Err(ChainError::NotFound(_))));
+
+        // a branch goes on top of the headers shared with it
+        let mut branch = headers.truncated(1);
+        branch.extend(&other.get_headers(1, other.height()).unwrap()).unwrap();
+        assert_eq!(branch.height(), 2);
+        assert!(branch.verify(&proof).is_ok());
+        assert!(matches!(branch.verify(&history[1]), Err(ChainError::NotFound(_))));
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use super::blockchain_core::{Block, Blockheader};
+use super::chain_error::ChainError;
+use super::storage::{balance_changes, transaction_addresses, ChainStore};
+
+// keeps the chain in memory. used by the tests
+#[derive(Default)]
+pub struct MemoryStore {
+    hashes: Vec<String>,
+    blocks: HashMap<String, Block>,
+    headers: HashMap<String, Blockheader>,
+    transactions: HashMap<String, String>,
+    addresses: HashMap<String, Vec<String>>,
+    balances: HashMap<String, f64>,
+    pruned_height: u32,
+}
+
+impl ChainStore for MemoryStore {
+    fn height(&self) -> Result<u32, ChainError> {
+        Ok(self.hashes.len() as u32)
+    }
+
+    fn get_hash(&self, height: u32) -> Result<Option<String>, ChainError> {
+        Ok(self.hashes.get(height as usize).cloned())
+    }
+
+    fn get_block(&self, hash: &str) -> Result<Option<Block>, ChainError> {
+        Ok(self.blocks.get(hash).cloned())
+    }
+
+    fn get_header(&self, hash: &str) -> Result<Option<Blockheader>, ChainError> {
+        Ok(self.headers.get(hash).cloned())
+    }
+
+    fn balance(&self, address: &str) -> Result<f64, ChainError> {
+        Ok(self.balances.get(address).copied().unwrap_or(0.0))
+    }
+
+    fn transaction_block(&self, transaction_id: &str) -> Result<Option<String>, ChainError> {
+        Ok(self.transactions.get(transaction_id).cloned())
+    }
+
+    fn address_transactions(&self, address: &str) -> Result<Vec<String>, ChainError> {
+        Ok(self.addresses.get(address).cloned().unwrap_or_default())
+    }
+
+    fn commit_block(&mut self, height: u32, hash: &str, block: &Block) -> Result<(), ChainError> {
+        if height as usize != self.hashes.len() {
+            return Err(ChainError::Storage(format!("block at height {height} does not extend the tip")));
+        }
+        if let Some((id, indexed)) = block.transactions.keys().find_map(|id| Some((id, self.transactions.get(id)?))) {
+            return Err(ChainError::Storage(format!("transaction {id} is already in block {indexed}")));
+        }
+        for (address, delta) in balance_changes(block) {
+            *self.balances.entry(address.to_string()).or_default() += delta;
+        }
+        for transaction in block.transactions.values() {
+            self.transactions.insert(transaction.id().to_string(), hash.to_string());
+            for address in transaction_addresses(transaction) {
+                self.addresses.entry(address.to_string()).or_default().push(transaction.id().to_string());
+            }
+        }
+        self.hashes.push(hash.to_string());
+        self.headers.insert(hash.to_string(), block.header.clone());
+        self.blocks.insert(hash.to_string(), block.clone());
+        Ok(())
+    }
+
+    fn truncate(&mut self, height: u32) -> Result<(), ChainError> {
+        if height < self.pruned_height {
+            return Err(ChainError::Storage(format!("cannot drop blocks below the pruned height {}", self.pruned_height)));
+        }
+        for hash in self.hashes.drain((height as usize).min(self.hashes.len())..) {
+            self.headers.remove(&hash);
+            if let Some(block) = self.blocks.remove(&hash) {
+                for (address, delta) in balance_changes(&block) {
+                    *self.balances.entry(address.to_string()).or_default() -= delta;
+                }
+                for transaction in block.transactions.values() {
+                    self.transactions.remove(transaction.id());
+                    for address in transaction_addresses(transaction) {
+                        if let Some(ids) = self.addresses.get_mut(address) {
+                            ids.retain(|id| id != transaction.id());
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // nothing in memory can be damaged
+    fn reset(&mut self, height: u32) -> Result<(), ChainError> {
+        self.truncate(height)
+    }
+
+    fn pruned_height(&self) -> Result<u32, ChainError> {
+        Ok(self.pruned_height)
+    }
+
+    fn prune(&mut self, height: u32) -> Result<u32, ChainError> {
+        let to = height.min(self.hashes.len() as u32);
+        let from = self.pruned_height;
+        for hash in self.hashes.iter().take(to as usize).skip(from as usize) {
+            self.blocks.remove(hash);
+        }
+        self.pruned_height = self.pruned_height.max(to);
+        Ok(to.saturating_sub(from))
+    }
+
+    fn flush(&self) -> Result<(), ChainError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use serde_derive::{Deserialize, Serialize};
+
+use super::blockchain_core::{Chain, Transaction};
+
+// the merkle root of a block. leaves are the transaction hashes in merkle
+// order. an odd leaf count is padded once with the last leaf, then pairs are
+// taken from the front of the queue and their parent pushed to the back
+pub fn merkle_root(leaves: Vec<String>) -> String {
+    let mut queue = padded(leaves);
+    while queue.len() > 1 {
+        let left = queue.remove(0);
+        let right = queue.remove(0);
+        queue.push(parent(&left, &right));
+    }
+    // a genesis block without premine has no transactions
+    queue.pop().unwrap_or_else(|| "0".repeat(64))
+}
+
+// the hashes needed to get from the leaf at `index` to the root
+pub fn merkle_proof(leaves: Vec<String>, index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut queue: Vec<(String, bool)> = padded(leaves).into_iter()
+        .enumerate()
+        .map(|(i, hash)| (hash, i == index))
+        .collect();
+    let mut path = vec![];
+    while queue.len() > 1 {
+        let (left, left_tracked) = queue.remove(0);
+        let (right, right_tracked) = queue.remove(0);
+        if left_tracked {
+            path.push(ProofStep { hash: right.clone(), left: false });
+        } else if right_tracked {
+            path.push(ProofStep { hash: left.clone(), left: true });
+        }
+        queue.push((parent(&left, &right), left_tracked || right_tracked));
+    }
+    Some(MerkleProof { path })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProofStep {
+    pub hash: String,
+    // the sibling is the left half of the pair
+    pub left: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    pub path: Vec<ProofStep>,
+}
+
+impl MerkleProof {
+    // the root reached from `leaf`. the proof holds if it is the block's merkle
+    pub fn root(&self, leaf: &str) -> String {
+        self.path.iter().fold(leaf.to_string(), |hash, step| match step.left {
+            true => parent(&step.hash, &hash),
+            false => parent(&hash, &step.hash),
+        })
+    }
+}
+
+// a transaction and the path from it to the merkle root of the block holding it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransactionProof {
+    pub block_hash: String,
+    pub transaction: Transaction,
+    pub proof: MerkleProof,
+}
+
+impl TransactionProof {
+    pub fn root(&self) -> String {
+        self.proof.root(&Chain::hash(&self.transaction))
+    }
+}
+
+fn padded(mut leaves: Vec<String>) -> Vec<String> {
+    if leaves.len() % 2 == 1 {
+        let last = leaves[leaves.len() - 1].clone();
+        leaves.push(last);
+    }
+    leaves
+}
+
+fn parent(left: &str, right: &str) -> String {
+    Chain::hash(&format!("{left}{right}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: usize) -> Vec<String> {
+        (0..n).map(|i| Chain::hash(&i)).collect()
+    }
+
+    #[test]
+    fn proves_every_leaf() {
+        for n in 1..=9 {
+            let root = merkle_root(leaves(n));
+            for (i, leaf) in leaves(n).iter().enumerate() {
+                let proof = merkle_proof(leaves(n), i).unwrap();
+                assert_eq!(proof.root(leaf), root, "leaf {i} of {n}");
+            }
+        }
+        assert!(merkle_proof(leaves(3), 3).is_none());
+    }
+
+    #[test]
+    fn rejects_another_leaf_or_a_changed_path() {
+        let root = merkle_root(leaves(5));
+        let mut proof = merkle_proof(leaves(5), 2).unwrap();
+        assert_ne!(proof.root(&leaves(5)[3]), root);
+
+        proof.path[0].left = !proof.path[0].left;
+        assert_ne!(proof.root(&leaves(5)[2]), root);
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::seen_cache::CacheStats;
+
+// (label, label) pairs of a counter family
+type Labels = (&'static str, &'static str);
+
+// process wide counters rendered in the prometheus text format by GET /metrics
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Clone, Copy)]
+pub enum Traffic {
+    In,
+    Out,
+}
+
+impl Traffic {
+    fn label(self) -> &'static str {
+        match self {
+            Traffic::In => "in",
+            Traffic::Out => "out",
+        }
+    }
+}
+
+// values read from the chain when the metrics are scraped
+pub struct NodeGauges {
+    pub height: u32,
+    pub mempool_size: usize,
+    pub peers: usize,
+    pub seen_cache: CacheStats,
+}
+
+pub struct Metrics {
+    block_processing_micros: AtomicU64,
+    blocks_processed: AtomicU64,
+    // f64 bits of the hashrate of the last mined block
+    hashrate: AtomicU64,
+    // (direction, message type) -> (messages, bytes)
+    traffic: Mutex<BTreeMap<Labels, (u64, u64)>>,
+    // (item, reason) -> count
+    rejected: Mutex<BTreeMap<Labels, u64>>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            block_processing_micros: AtomicU64::new(0),
+            blocks_processed: AtomicU64::new(0),
+            hashrate: AtomicU64::new(0),
+            traffic: Mutex::new(BTreeMap::new()),
+            rejected: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe_block_processing(&self, elapsed: Duration) {
+        self.block_processing_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.blocks_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_hashrate(&self, hashes: u64, elapsed: Duration) {
+        let rate = hashes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        self.hashrate.store(rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn record_traffic(&self, direction: Traffic, kind: &'static str, messages: u64, bytes: u64) {
+        let mut traffic = self.traffic.lock().unwrap();
+        let entry = traffic.entry((direction.label(), kind)).or_default();
+        entry.0 += messages;
+        entry.1 += bytes;
+    }
+
+    pub fn reject_block(&self, reason: &'static str) {
+        *self.rejected.lock().unwrap().entry(("block", reason)).or_default() += 1;
+    }
+
+    pub fn reject_transaction(&self, reason: &'static str) {
+        *self.rejected.lock().unwrap().entry(("transaction", reason)).or_default() += 1;
+    }
+
+    pub fn render(&self, gauges: &NodeGauges) -> String {
+        let mut out = String::new();
+
+        gauge(&mut out, "edblock_chain_height", "Number of blocks in the local chain", gauges.height as f64);
+        gauge(&mut out, "edblock_mempool_size", "Transactions waiting to be mined", gauges.mempool_size as f64);
+        gauge(&mut out, "edblock_peers", "Connected peers", gauges.peers as f64);
+        gauge(&mut out, "edblock_mining_hashrate", "Hashes per second while mining the last block",
+            f64::from_bits(self.hashrate.load(Ordering::Relaxed)));
+
+        let seen = &gauges.seen_cache;
+        gauge(&mut out, "edblock_seen_cache_entries", "Message hashes in the gossip dedup cache", seen.entries as f64);
+        gauge(&mut out, "edblock_seen_cache_hit_rate", "Share of gossip messages dropped as already seen", seen.hit_rate);
+        counter(&mut out, "edblock_seen_cache_hits_total", "Gossip messages dropped as already seen", seen.hits);
+        counter(&mut out, "edblock_seen_cache_misses_total", "Gossip messages seen for the first time", seen.misses);
+        counter(&mut out, "edblock_seen_cache_evictions_total", "Hashes dropped from the full dedup cache", seen.evictions);
+
+        let micros = self.block_processing_micros.load(Ordering::Relaxed);
+        let count = self.blocks_processed.load(Ordering::Relaxed);
+        let _ = writeln!(out, "# HELP edblock_block_processing_seconds Time spent validating and storing blocks received from peers");
+        let _ = writeln!(out, "# TYPE edblock_block_processing_seconds summary");
+        let _ = writeln!(out, "edblock_block_processing_seconds_sum {}", micros as f64 / 1_000_000.0);
+        let _ = writeln!(out, "edblock_block_processing_seconds_count {count}");
+
+        let traffic = self.traffic.lock().unwrap();
+        let _ = writeln!(out, "# HELP edblock_messages_total Peer messages by direction and type");
+        let _ = writeln!(out, "# TYPE edblock_messages_total counter");
+        for ((direction, kind), (messages, _)) in traffic.iter() {
+            let _ = writeln!(out, "edblock_messages_total{{direction=\"{direction}\",type=\"{kind}\"}} {messages}");
+        }
+        let _ = writeln!(out, "# HELP edblock_bytes_total Peer message bytes by direction and type");
+        let _ = writeln!(out, "# TYPE edblock_bytes_total counter");
+        for ((direction, kind), (_, bytes)) in traffic.iter() {
+            let _ = writeln!(out, "edblock_bytes_total{{direction=\"{direction}\",type=\"{kind}\"}} {bytes}");
+        }
+        drop(traffic);
+
+        let _ = writeln!(out, "# HELP edblock_rejected_total Rejected blocks and transactions by reason");
+        let _ = writeln!(out, "# TYPE edblock_rejected_total counter");
+        for ((item, reason), count) in self.rejected.lock().unwrap().iter() {
+            let _ = writeln!(out, "edblock_rejected_total{{item=\"{item}\",reason=\"{reason}\"}} {count}");
+        }
+
+        out
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} gauge");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    let _ = writeln!(out, "{name} {value}");
+}
@@ -1,16 +1,69 @@
 use uuid::Uuid;
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::tcp::{OwnedReadHalf, OwnedWriteHalf}};
-use core::str;
-use std::{collections::HashMap, str::FromStr, sync::Arc};
-
-use super::blockchain_core::{Block, Transaction};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use chrono::Utc;
+use tracing::{debug, info, info_span, warn, Instrument};
+use std::{collections::{HashMap, HashSet}, net::{IpAddr, Ipv4Addr, SocketAddr}, path::PathBuf, str::FromStr, sync::Arc};
+
+use super::blockchain_core::{Block, Chain, Transaction};
+use super::chain_spec::ChainSpec;
+use super::seen_cache::SeenCache;
+use super::metrics::{Traffic, METRICS};
+use super::events::{ChainEvent, EventReceiver, EventSender, EVENT_CAPACITY};
+use super::peer_registry::{Direction, PeerInfo, PeerLimits, PeerRegistry, RateLimiter};
+use super::secure_transport::{self, FrameReader, FrameWriter, NodeKey, PeerSession, Transport};
+
+// capacity of the incoming and outgoing message bus. producers wait when it is full
+pub const BUS_CAPACITY: usize = 1024;
+// frames queued for a single peer before new broadcasts to it are dropped
+pub const PEER_QUEUE_CAPACITY: usize = 256;
+
+pub type MessageSender = mpsc::Sender<Message>;
+pub type MessageReceiver = mpsc::Receiver<Message>;
+// encoded frames handed to the writer task of a peer
+pub type PeerSender = mpsc::Sender<Arc<Vec<u8>>>;
+
+// first bytes sent by the initiator of a plaintext connection
+pub const PLAIN_HANDSHAKE_TAG: &[u8; 7] = b"HNDSHK ";
+
+// time a connection gets to identify its protocol and finish the handshake
+pub const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
 
 #[derive(Debug)]
 pub enum Protocol {
     Handshake, //handshake (permanent connection)
+    SecureHandshake, // encrypted and authenticated handshake
     Unknown
 }
 
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    // interface the peer listener binds to (0.0.0.0 or :: for all interfaces)
+    pub bind_addr: IpAddr,
+    // only accept encrypted connections authenticated by the node key
+    pub secure: bool,
+    // None uses a throwaway key, for nodes that need no stable id
+    pub key_path: Option<PathBuf>,
+    // node ids allowed to connect, None accepts every peer
+    pub trusted_peers: Option<HashSet<Uuid>>,
+    pub limits: PeerLimits,
+    // id of the chain spec, see `ChainSpec::network_id`
+    pub network_id: String,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            secure: false,
+            key_path: Some(PathBuf::from("node.key")),
+            trusted_peers: None,
+            limits: PeerLimits::default(),
+            network_id: ChainSpec::default().network_id(),
+        }
+    }
+}
+
 #[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize, Clone)]
 pub struct Message {
     pub uuid: String,
@@ -20,204 +73,292 @@ pub struct Message {
     pub message_hash: String,
 }
 
+impl Message {
+    // hash of the payload computed locally. `message_hash` is supplied by the
+    // sender and is never trusted for deduplication. blocks are identified by
+    // their header, the transaction map serializes in a different order on
+    // every node
+    pub fn content_hash(&self) -> String {
+        Chain::hash(&(self.block.as_ref().map(|block| &block.header), &self.transaction))
+    }
+
+    // label used for the per message type metrics
+    pub fn kind(&self) -> &'static str {
+        match (&self.block, &self.transaction) {
+            (Some(_), _) => "block",
+            (None, Some(_)) => "transaction",
+            (None, None) => "empty",
+        }
+    }
+}
+
+// a parsed plaintext handshake message
+struct Handshake {
+    code: String,
+    network_id: String,
+    node_id: Uuid,
+    server_addr: String,
+}
+
+// a malformed message is an error, so the connection is dropped
+async fn read_handshake(stream: &mut tokio::net::TcpStream) -> std::io::Result<Handshake> {
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+    let frame = secure_transport::read_handshake_frame(stream).await?;
+    let text = String::from_utf8(frame).map_err(|_| invalid("handshake is not utf-8"))?;
+    match text.split(' ').collect::<Vec<_>>()[..] {
+        [code, network_id, node_id, server_addr] => Ok(Handshake {
+            code: code.to_string(),
+            network_id: network_id.to_string(),
+            node_id: Uuid::from_str(node_id).map_err(|_| invalid("malformed node id in the handshake"))?,
+            server_addr: server_addr.to_string(),
+        }),
+        _ => Err(invalid("malformed handshake")),
+    }
+}
+
 #[derive(Clone)]
 pub struct Node {
     pub node_id: Uuid,
     pub port: u16,
+    pub bind_addr: IpAddr,
 
-    pub write_streams: Arc<tokio::sync::Mutex<HashMap<Uuid, OwnedWriteHalf>>>,
+    pub write_streams: Arc<tokio::sync::Mutex<HashMap<Uuid, PeerSender>>>,
 
     pub peer_connected: Arc<tokio::sync::Mutex<Vec<Uuid>>>,
     pub peer_server_addr: Arc<tokio::sync::Mutex<Vec<String>>>,
 
-    pub msg_incoming_tx: crossbeam_channel::Sender<Message>,
-    pub msg_incoming_rx: crossbeam_channel::Receiver<Message>,
+    pub msg_incoming_tx: MessageSender,
+    msg_incoming_rx: Arc<tokio::sync::Mutex<Option<MessageReceiver>>>,
 
-    pub msg_outgoing_tx: crossbeam_channel::Sender<Message>,
-    // msg_outgoing_rx: crossbeam_channel::Receiver<Message>,
+    pub msg_outgoing_tx: MessageSender,
 
-    pub msg_hashes: Arc<tokio::sync::Mutex<HashMap<String, bool>>>,
+    pub msg_hashes: Arc<tokio::sync::Mutex<SeenCache>>,
+    pub transport: Arc<Transport>,
+    pub registry: Arc<tokio::sync::Mutex<PeerRegistry>>,
+    events: EventSender,
+    // parent span carrying the node id for everything the node spawns
+    span: tracing::Span,
+    pub started_at: std::time::Instant,
     server_addr: String,
 }
 
 impl Node {
-    pub async fn new(port: u16, server_addr: String) -> Node {
+    pub async fn new(port: u16, server_addr: String, config: NodeConfig) -> Node {
 
-        let (msg_incoming_tx, msg_incoming_rx) = crossbeam_channel::unbounded();
-        let (msg_outgoing_tx, msg_outgoing_rx) = crossbeam_channel::unbounded();
+        let (msg_incoming_tx, msg_incoming_rx) = mpsc::channel(BUS_CAPACITY);
+        let (msg_outgoing_tx, msg_outgoing_rx) = mpsc::channel(BUS_CAPACITY);
 
         let write_streams =  Arc::new(tokio::sync::Mutex::new(HashMap::new()));
-        let msg_hashes = Arc::new(tokio::sync::Mutex::new(HashMap::new()));
+        let msg_hashes = Arc::new(tokio::sync::Mutex::new(SeenCache::default()));
         let peer_server_addr = Arc::new(tokio::sync::Mutex::new(Vec::new()));
 
-        let node_id = Uuid::new_v4();
+        let key = match &config.key_path {
+            Some(path) => NodeKey::load_or_generate(path).expect("Failed to load the node key"),
+            None => NodeKey::generate(),
+        };
+        let node_id = key.node_id();
+        let transport = Arc::new(Transport {
+            key,
+            secure: config.secure,
+            trusted_peers: config.trusted_peers,
+            network_id: config.network_id,
+        });
+        let registry = Arc::new(tokio::sync::Mutex::new(PeerRegistry::new(config.limits)));
+        let (events, _) = tokio::sync::broadcast::channel(EVENT_CAPACITY);
 
-        println!("Node id: {node_id}");
+        let span = info_span!("node", %node_id);
+        info!(parent: &span, secure = transport.secure, "Node started");
 
-        Self::send_msg(msg_outgoing_rx.clone(), write_streams.clone(), msg_hashes.clone()).await;
-        // Self::message_reciever(msg_incoming_rx.clone(), msg_outgoing_tx.clone()).await;
+        Self::send_msg(msg_outgoing_rx, write_streams.clone(), msg_hashes.clone()).instrument(span.clone()).await;
 
         Node {
             node_id,
             port,
+            bind_addr: config.bind_addr,
 
             peer_connected: Arc::new(tokio::sync::Mutex::new(Vec::new())),
             peer_server_addr,
             write_streams,
 
             msg_incoming_tx,
-            msg_incoming_rx,
+            msg_incoming_rx: Arc::new(tokio::sync::Mutex::new(Some(msg_incoming_rx))),
 
             msg_outgoing_tx,
-            // msg_outgoing_rx,
             msg_hashes,
+            transport,
+            registry,
+            events,
+            span,
+            started_at: std::time::Instant::now(),
             server_addr
         }
     }
 
-    // async fn message_reciever(
-    //     msg_incoming_rx: crossbeam_channel::Receiver<Message>,
-    //     msg_outgoing_tx: crossbeam_channel::Sender<Message>
-    // ) {
-    //     tokio::spawn(async move {
-    //         let reciever = msg_incoming_rx;
-    //         while let Ok(msg) = reciever.recv() {
-    //             println!("{msg:?}");
-    //             if let Err(e) = msg_outgoing_tx.send(msg) {
-    //                 eprintln!("Cannot transmit the message to internal reciever: {e}")
-    //             }
-    //         }
-    //     });
-    // }
-
+    // the incoming messages have a single consumer, the first caller takes the receiver
+    pub async fn take_receiver(&self) -> Option<MessageReceiver> {
+        self.msg_incoming_rx.lock().await.take()
+    }
 
     pub fn get_id(&self) -> Uuid {
         self.node_id
     }
 
-    pub async fn server_listen(&self) -> tokio::task::JoinHandle<u32> {
+    pub fn span(&self) -> tracing::Span {
+        self.span.clone()
+    }
 
-        let port = self.port;
-        let node_id = self.node_id.clone();
+    pub fn subscribe(&self) -> EventReceiver {
+        self.events.subscribe()
+    }
 
-        let peer_connected = self.peer_connected.clone();
-        let peer_server_addr = self.peer_server_addr.clone();
-        let write_streams = self.write_streams.clone();
-        let msg_incoming_tx = self.msg_incoming_tx.clone();
-        let msg_hashes = self.msg_hashes.clone();
-        let server_addr = self.server_addr.clone();
+    // events are dropped when nobody is subscribed
+    pub fn publish(&self, event: ChainEvent) {
+        let _ = self.events.send(event);
+    }
 
-        tokio::spawn(async move {
+    pub fn listen_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_addr, self.port)
+    }
 
-            let node_id = node_id.clone();
-            let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}",port)).await.expect("Failed to bind the socket to the address");
+    // REST server address advertised to peers during the handshake
+    pub fn server_addr(&self) -> &str {
+        &self.server_addr
+    }
 
-            println!("Server listening on port {}",port);
+    // binds before returning, so with port 0 `self.port` holds the port picked by the OS
+    pub async fn server_listen(&mut self) -> tokio::task::JoinHandle<u32> {
 
-            loop {
+        let listen_addr = self.listen_addr();
+        let listener = tokio::net::TcpListener::bind(listen_addr).await.expect("Failed to bind the socket to the address");
+        let listen_addr = listener.local_addr().expect("Failed to get the listening address");
+        self.port = listen_addr.port();
+
+        let node = self.clone();
 
-                let peer_connected = peer_connected.clone();
-                let peer_server_addr = peer_server_addr.clone();
-                let write_streams = write_streams.clone();
-                let msg_incoming_tx = msg_incoming_tx.clone();
-                let msg_hashes = msg_hashes.clone();
-                let server_addr = server_addr.clone();
+        let span = self.span.clone();
+        tokio::spawn(async move {
 
+            info!(%listen_addr, "Server listening");
+
+            loop {
                 if let Ok((stream, addr)) = listener.accept().await {
-                    println!("New Peer Connected: {addr}");
+                    {
+                        let mut registry = node.registry.lock().await;
+                        if registry.is_banned(&addr.ip()) {
+                            info!(%addr, "Rejected connection from banned address");
+                            continue;
+                        }
+                        // connections still in the handshake hold a slot too
+                        if !registry.reserve_inbound() {
+                            info!(%addr, "Rejected connection. Inbound peer limit reached");
+                            continue;
+                        }
+                    }
+                    debug!(%addr, "New peer connection");
+                    let node = node.clone();
+                    let span = info_span!(parent: &node.span, "inbound", %addr);
                     tokio::spawn(async move {
-                        Self::handle_connection(stream, node_id, peer_connected, peer_server_addr, write_streams, msg_incoming_tx,msg_hashes,server_addr).await;
-                    });
+                        let registry = node.registry.clone();
+                        node.handle_connection(stream, addr).await;
+                        registry.lock().await.release_inbound();
+                    }.instrument(span));
                 }
             }
-        })
-    }
-
-    async fn handle_connection (
-        mut stream: tokio::net::TcpStream, 
-        node_id: Uuid, 
-        peer_connected: Arc<tokio::sync::Mutex<Vec<Uuid>>>,
-        peer_server_addr: Arc<tokio::sync::Mutex<Vec<String>>>,
-        write_streams: Arc<tokio::sync::Mutex<HashMap<Uuid, OwnedWriteHalf>>>,
-        msg_incoming_tx: crossbeam_channel::Sender<Message>,
-        msg_hashes: Arc<tokio::sync::Mutex<HashMap<String,bool>>>,
-        server_addr: String,
-    ) {
-
-        tokio::spawn(async move {
-            let protocol = Self::detect_protocol(&mut stream).await;
+        }.instrument(span))
+    }
 
-            match protocol {
-                Protocol::Handshake => {
-                    Self::handle_handshake(stream,  peer_connected, peer_server_addr, node_id, write_streams, msg_incoming_tx, msg_hashes, server_addr).await;
-                },
-                _ => {
-                    println!("Unknown protocol or format")
+    async fn handle_connection(self, mut stream: tokio::net::TcpStream, addr: SocketAddr) {
+        let deadline = tokio::time::Instant::now() + HANDSHAKE_TIMEOUT;
+        let protocol = match tokio::time::timeout_at(deadline, Self::detect_protocol(&mut stream)).await {
+            Ok(protocol) => protocol,
+            Err(_) => {
+                warn!("Handshake timed out");
+                return
+            }
+        };
+
+        match protocol {
+            Protocol::Handshake if self.transport.secure => {
+                info!("Plaintext handshake refused. Only secure connections are accepted");
+                let _ = secure_transport::write_frame(&mut stream, &self.handshake_msg("00")).await;
+            },
+            Protocol::Handshake => {
+                self.handle_handshake(stream, addr, deadline).await;
+            },
+            Protocol::SecureHandshake if self.transport.secure => {
+                let response = secure_transport::respond(stream, &self.transport, &self.server_addr);
+                match tokio::time::timeout_at(deadline, response).await {
+                    Ok(Ok(session)) => {
+                        self.register_session(session, Direction::Inbound).await;
+                    }
+                    Ok(Err(e)) => warn!(error = %e, "Secure handshake failed"),
+                    Err(_) => warn!("Secure handshake timed out"),
                 }
+            },
+            _ => {
+                warn!("Unknown protocol or format")
             }
-        });
-
+        }
     }
 
-    async fn handle_handshake(
-        mut stream: tokio::net::TcpStream,
-        peer_connected: Arc<tokio::sync::Mutex<Vec<Uuid>>>,
-        peer_server_addr: Arc<tokio::sync::Mutex<Vec<String>>>,
-        node_id: Uuid,
-        write_streams: Arc<tokio::sync::Mutex<HashMap<Uuid, OwnedWriteHalf>>>,
-        msg_incoming_tx: crossbeam_channel::Sender<Message>,
-        msg_hashes: Arc<tokio::sync::Mutex<HashMap<String,bool>>>,
-        server_addr: String
-    ) {
-
-        let handshake_msg = format!("HNDSHK 10 {} {}", node_id, server_addr);
-        let msg_len = handshake_msg.as_bytes().len();
-        let mut peer_request = vec![0 as u8; msg_len];
-
-        if let Err(e) = stream.read_exact(&mut peer_request).await {
-            println!("Handshake failed due to {e}");
+    async fn handle_handshake(self, mut stream: tokio::net::TcpStream, addr: SocketAddr, deadline: tokio::time::Instant) {
+        let request = async {
+            let mut tag = [0u8; 7];
+            stream.read_exact(&mut tag).await?;
+            read_handshake(&mut stream).await
+        };
+        let request = match tokio::time::timeout_at(deadline, request).await {
+            Ok(Ok(request)) => request,
+            Ok(Err(e)) => {
+                warn!(error = %e, "Handshake failed");
+                return
+            }
+            Err(_) => {
+                warn!("Handshake timed out");
+                return
+            }
+        };
+
+        let accepted = if request.code != "01" {
+            warn!(code = request.code, "Invalid request. Handshake rejected");
+            false
+        } else if request.network_id != self.transport.network_id {
+            warn!(network_id = request.network_id, "Peer is on another network. Handshake rejected");
+            false
+        } else {
+            self.try_register(request.node_id, addr, &request.server_addr, Direction::Inbound).await
+        };
+        if !accepted {
+            let _ = secure_transport::write_frame(&mut stream, &self.handshake_msg("00")).await;
             return
         }
-        let response = String::from_utf8(peer_request).unwrap();
-        let response: Vec<&str> = response.split(" ").collect();
-        match response[..] {
-            // if proper request
-            ["HNDSHK", "01", uuid, peer_addr] => {
-                let mut peer_connected = peer_connected.lock().await;
-                let peer_uuid = Uuid::from_str(uuid).unwrap();
-                if !peer_connected.contains(&peer_uuid) {
 
-                    peer_connected.push(peer_uuid);
-
-                    println!("Peer {uuid} accepted with server ip {peer_addr}");
-
-                    // respond with success message
-                    stream.write_all(handshake_msg.as_bytes()).await.unwrap();
-
-                    let (read_half, write_half) = stream.into_split();
-                    let mut write_streams = write_streams.lock().await;
-
-                    write_streams.insert(peer_uuid, write_half); // add to write_streams for writing to the clients
+        // respond with success message
+        if let Err(e) = secure_transport::write_frame(&mut stream, &self.handshake_msg("10")).await {
+            warn!(error = %e, "Handshake failed");
+            self.remove_peer(request.node_id, &request.server_addr).await;
+            return
+        }
+        info!(peer_id = %request.node_id, server_addr = request.server_addr, "Peer accepted");
 
-                    let mut peer_server_addr = peer_server_addr.lock().await;
-                    peer_server_addr.push(peer_addr.to_string());
+        let (read_half, write_half) = stream.into_split();
+        self.start_peer(request.node_id, addr, request.server_addr, FrameReader::plain(read_half), FrameWriter::plain(write_half)).await;
+    }
 
-                    Self::peer_receiver(read_half, msg_incoming_tx, msg_hashes).await;
-                }
-            }
-            _ => {
-                println!("Invalid response. Handshake rejected");
-                let handshake_msg = format!("HNDSHK 00 {} {}", node_id, server_addr);
-                stream.write_all(handshake_msg.as_bytes()).await.unwrap();
-            }
-        }
+    // plaintext handshake: the tag, then a length prefixed frame holding
+    // <code> <network id> <node id> <REST address>. the REST address is
+    // configurable, so the messages of two nodes differ in length
+    fn handshake_msg(&self, code: &str) -> Vec<u8> {
+        format!("{code} {} {} {}", self.transport.network_id, self.node_id, self.server_addr).into_bytes()
     }
 
     async fn detect_protocol(stream: &mut tokio::net::TcpStream) -> Protocol {
         let mut buf = [0; 1024];
         if let Ok(n) = stream.peek(&mut buf).await {
-            if n >= 7 && &buf[..7] == b"HNDSHK " {
+            if n >= 7 && &buf[..7] == PLAIN_HANDSHAKE_TAG {
                 Protocol::Handshake
+            } else if n >= 7 && &buf[..7] == secure_transport::SECURE_HANDSHAKE_TAG {
+                Protocol::SecureHandshake
             } else {
                 Protocol::Unknown
             }
@@ -229,23 +370,21 @@ impl Node {
     // Connecting to peer server. if successful use this thread to recieve messages and add the WriteHalf to collection for writing
     pub async fn add_peer(&self, addr: String) {
 
-        let node_id = self.node_id;
-
-
-        let peer_connected = self.peer_connected.clone();
-        let write_streams = self.write_streams.clone();
-        let msg_incoming_tx = self.msg_incoming_tx.clone();
-        let msg_hashes = self.msg_hashes.clone();
-        let server_addr = self.server_addr.clone();
-        let peer_server_addr = self.peer_server_addr.clone();
+        let node = self.clone();
+        let span = info_span!(parent: &self.span, "outbound", %addr);
 
         tokio::spawn(async move {
-            println!("Connecting to {addr}...");
+            if !node.registry.lock().await.has_capacity(Direction::Outbound) {
+                info!("Not connecting. Outbound peer limit reached");
+                return
+            }
+
+            info!("Connecting to peer");
             let mut stream: Option<tokio::net::TcpStream> = None;
             while stream.is_none() {
                 match tokio::net::TcpStream::connect(addr.clone()).await {
                     Ok(s) => {
-                        println!("Successfully connected to {addr}. Waiting for handshake to complete");
+                        debug!("Connected. Waiting for handshake to complete");
                         stream = Some(s)
                     }
                     Err(_) => {
@@ -255,146 +394,423 @@ impl Node {
             }
 
             let mut stream = stream.unwrap();
-            let handshake_msg = format!("HNDSHK 01 {} {}",node_id, server_addr);
-            let msg_len = handshake_msg.as_bytes().len();
-            stream.write_all(handshake_msg.as_bytes()).await.unwrap();
-
-
-            let mut peer_response = vec![0 as u8; msg_len];
+            let socket_addr = match stream.peer_addr() {
+                Ok(socket_addr) => socket_addr,
+                Err(e) => {
+                    warn!(error = %e, "Connection lost");
+                    return
+                }
+            };
 
-            if let Err(e) = stream.read_exact(&mut peer_response).await {
-                println!("Handshake failed due to {e}");
+            if node.transport.secure {
+                let request = secure_transport::initiate(stream, &node.transport, &node.server_addr);
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, request).await {
+                    Ok(Ok(session)) => {
+                        node.register_session(session, Direction::Outbound).await;
+                    }
+                    Ok(Err(e)) => warn!(error = %e, "Secure handshake failed"),
+                    Err(_) => warn!("Secure handshake timed out"),
+                }
                 return
             }
 
-            let response = String::from_utf8(peer_response).unwrap();
-            let tokens: Vec<&str> = response.split(" ").into_iter().filter(|x| !x.is_empty()).collect();
-
-            match tokens[..] {
-                ["HNDSHK", "10", uuid, peer_addr] => {
-                    let mut peer_connected = peer_connected.lock().await;
-                    let peer_uuid = Uuid::from_str(uuid).unwrap();
-                    if !peer_connected.contains(&peer_uuid) {
+            let request = async {
+                stream.write_all(PLAIN_HANDSHAKE_TAG).await?;
+                secure_transport::write_frame(&mut stream, &node.handshake_msg("01")).await?;
+                read_handshake(&mut stream).await
+            };
+            let response = match tokio::time::timeout(HANDSHAKE_TIMEOUT, request).await {
+                Ok(Ok(response)) => response,
+                Ok(Err(e)) => {
+                    warn!(error = %e, "Handshake failed");
+                    return
+                }
+                Err(_) => {
+                    warn!("Handshake timed out");
+                    return
+                }
+            };
 
-                        peer_connected.push(peer_uuid);
+            let peer_uuid = response.node_id;
+            match response.code.as_str() {
+                "10" if response.network_id != node.transport.network_id => {
+                    warn!(peer_id = %peer_uuid, network_id = response.network_id, "Peer is on another network. Not connecting")
+                },
+                "10" => {
+                    if node.try_register(peer_uuid, socket_addr, &response.server_addr, Direction::Outbound).await {
 
-                        println!("Peer {peer_uuid} accepted with server ip {peer_addr}");
+                        info!(peer_id = %peer_uuid, server_addr = response.server_addr, "Peer accepted");
 
                         let (read_half, write_half) = stream.into_split();
-                        let mut write_streams = write_streams.lock().await;
-
-                        write_streams.insert(peer_uuid, write_half); // add to write_streams for writing to the clients
-
-                        let mut peer_server_addr = peer_server_addr.lock().await;
-                        peer_server_addr.push(peer_addr.to_string());
-
-                        Self::peer_receiver(read_half, msg_incoming_tx, msg_hashes).await;
-                        return
+                        node.start_peer(peer_uuid, socket_addr, response.server_addr, FrameReader::plain(read_half), FrameWriter::plain(write_half)).await;
                     }
                 },
-                ["HNDSHK", "00", uuid, peer_addr] => {
-                    println!("Handshake rejected from the peer {uuid} with peer server address {peer_addr}. may be already connected")
+                "00" => {
+                    info!(peer_id = %peer_uuid, server_addr = response.server_addr, "Handshake rejected by the peer. may be already connected")
                 },
                 _ => {
-                    println!("Invalid response or Handshake already established")
+                    warn!("Invalid response or Handshake already established")
                 },
             }
+        }.instrument(span));
+    }
+
+    // add an authenticated peer after the secure handshake, checking it against the allowlist
+    async fn register_session(self, session: PeerSession, direction: Direction) {
+        let peer_uuid = session.peer_id;
+        if peer_uuid == self.node_id {
+            debug!("Refusing connection to self");
+            return
+        }
+        if !self.transport.is_trusted(&peer_uuid) {
+            warn!(peer_id = %peer_uuid, "Peer is not in the trusted peers list. Connection rejected");
+            return
+        }
+
+        if !self.try_register(peer_uuid, session.addr, &session.peer_server_addr, direction).await {
+            return
+        }
+
+        info!(peer_id = %peer_uuid, server_addr = %session.peer_server_addr, "Peer authenticated");
+
+        self.start_peer(peer_uuid, session.addr, session.peer_server_addr, session.reader, session.writer).await;
+    }
+
+    // reserve a slot for the peer. fails if it is already connected or the connection limit is reached
+    async fn try_register(&self, peer_uuid: Uuid, addr: SocketAddr, peer_server_addr: &str, direction: Direction) -> bool {
+        let mut peer_connected = self.peer_connected.lock().await;
+        if peer_connected.contains(&peer_uuid) {
+            debug!(peer_id = %peer_uuid, "Peer already connected");
+            return false;
+        }
+
+        let registered = self.registry.lock().await.register(PeerInfo {
+            id: peer_uuid,
+            addr: addr.to_string(),
+            server_addr: peer_server_addr.to_string(),
+            direction,
+            connected_at: Utc::now().timestamp_millis(),
+            messages_in: 0,
+            bytes_in: 0,
+            rate_violations: 0,
         });
+        if !registered {
+            info!(peer_id = %peer_uuid, ?direction, "Peer rejected. Peer limit reached");
+            return false;
+        }
+
+        peer_connected.push(peer_uuid);
+        self.publish(ChainEvent::PeerConnected { peer_id: peer_uuid, addr: addr.to_string(), direction });
+        true
     }
 
-    async fn peer_receiver(mut stream: OwnedReadHalf, 
-        msg_incoming_tx: crossbeam_channel::Sender<Message>,
-        msg_hashes: Arc<tokio::sync::Mutex<HashMap<String,bool>>>
-    ) {
-        let msg_hashes = msg_hashes.clone();
-        tokio::spawn(async move {
-            loop {
+    async fn start_peer(&self, peer_uuid: Uuid, addr: SocketAddr, peer_server_addr: String, reader: FrameReader, writer: FrameWriter) {
+        // add to write_streams for writing to the clients
+        self.write_streams.lock().await.insert(peer_uuid, Self::spawn_peer_writer(peer_uuid, writer));
+        self.peer_server_addr.lock().await.push(peer_server_addr.clone());
 
-                let mut buff = vec![0 as u8; 8];
-                if let Err(e) = stream.read_exact(&mut buff).await {
-                    eprintln!("Connection closed. Failed to recieve the message: {e}");
-                    break;
-                }
-                let mut msg_len = [0u8; std::mem::size_of::<usize>()];
-                msg_len.copy_from_slice(&buff);
-                let msg_len = usize::from_be_bytes(msg_len);
+        self.clone().peer_receiver(reader, peer_uuid, addr, peer_server_addr).await;
+    }
+
+    // close the connection to a peer. returns false if the peer is not connected
+    pub async fn disconnect_peer(&self, peer_uuid: Uuid) -> bool {
+        let (task, peer) = {
+            let mut registry = self.registry.lock().await;
+            (registry.take_task(&peer_uuid), registry.get(&peer_uuid).cloned())
+        };
+        let Some(peer) = peer else {
+            return false;
+        };
+        if let Some(task) = task {
+            task.abort();
+        }
+        // dropping the writer closes our side of the connection
+        self.remove_peer(peer_uuid, &peer.server_addr).await;
+        info!(peer_id = %peer_uuid, "Peer disconnected");
+        true
+    }
 
-                buff.clear();
-                buff.resize(msg_len, 0);
+    // ban an address and close the connections from it. returns the number
+    // of peers disconnected
+    pub async fn ban(&self, addr: IpAddr, reason: &str) -> usize {
+        let peers = {
+            let mut registry = self.registry.lock().await;
+            registry.ban(addr, reason);
+            registry.peers_from(&addr)
+        };
+        let mut disconnected = 0;
+        for peer_uuid in peers {
+            if self.disconnect_peer(peer_uuid).await {
+                disconnected += 1;
+            }
+        }
+        disconnected
+    }
 
-                if let Err(e) = stream.read_exact(&mut buff).await {
-                    eprintln!("Connection closed. Failed to recieve the message: {e}");
-                    break;
-                }
+    // forget a peer whose connection closed
+    async fn remove_peer(&self, peer_uuid: Uuid, peer_server_addr: &str) {
+        if self.registry.lock().await.remove(&peer_uuid).is_some() {
+            self.publish(ChainEvent::PeerDisconnected { peer_id: peer_uuid });
+        }
+        self.write_streams.lock().await.remove(&peer_uuid);
+        self.peer_connected.lock().await.retain(|id| *id != peer_uuid);
 
-                let msg: Message= serde_json::from_str(str::from_utf8(&buff).unwrap()).unwrap();
-                // continue if the message is already recieved
+        let mut server_addrs = self.peer_server_addr.lock().await;
+        if let Some(pos) = server_addrs.iter().position(|a| a == peer_server_addr) {
+            server_addrs.remove(pos);
+        }
+    }
 
-                let data_hash = msg.message_hash.clone();
+    async fn peer_receiver(self, mut stream: FrameReader, peer_uuid: Uuid, addr: SocketAddr, peer_server_addr: String) {
+        let mut limiter = RateLimiter::new(&self.registry.lock().await.limits);
+        let registry = self.registry.clone();
+        let span = info_span!(parent: &self.span, "peer", peer_id = %peer_uuid, %addr);
+        let task = tokio::spawn(async move {
+            loop {
+
+                let buff = match stream.read_frame().await {
+                    Ok(buff) => buff,
+                    Err(e) => {
+                        info!(error = %e, "Connection closed");
+                        break;
+                    }
+                };
 
-                let mut msg_hashes = msg_hashes.lock().await;
-                if let Some(_) = msg_hashes.get(&data_hash) {
+                if !limiter.allow(buff.len()) {
+                    if self.registry.lock().await.record_violation(&peer_uuid) {
+                        warn!(ip = %addr.ip(), "Peer keeps exceeding the rate limit. Banning");
+                        self.registry.lock().await.ban(addr.ip(), "rate limit exceeded");
+                        break;
+                    }
                     continue;
                 }
+                self.registry.lock().await.record_message(&peer_uuid, buff.len());
 
-                msg_hashes.insert(data_hash, true);
+                let msg: Message = match serde_json::from_slice(&buff) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        warn!(error = %e, "Malformed message from peer");
+                        break;
+                    }
+                };
+                METRICS.record_traffic(Traffic::In, msg.kind(), 1, buff.len() as u64);
 
-                if let Err(e) = msg_incoming_tx.send(msg) {
-                    println!("Failed to send the message from client {} to message reciever due to {e}", stream.peer_addr().unwrap())
+                // continue if the message is already recieved
+                if !self.msg_hashes.lock().await.insert(msg.content_hash()) {
+                    continue;
+                }
+
+                // waits while the bus is full, which stops reading from this peer's socket
+                if let Err(e) = self.msg_incoming_tx.send(msg).await {
+                    warn!(error = %e, "Failed to pass the message to the message reciever");
+                    break;
                 }
             }
-        });
+            self.remove_peer(peer_uuid, &peer_server_addr).await;
+        }.instrument(span));
+        registry.lock().await.set_task(peer_uuid, task.abort_handle());
     }
 
-    // pub fn take_reciever(&mut self) ->crossbeam_channel::Receiver<Message> {
-    //     self.msg_incoming_rx.clone()
-    // }
-
-    // pub fn take_sender(&mut self) -> crossbeam_channel::Sender<Message> {
-    //     self.msg_outgoing_tx.clone()
-    // }
-
-    async fn send_msg(msg_outgoing_rx: crossbeam_channel::Receiver<Message>, 
-        write_streams: Arc<tokio::sync::Mutex<HashMap<Uuid, OwnedWriteHalf>>>,
-        msg_hashes: Arc<tokio::sync::Mutex<HashMap<String,bool>>>,
+    async fn send_msg(mut msg_outgoing_rx: MessageReceiver,
+        write_streams: Arc<tokio::sync::Mutex<HashMap<Uuid, PeerSender>>>,
+        msg_hashes: Arc<tokio::sync::Mutex<SeenCache>>,
     ) {
         let write_streams = write_streams.clone();
         let msg_hashes = msg_hashes.clone();
 
         tokio::spawn(async move {
-            while let Ok(data) = msg_outgoing_rx.recv() {
-
-                let mut msg_hashes = msg_hashes.lock().await;
-                let data_hash = data.message_hash.clone();
+            while let Some(data) = msg_outgoing_rx.recv().await {
 
                 // Add data hash to already seen
-                msg_hashes.insert(data_hash, true);
+                msg_hashes.lock().await.mark_seen(data.content_hash());
 
-                let msg = Self::encode_msg_bytes(&data);
+                // encode once and share the frame between all peers
+                let msg = Arc::new(serde_json::to_vec(&data).unwrap());
                 let mut write_stream = write_streams.lock().await;
 
                 let mut clients_to_remove = vec![];
-
-                for (&uuid, stream) in write_stream.iter_mut() {
-                    if let Err(e) = stream.write_all(&msg).await {
-                        println!("Failed to write to the peer {}: {e}",stream.peer_addr().unwrap());
-                        clients_to_remove.push(uuid)
+                let mut sent = 0;
+
+                // never wait on a peer here. a slow peer only loses its own messages
+                for (&uuid, peer) in write_stream.iter() {
+                    match peer.try_send(msg.clone()) {
+                        Ok(()) => sent += 1,
+                        Err(TrySendError::Full(_)) => warn!(peer_id = %uuid, "Peer is not keeping up. Message dropped"),
+                        Err(TrySendError::Closed(_)) => clients_to_remove.push(uuid),
                     }
                 }
+                METRICS.record_traffic(Traffic::Out, data.kind(), sent, sent * msg.len() as u64);
 
-                for &uuid in clients_to_remove.iter().rev() {
+                for uuid in clients_to_remove {
                     write_stream.remove(&uuid);
-                } 
+                }
             }
-        });
+        }.instrument(tracing::Span::current()));
+    }
+
+    // owns the write half of a peer connection and drains its queue
+    fn spawn_peer_writer(peer_uuid: Uuid, mut stream: FrameWriter) -> PeerSender {
+        let (peer_tx, mut peer_rx) = mpsc::channel::<Arc<Vec<u8>>>(PEER_QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(frame) = peer_rx.recv().await {
+                if let Err(e) = stream.write_frame(&frame).await {
+                    warn!(peer_id = %peer_uuid, error = %e, "Failed to write to the peer");
+                    break;
+                }
+            }
+        }.instrument(tracing::Span::current()));
+        peer_tx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    async fn test_node() -> Node {
+        let config = NodeConfig {
+            key_path: None,
+            limits: PeerLimits {
+                max_inbound: 64,
+                max_messages_per_sec: u32::MAX,
+                max_bytes_per_sec: u64::MAX,
+                ..PeerLimits::default()
+            },
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(0, String::from("127.0.0.1:0"), config).await;
+        node.server_listen().await;
+        node
     }
 
-    fn encode_msg_bytes(data: &Message) -> Vec<u8> {
-        let msg = serde_json::to_string(&data).unwrap();
-        let header = (msg.len()).to_be_bytes();
-        let mut msg_vec = Vec::with_capacity(msg.len() + header.len());
-        msg_vec.extend_from_slice(&header);
-        msg_vec.extend_from_slice(msg.as_bytes());
-        msg_vec
+    async fn plain_node(server_addr: &str) -> Node {
+        let config = NodeConfig {
+            key_path: None,
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(0, server_addr.to_string(), config).await;
+        node.server_listen().await;
+        node
     }
-}
\ No newline at end of file
+
+    async fn wait_for_peer(node: &Node, peer: &Node) {
+        let deadline = Instant::now() + Duration::from_secs(15);
+        while !node.peer_connected.lock().await.contains(&peer.get_id()) {
+            assert!(Instant::now() < deadline, "peer {} never connected", peer.get_id());
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn handshakes_with_rest_addresses_of_different_lengths() {
+        let short = plain_node("10.0.0.5:5000").await;
+        let long = plain_node("192.168.10.20:5000").await;
+        short.add_peer(long.listen_addr().to_string()).await;
+        wait_for_peer(&short, &long).await;
+        wait_for_peer(&long, &short).await;
+
+        assert_eq!(*short.peer_server_addr.lock().await, vec![String::from("192.168.10.20:5000")]);
+        assert_eq!(*long.peer_server_addr.lock().await, vec![String::from("10.0.0.5:5000")]);
+    }
+
+    #[tokio::test]
+    async fn drops_connections_with_a_malformed_handshake() {
+        let node = plain_node("127.0.0.1:0").await;
+        let mut stream = tokio::net::TcpStream::connect(node.listen_addr()).await.unwrap();
+        stream.write_all(PLAIN_HANDSHAKE_TAG).await.unwrap();
+        let request = format!("01 {} not-a-node-id 127.0.0.1:1", node.transport.network_id);
+        secure_transport::write_frame(&mut stream, request.as_bytes()).await.unwrap();
+
+        let mut reply = vec![];
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert!(reply.is_empty());
+        assert!(node.peer_connected.lock().await.is_empty());
+    }
+
+    async fn secure_node(trusted_peers: Option<HashSet<Uuid>>) -> Node {
+        let config = NodeConfig {
+            key_path: None,
+            secure: true,
+            trusted_peers,
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(0, String::from("127.0.0.1:0"), config).await;
+        node.server_listen().await;
+        node
+    }
+
+    #[tokio::test]
+    async fn refuses_peers_missing_from_the_allowlist() {
+        let (trusted, stranger) = (secure_node(None).await, secure_node(None).await);
+        let hub = secure_node(Some([trusted.get_id()].into())).await;
+        trusted.add_peer(hub.listen_addr().to_string()).await;
+        stranger.add_peer(hub.listen_addr().to_string()).await;
+
+        wait_for_peer(&hub, &trusted).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(*hub.peer_connected.lock().await, vec![trusted.get_id()]);
+    }
+
+    fn transaction_msg(node: &Node, i: usize) -> Message {
+        let transaction = Transaction::new(String::from("load"), format!("receiver {i}"), 1.0);
+        Message { uuid: node.get_id().to_string(), block: None, transaction: Some(transaction), message_hash: String::new() }
+    }
+
+    // broadcasts from one node to many peers, one of which never reads its messages.
+    // run with: cargo test --release broadcast_throughput -- --ignored --nocapture
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn broadcast_throughput() {
+        const PEERS: usize = 32;
+        const MESSAGES: usize = 5_000;
+
+        let hub = test_node().await;
+        let mut peers = Vec::new();
+        for _ in 0..=PEERS {
+            let peer = test_node().await;
+            peer.add_peer(hub.listen_addr().to_string()).await;
+            peers.push(peer);
+        }
+        while hub.write_streams.lock().await.len() < PEERS + 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // the last peer is stalled: its receiver is held but never drained
+        let _stalled = peers[PEERS].take_receiver().await.unwrap();
+
+        let mut readers = Vec::new();
+        for peer in &peers[..PEERS] {
+            let mut rx = peer.take_receiver().await.unwrap();
+            readers.push(tokio::spawn(async move {
+                let mut received = 0;
+                while let Ok(Some(_)) = tokio::time::timeout(Duration::from_secs(2), rx.recv()).await {
+                    received += 1;
+                }
+                received
+            }));
+        }
+
+        let start = Instant::now();
+        for i in 0..MESSAGES {
+            hub.msg_outgoing_tx.send(transaction_msg(&hub, i)).await.unwrap();
+        }
+        let sent = start.elapsed();
+
+        let mut delivered = 0;
+        for reader in readers {
+            let received = reader.await.unwrap();
+            assert!(received > 0, "a healthy peer was starved by the stalled one");
+            delivered += received;
+        }
+        // the readers stop after two idle seconds
+        let elapsed = start.elapsed().saturating_sub(Duration::from_secs(2)).max(sent);
+
+        eprintln!("{PEERS} peers (+1 stalled), {MESSAGES} messages broadcast in {sent:?}");
+        eprintln!(
+            "{delivered}/{} deliveries in {elapsed:?}: {:.0} msg/s",
+            PEERS * MESSAGES,
+            delivered as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
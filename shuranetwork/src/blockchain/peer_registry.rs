@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::task::AbortHandle;
+use uuid::Uuid;
+
+// rate limit violations tolerated before the peer is disconnected and banned
+pub const BAN_AFTER_VIOLATIONS: u32 = 50;
+
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct PeerLimits {
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+    pub max_messages_per_sec: u32,
+    pub max_bytes_per_sec: u64,
+    pub ban_secs: u64,
+}
+
+impl Default for PeerLimits {
+    fn default() -> Self {
+        PeerLimits {
+            max_inbound: 32,
+            max_outbound: 16,
+            max_messages_per_sec: 1000,
+            max_bytes_per_sec: 4 * 1024 * 1024,
+            ban_secs: 10 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct PeerInfo {
+    pub id: Uuid,
+    pub addr: String,
+    pub server_addr: String,
+    pub direction: Direction,
+    pub connected_at: i64,
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub rate_violations: u32,
+}
+
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct Ban {
+    pub addr: IpAddr,
+    pub reason: String,
+    pub expires_in_secs: u64,
+}
+
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct PeersSnapshot {
+    pub peers: Vec<PeerInfo>,
+    // inbound connections still in the handshake
+    #[serde(default)]
+    pub pending_inbound: usize,
+    pub bans: Vec<Ban>,
+    pub limits: PeerLimits,
+}
+
+// token buckets refilled every second with the configured message and byte
+// budget. a frame larger than the byte budget passes when the bucket is full
+// and leaves it in debt, so the average rate still holds
+pub struct RateLimiter {
+    max_messages: f64,
+    max_bytes: f64,
+    messages: f64,
+    bytes: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &PeerLimits) -> Self {
+        let max_messages = limits.max_messages_per_sec as f64;
+        let max_bytes = limits.max_bytes_per_sec as f64;
+        RateLimiter { max_messages, max_bytes, messages: max_messages, bytes: max_bytes, last: Instant::now() }
+    }
+
+    // false when the peer is over its message or byte budget
+    pub fn allow(&mut self, bytes: usize) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.messages = (self.messages + elapsed * self.max_messages).min(self.max_messages);
+        self.bytes = (self.bytes + elapsed * self.max_bytes).min(self.max_bytes);
+
+        if self.messages < 1.0 || self.bytes < (bytes as f64).min(self.max_bytes) {
+            return false;
+        }
+        self.messages -= 1.0;
+        self.bytes -= bytes as f64;
+        true
+    }
+}
+
+// connected peers, connection limits and banned addresses of a node
+pub struct PeerRegistry {
+    pub limits: PeerLimits,
+    peers: HashMap<Uuid, PeerInfo>,
+    // receiver task of each peer, aborted when the peer is disconnected on request
+    tasks: HashMap<Uuid, AbortHandle>,
+    bans: HashMap<IpAddr, (Instant, String)>,
+    // accepted connections that have not finished the handshake yet
+    pending_inbound: usize,
+}
+
+impl PeerRegistry {
+    pub fn new(limits: PeerLimits) -> Self {
+        PeerRegistry { limits, peers: HashMap::new(), tasks: HashMap::new(), bans: HashMap::new(), pending_inbound: 0 }
+    }
+
+    pub fn has_capacity(&self, direction: Direction) -> bool {
+        let max = match direction {
+            Direction::Inbound => self.limits.max_inbound,
+            Direction::Outbound => self.limits.max_outbound,
+        };
+        self.peers.values().filter(|peer| peer.direction == direction).count() < max
+    }
+
+    // takes an inbound slot for a connection until its handshake is over, so
+    // connections that never finish one still count against the limit
+    pub fn reserve_inbound(&mut self) -> bool {
+        let inbound = self.peers.values().filter(|peer| peer.direction == Direction::Inbound).count();
+        if inbound + self.pending_inbound >= self.limits.max_inbound {
+            return false;
+        }
+        self.pending_inbound += 1;
+        true
+    }
+
+    pub fn release_inbound(&mut self) {
+        self.pending_inbound = self.pending_inbound.saturating_sub(1);
+    }
+
+    // false if the connection limit for the direction was reached in the meantime
+    pub fn register(&mut self, peer: PeerInfo) -> bool {
+        if !self.has_capacity(peer.direction) {
+            return false;
+        }
+        self.peers.insert(peer.id, peer);
+        true
+    }
+
+    pub fn remove(&mut self, peer_id: &Uuid) -> Option<PeerInfo> {
+        self.tasks.remove(peer_id);
+        self.peers.remove(peer_id)
+    }
+
+    pub fn get(&self, peer_id: &Uuid) -> Option<&PeerInfo> {
+        self.peers.get(peer_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn set_task(&mut self, peer_id: Uuid, task: AbortHandle) {
+        if self.peers.contains_key(&peer_id) {
+            self.tasks.insert(peer_id, task);
+        }
+    }
+
+    pub fn take_task(&mut self, peer_id: &Uuid) -> Option<AbortHandle> {
+        self.tasks.remove(peer_id)
+    }
+
+    pub fn record_message(&mut self, peer_id: &Uuid, bytes: usize) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.messages_in += 1;
+            peer.bytes_in += bytes as u64;
+        }
+    }
+
+    // returns true once the peer crossed the violation threshold and should be banned
+    pub fn record_violation(&mut self, peer_id: &Uuid) -> bool {
+        match self.peers.get_mut(peer_id) {
+            Some(peer) => {
+                peer.rate_violations += 1;
+                peer.rate_violations >= BAN_AFTER_VIOLATIONS
+            }
+            None => false,
+        }
+    }
+
+    pub fn ban(&mut self, addr: IpAddr, reason: &str) {
+        let expiry = Instant::now() + Duration::from_secs(self.limits.ban_secs);
+        self.bans.insert(addr, (expiry, reason.to_string()));
+    }
+
+    // false if the address was not banned
+    pub fn unban(&mut self, addr: &IpAddr) -> bool {
+        self.expire_bans();
+        self.bans.remove(addr).is_some()
+    }
+
+    // peers connected from the address
+    pub fn peers_from(&self, addr: &IpAddr) -> Vec<Uuid> {
+        self.peers.values()
+            .filter(|peer| peer.addr.parse::<std::net::SocketAddr>().is_ok_and(|peer_addr| peer_addr.ip() == *addr))
+            .map(|peer| peer.id)
+            .collect()
+    }
+
+    pub fn is_banned(&mut self, addr: &IpAddr) -> bool {
+        self.expire_bans();
+        self.bans.contains_key(addr)
+    }
+
+    pub fn snapshot(&mut self) -> PeersSnapshot {
+        self.expire_bans();
+        let now = Instant::now();
+        PeersSnapshot {
+            peers: self.peers.values().cloned().collect(),
+            pending_inbound: self.pending_inbound,
+            bans: self.bans.iter().map(|(addr, (expiry, reason))| Ban {
+                addr: *addr,
+                reason: reason.clone(),
+                expires_in_secs: expiry.saturating_duration_since(now).as_secs(),
+            }).collect(),
+            limits: self.limits.clone(),
+        }
+    }
+
+    fn expire_bans(&mut self) {
+        let now = Instant::now();
+        self.bans.retain(|_, (expiry, _)| *expiry > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> PeerLimits {
+        PeerLimits { max_inbound: 2, max_outbound: 1, max_messages_per_sec: 10, max_bytes_per_sec: 1000, ban_secs: 60 }
+    }
+
+    fn peer(direction: Direction) -> PeerInfo {
+        PeerInfo {
+            id: Uuid::new_v4(),
+            addr: String::from("127.0.0.1:1"),
+            server_addr: String::from("127.0.0.1:2"),
+            direction,
+            connected_at: 0,
+            messages_in: 0,
+            bytes_in: 0,
+            rate_violations: 0,
+        }
+    }
+
+    #[test]
+    fn limits_messages_and_bytes_until_the_buckets_refill() {
+        let mut limiter = RateLimiter::new(&limits());
+        for _ in 0..10 {
+            assert!(limiter.allow(10));
+        }
+        assert!(!limiter.allow(10));
+
+        // half a second refills half of the budget
+        limiter.last -= Duration::from_millis(500);
+        for _ in 0..5 {
+            assert!(limiter.allow(10));
+        }
+        assert!(!limiter.allow(10));
+
+        limiter.last -= Duration::from_secs(10);
+        assert!(limiter.allow(1000));
+        assert!(!limiter.allow(1));
+
+        // a frame over the whole budget waits for a full bucket, then runs it
+        // into debt
+        limiter.last -= Duration::from_millis(500);
+        assert!(!limiter.allow(1500));
+        limiter.last -= Duration::from_secs(10);
+        assert!(limiter.allow(1500));
+        limiter.last -= Duration::from_millis(400);
+        assert!(!limiter.allow(1));
+    }
+
+    #[test]
+    fn refuses_peers_once_a_direction_is_full() {
+        let mut registry = PeerRegistry::new(limits());
+        assert!(registry.register(peer(Direction::Inbound)));
+        assert!(registry.register(peer(Direction::Inbound)));
+        assert!(!registry.has_capacity(Direction::Inbound));
+        assert!(!registry.register(peer(Direction::Inbound)));
+
+        let outbound = peer(Direction::Outbound);
+        assert!(registry.register(outbound.clone()));
+        assert!(!registry.register(peer(Direction::Outbound)));
+        assert_eq!(registry.len(), 3);
+
+        registry.remove(&outbound.id);
+        assert!(registry.register(peer(Direction::Outbound)));
+    }
+
+    #[test]
+    fn counts_handshakes_against_the_inbound_limit() {
+        let mut registry = PeerRegistry::new(limits());
+        assert!(registry.reserve_inbound());
+        assert!(registry.reserve_inbound());
+        assert!(!registry.reserve_inbound());
+
+        // a finished handshake hands its slot to the registered peer
+        registry.release_inbound();
+        assert!(registry.register(peer(Direction::Inbound)));
+        assert!(!registry.reserve_inbound());
+        registry.release_inbound();
+        assert!(registry.reserve_inbound());
+        assert_eq!(registry.snapshot().pending_inbound, 1);
+    }
+
+    #[test]
+    fn bans_after_repeated_violations_until_the_ban_expires() {
+        let mut registry = PeerRegistry::new(limits());
+        let offender = peer(Direction::Inbound);
+        registry.register(offender.clone());
+        for _ in 1..BAN_AFTER_VIOLATIONS {
+            assert!(!registry.record_violation(&offender.id));
+        }
+        assert!(registry.record_violation(&offender.id));
+
+        let ip: IpAddr = "10.0.0.5".parse().unwrap();
+        registry.ban(ip, "rate limit exceeded");
+        assert!(registry.is_banned(&ip));
+        assert!(!registry.is_banned(&"10.0.0.6".parse().unwrap()));
+        assert_eq!(registry.snapshot().bans.len(), 1);
+        assert_eq!(registry.peers_from(&"127.0.0.1".parse().unwrap()), vec![offender.id]);
+
+        assert!(registry.unban(&ip));
+        assert!(!registry.unban(&ip));
+        assert!(!registry.is_banned(&ip));
+        registry.ban(ip, "rate limit exceeded");
+
+        // move the expiry into the past
+        registry.bans.get_mut(&ip).unwrap().0 = Instant::now() - Duration::from_secs(1);
+        assert!(!registry.is_banned(&ip));
+        assert!(registry.snapshot().bans.is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+use std::str::FromStr;
+
+use secp256k1::ecdsa::Signature;
+use secp256k1::{Message, PublicKey, Secp256k1};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use signing::multisig::MultisigPolicy;
+use signing::Address;
+
+use super::address::derive_address;
+
+// conditions deeper than this are refused rather than evaluated
+const MAX_DEPTH: usize = 8;
+const MAX_MULTISIG_KEYS: usize = 16;
+
+// a spending condition. funds sent to the script address of a condition can
+// only be moved by a transaction revealing the condition and a witness that
+// satisfies it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    // a signature by the key of a key address
+    Key { address: String },
+    // signatures by `threshold` of the hex encoded public keys
+    Multisig { threshold: usize, public_keys: Vec<String> },
+    // only from the block at `height` on
+    AfterHeight { height: u32 },
+    // only in blocks stamped at or after `timestamp`, unix milliseconds
+    AfterTime { timestamp: i64 },
+    // the preimage of a hex encoded sha256
+    Hashlock { sha256: String },
+    All { conditions: Vec<Condition> },
+    Any { conditions: Vec<Condition> },
+}
+
+// what a spending transaction reveals next to the condition
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Witness {
+    #[serde(default)]
+    pub signatures: Vec<KeySignature>,
+    // hex encoded
+    #[serde(default)]
+    pub preimages: Vec<String>,
+}
+
+// hex encoded compressed public key and compact ecdsa signature
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeySignature {
+    pub public_key: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Spend {
+    pub condition: Condition,
+    pub witness: Witness,
+}
+
+// the block a transaction is evaluated for
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptContext {
+    pub height: u32,
+    // unix milliseconds
+    pub timestamp: i64,
+}
+
+impl Condition {
+    // the address funds locked by this condition are sent to
+    pub fn address(&self) -> String {
+        let digest = Sha256::digest(serde_json::to_vec(self).expect("condition serializes"));
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(&digest[12..32]);
+        Address::from_script_hash(hash).to_string()
+    }
+
+    // `digest` is the signing hash of the spending transaction
+    pub fn evaluate(&self, digest: &Message, witness: &Witness, context: &ScriptContext) -> Result<(), String> {
+        let signatures = witness.signatures.iter()
+            .map(|s| Ok((parse_public_key(&s.public_key)?, parse_signature(&s.signature)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        let preimages = witness.preimages.iter()
+            .map(|p| hex::decode(p).map_err(|_| format!("malformed preimage {p}")))
+            .collect::<Result<Vec<_>, String>>()?;
+        self.evaluate_at(0, &Evaluation { digest, signatures, preimages, context })
+    }
+
+    fn evaluate_at(&self, depth: usize, eval: &Evaluation) -> Result<(), String> {
+        if depth >= MAX_DEPTH {
+            return Err(format!("condition nested deeper than {MAX_DEPTH}"));
+        }
+        let secp = Secp256k1::verification_only();
+        match self {
+            Condition::Key { address } => {
+                let signed = eval.signatures.iter()
+                    .any(|(key, signature)| derive_address(key) == *address && secp.verify_ecdsa(eval.digest, signature, key).is_ok());
+                signed.then_some(()).ok_or_else(|| format!("no valid signature for {address}"))
+            }
+            Condition::Multisig { threshold, public_keys } => {
+                if public_keys.len() > MAX_MULTISIG_KEYS {
+                    return Err(format!("multisig of more than {MAX_MULTISIG_KEYS} keys"));
+                }
+                let keys = public_keys.iter().map(|key| parse_public_key(key)).collect::<Result<Vec<_>, String>>()?;
+                let policy = MultisigPolicy::new(*threshold, keys).map_err(|e| e.to_string())?;
+                let signatures: Vec<Signature> = eval.signatures.iter().map(|(_, signature)| *signature).collect();
+                let signers = policy.count_signers(eval.digest, &signatures);
+                (signers >= *threshold).then_some(()).ok_or_else(|| format!("{signers} of the {threshold} required signatures"))
+            }
+            Condition::AfterHeight { height } => (eval.context.height >= *height).then_some(())
+                .ok_or_else(|| format!("locked until height {height}")),
+            Condition::AfterTime { timestamp } => (eval.context.timestamp >= *timestamp).then_some(())
+                .ok_or_else(|| format!("locked until {timestamp}")),
+            Condition::Hashlock { sha256 } => {
+                let revealed = eval.preimages.iter().any(|preimage| hex::encode(Sha256::digest(preimage)) == sha256.to_lowercase());
+                revealed.then_some(()).ok_or_else(|| format!("no preimage of {sha256}"))
+            }
+            Condition::All { conditions } => conditions.iter().try_for_each(|condition| condition.evaluate_at(depth + 1, eval)),
+            Condition::Any { conditions } => {
+                let mut errors = vec![];
+                for condition in conditions {
+                    match condition.evaluate_at(depth + 1, eval) {
+                        Ok(()) => return Ok(()),
+                        Err(e) => errors.push(e),
+                    }
+                }
+                Err(format!("no branch satisfied ({})", errors.join(", ")))
+            }
+        }
+    }
+}
+
+// the parsed witness, shared by every node of a condition
+struct Evaluation<'a> {
+    digest: &'a Message,
+    signatures: Vec<(PublicKey, Signature)>,
+    preimages: Vec<Vec<u8>>,
+    context: &'a ScriptContext,
+}
+
+fn parse_public_key(key: &str) -> Result<PublicKey, String> {
+    PublicKey::from_str(key).map_err(|_| format!("malformed public key {key}"))
+}
+
+fn parse_signature(signature: &str) -> Result<Signature, String> {
+    hex::decode(signature).ok()
+        .and_then(|bytes| Signature::from_compact(&bytes).ok())
+        .ok_or_else(|| format!("malformed signature {signature}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::SecretKey;
+
+    fn key(seed: u8) -> (SecretKey, PublicKey) {
+        let secret = SecretKey::from_slice(&[seed; 32]).unwrap();
+        (secret, PublicKey::from_secret_key(&Secp256k1::new(), &secret))
+    }
+
+    fn sign(secret: &SecretKey, digest: &Message) -> KeySignature {
+        let secp = Secp256k1::new();
+        KeySignature {
+            public_key: hex::encode(PublicKey::from_secret_key(&secp, secret).serialize()),
+            signature: hex::encode(secp.sign_ecdsa(digest, secret).serialize_compact()),
+        }
+    }
+
+    // 2 of 3 between a buyer, a seller and an arbiter settling disputes
+    pub fn escrow(buyer: &PublicKey, seller: &PublicKey, arbiter: &PublicKey) -> Condition {
+        let public_keys = [buyer, seller, arbiter].iter().map(|key| hex::encode(key.serialize())).collect();
+        Condition::Multisig { threshold: 2, public_keys }
+    }
+
+    // `receiver` can claim with the secret behind `sha256`. if it never does,
+    // `refund` gets the funds back from block `refund_height` on
+    pub fn atomic_swap(receiver: &str, sha256: &str, refund: &str, refund_height: u32) -> Condition {
+        Condition::Any { conditions: vec![
+            Condition::All { conditions: vec![
+                Condition::Hashlock { sha256: sha256.to_string() },
+                Condition::Key { address: receiver.to_string() },
+            ] },
+            Condition::All { conditions: vec![
+                Condition::AfterHeight { height: refund_height },
+                Condition::Key { address: refund.to_string() },
+            ] },
+        ] }
+    }
+
+    const AT_10: ScriptContext = ScriptContext { height: 10, timestamp: 1_000 };
+
+    #[test]
+    fn escrow_needs_two_of_three() {
+        let (buyer, seller, arbiter) = (key(1), key(2), key(3));
+        let lock = escrow(&buyer.1, &seller.1, &arbiter.1);
+        let digest = Message::from_digest([9; 32]);
+
+        let witness = |signers: &[&SecretKey]| Witness { signatures: signers.iter().map(|s| sign(s, &digest)).collect(), preimages: vec![] };
+        assert!(lock.evaluate(&digest, &witness(&[&buyer.0, &seller.0]), &AT_10).is_ok());
+        assert!(lock.evaluate(&digest, &witness(&[&arbiter.0, &seller.0]), &AT_10).is_ok());
+        assert!(lock.evaluate(&digest, &witness(&[&buyer.0]), &AT_10).is_err());
+        assert!(lock.evaluate(&digest, &witness(&[&buyer.0, &buyer.0]), &AT_10).is_err());
+        // signatures of another transaction do not count
+        let other = Message::from_digest([8; 32]);
+        assert!(lock.evaluate(&other, &witness(&[&buyer.0, &seller.0]), &AT_10).is_err());
+    }
+
+    #[test]
+    fn atomic_swap_claims_with_the_secret_or_refunds_later() {
+        let (alice, bob) = (key(1), key(2));
+        let secret = b"swap secret";
+        let hash = hex::encode(Sha256::digest(secret));
+        let swap = atomic_swap(&derive_address(&bob.1), &hash, &derive_address(&alice.1), 20);
+        let digest = Message::from_digest([9; 32]);
+
+        let claim = Witness { signatures: vec![sign(&bob.0, &digest)], preimages: vec![hex::encode(secret)] };
+        assert!(swap.evaluate(&digest, &claim, &AT_10).is_ok());
+        let no_secret = Witness { preimages: vec![hex::encode(b"guess")], ..claim.clone() };
+        assert!(swap.evaluate(&digest, &no_secret, &AT_10).is_err());
+
+        let refund = Witness { signatures: vec![sign(&alice.0, &digest)], preimages: vec![] };
+        assert!(swap.evaluate(&digest, &refund, &AT_10).is_err());
+        assert!(swap.evaluate(&digest, &refund, &ScriptContext { height: 20, ..AT_10 }).is_ok());
+    }
+
+    #[test]
+    fn script_addresses_commit_to_the_condition() {
+        let lock = Condition::AfterTime { timestamp: 5_000 };
+        let address = lock.address();
+        assert!(address.parse::<Address>().unwrap().is_script());
+        assert_ne!(address, Condition::AfterTime { timestamp: 5_001 }.address());
+
+        let digest = Message::from_digest([9; 32]);
+        assert!(lock.evaluate(&digest, &Witness::default(), &AT_10).is_err());
+        assert!(lock.evaluate(&digest, &Witness::default(), &ScriptContext { timestamp: 5_000, ..AT_10 }).is_ok());
+
+        let mut nested = lock;
+        for _ in 0..MAX_DEPTH {
+            nested = Condition::All { conditions: vec![nested] };
+        }
+        assert!(nested.evaluate(&digest, &Witness::default(), &ScriptContext { timestamp: 5_000, ..AT_10 }).is_err());
+    }
+}
@@ -0,0 +1,496 @@
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use secp256k1::ecdh::SharedSecret;
+use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::str::FromStr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+// first bytes sent by the initiator of an encrypted connection
+pub const SECURE_HANDSHAKE_TAG: &[u8; 7] = b"SECHSK ";
+
+// upper bound for a single frame, so a peer cannot make us allocate arbitrary memory
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+// upper bound for a frame before the peer is authenticated. handshake messages
+// are a few hundred bytes
+pub const MAX_HANDSHAKE_FRAME_LEN: usize = 4 * 1024;
+
+// persistent secp256k1 identity of the node. the node id is derived from the public key
+pub struct NodeKey {
+    secret: SecretKey,
+    pub public: PublicKey,
+}
+
+impl NodeKey {
+    // reads the raw 32 byte secret key from `path`, generating and saving a new one if missing
+    pub fn load_or_generate(path: &Path) -> std::io::Result<NodeKey> {
+        if !path.exists() {
+            let key = NodeKey::generate();
+            write_private(path, &key.secret.secret_bytes())?;
+            tracing::info!(path = %path.display(), "Generated new node key");
+            return Ok(key);
+        }
+        let bytes = std::fs::read(path)?;
+        let secret = SecretKey::from_slice(&bytes)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("invalid node key {}: {e}", path.display())))?;
+        Ok(NodeKey::from_secret(secret))
+    }
+
+    pub fn generate() -> NodeKey {
+        NodeKey::from_secret(SecretKey::new(&mut secp256k1::rand::thread_rng()))
+    }
+
+    fn from_secret(secret: SecretKey) -> NodeKey {
+        let public = PublicKey::from_secret_key(&Secp256k1::new(), &secret);
+        NodeKey { secret, public }
+    }
+
+    pub fn node_id(&self) -> Uuid {
+        node_id_from_key(&self.public)
+    }
+}
+
+// the key file is only readable by its owner
+fn write_private(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, bytes)
+}
+
+pub fn node_id_from_key(public: &PublicKey) -> Uuid {
+    let digest = sha256(&[&public.serialize()]);
+    let mut id = [0u8; 16];
+    id.copy_from_slice(&digest[..16]);
+    Uuid::from_bytes(id)
+}
+
+// transport settings shared by every connection of a node
+pub struct Transport {
+    pub key: NodeKey,
+    pub secure: bool,
+    pub trusted_peers: Option<HashSet<Uuid>>,
+    // id of the chain spec. peers on another network are refused
+    pub network_id: String,
+}
+
+impl Transport {
+    pub fn is_trusted(&self, peer_id: &Uuid) -> bool {
+        match &self.trusted_peers {
+            Some(trusted) => trusted.contains(peer_id),
+            None => true,
+        }
+    }
+}
+
+// reads node ids from a file, one per line. empty lines and lines starting with '#' are ignored
+pub fn load_trusted_peers(path: &Path) -> std::io::Result<HashSet<Uuid>> {
+    let content = std::fs::read_to_string(path)?;
+    let mut trusted = HashSet::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let id = Uuid::from_str(line)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("invalid node id {line}: {e}")))?;
+        trusted.insert(id);
+    }
+    Ok(trusted)
+}
+
+struct FrameCipher {
+    aead: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl FrameCipher {
+    fn new(key: [u8; 32]) -> Self {
+        FrameCipher { aead: ChaCha20Poly1305::new(Key::from_slice(&key)), counter: 0 }
+    }
+
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> std::io::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        self.aead.encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "failed to encrypt frame"))
+    }
+
+    fn open(&mut self, ciphertext: &[u8]) -> std::io::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        self.aead.decrypt(Nonce::from_slice(&nonce), ciphertext)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "failed to decrypt frame"))
+    }
+}
+
+// length prefixed frames, encrypted when the connection was opened with the secure handshake
+pub struct FrameWriter {
+    stream: OwnedWriteHalf,
+    cipher: Option<FrameCipher>,
+}
+
+impl FrameWriter {
+    pub fn plain(stream: OwnedWriteHalf) -> Self {
+        FrameWriter { stream, cipher: None }
+    }
+
+    pub async fn write_frame(&mut self, payload: &[u8]) -> std::io::Result<()> {
+        match self.cipher.as_mut() {
+            Some(cipher) => {
+                let sealed = cipher.seal(payload)?;
+                write_frame(&mut self.stream, &sealed).await
+            }
+            None => write_frame(&mut self.stream, payload).await,
+        }
+    }
+}
+
+pub struct FrameReader {
+    stream: OwnedReadHalf,
+    cipher: Option<FrameCipher>,
+}
+
+impl FrameReader {
+    pub fn plain(stream: OwnedReadHalf) -> Self {
+        FrameReader { stream, cipher: None }
+    }
+
+    pub async fn read_frame(&mut self) -> std::io::Result<Vec<u8>> {
+        let frame = read_frame(&mut self.stream).await?;
+        match self.cipher.as_mut() {
+            Some(cipher) => cipher.open(&frame),
+            None => Ok(frame),
+        }
+    }
+}
+
+pub async fn write_frame<W: AsyncWriteExt + Unpin>(stream: &mut W, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 8);
+    frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).await
+}
+
+pub async fn read_frame<R: AsyncReadExt + Unpin>(stream: &mut R) -> std::io::Result<Vec<u8>> {
+    read_frame_limited(stream, MAX_FRAME_LEN).await
+}
+
+pub async fn read_handshake_frame<R: AsyncReadExt + Unpin>(stream: &mut R) -> std::io::Result<Vec<u8>> {
+    read_frame_limited(stream, MAX_HANDSHAKE_FRAME_LEN).await
+}
+
+async fn read_frame_limited<R: AsyncReadExt + Unpin>(stream: &mut R, limit: usize) -> std::io::Result<Vec<u8>> {
+    let mut len = [0u8; 8];
+    stream.read_exact(&mut len).await?;
+    let len = u64::from_be_bytes(len) as usize;
+    if len > limit {
+        return Err(Error::new(ErrorKind::InvalidData, format!("frame of {len} bytes exceeds the limit")));
+    }
+    let mut buff = vec![0u8; len];
+    stream.read_exact(&mut buff).await?;
+    Ok(buff)
+}
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct Hello {
+    static_key: String,
+    ephemeral_key: String,
+    network_id: String,
+    server_addr: String,
+    signature: Option<String>,
+}
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct Finish {
+    signature: String,
+}
+
+// an authenticated connection after a completed secure handshake
+pub struct PeerSession {
+    pub peer_id: Uuid,
+    pub addr: std::net::SocketAddr,
+    pub peer_server_addr: String,
+    pub reader: FrameReader,
+    pub writer: FrameWriter,
+}
+
+// Noise-XX style handshake over secp256k1:
+//   initiator -> responder: tag, static key, ephemeral key
+//   responder -> initiator: static key, ephemeral key, signature over the transcript
+//   initiator -> responder: signature over the transcript
+// the network id and server address of each side are in the transcript too, so
+// they cannot be changed on the way
+// both sides prove possession of their static key, and the traffic keys are derived
+// from the ECDH of the ephemeral keys so past sessions stay private if a node key leaks
+pub async fn initiate(mut stream: TcpStream, transport: &Transport, server_addr: &str) -> std::io::Result<PeerSession> {
+    let secp = Secp256k1::new();
+    let (ephemeral, ephemeral_pub) = secp.generate_keypair(&mut secp256k1::rand::thread_rng());
+
+    stream.write_all(SECURE_HANDSHAKE_TAG).await?;
+    let hello = Hello {
+        static_key: hex::encode(transport.key.public.serialize()),
+        ephemeral_key: hex::encode(ephemeral_pub.serialize()),
+        network_id: transport.network_id.clone(),
+        server_addr: server_addr.to_string(),
+        signature: None,
+    };
+    write_frame(&mut stream, &to_json(&hello)?).await?;
+
+    let reply: Hello = from_json(&read_handshake_frame(&mut stream).await?)?;
+    check_network(transport, &reply)?;
+    let peer_static = parse_key(&reply.static_key)?;
+    let peer_ephemeral = parse_key(&reply.ephemeral_key)?;
+    let transcript = transcript(&hello, &reply);
+
+    let signature = reply.signature.as_deref().ok_or_else(|| invalid("handshake reply is not signed"))?;
+    verify(&secp, &peer_static, &transcript, b"responder", signature)?;
+
+    let finish = Finish { signature: sign(&secp, &transport.key.secret, &transcript, b"initiator") };
+    write_frame(&mut stream, &to_json(&finish)?).await?;
+
+    let shared = SharedSecret::new(&peer_ephemeral, &ephemeral).secret_bytes();
+    let send_key = sha256(&[&shared, &transcript, b"initiator->responder"]);
+    let recv_key = sha256(&[&shared, &transcript, b"responder->initiator"]);
+    session(stream, &peer_static, reply.server_addr, send_key, recv_key)
+}
+
+// expects the handshake tag to still be unread on the stream
+pub async fn respond(mut stream: TcpStream, transport: &Transport, server_addr: &str) -> std::io::Result<PeerSession> {
+    let secp = Secp256k1::new();
+    let (ephemeral, ephemeral_pub) = secp.generate_keypair(&mut secp256k1::rand::thread_rng());
+
+    let mut tag = [0u8; 7];
+    stream.read_exact(&mut tag).await?;
+    if &tag != SECURE_HANDSHAKE_TAG {
+        return Err(invalid("not a secure handshake"));
+    }
+
+    let hello: Hello = from_json(&read_handshake_frame(&mut stream).await?)?;
+    check_network(transport, &hello)?;
+    let peer_static = parse_key(&hello.static_key)?;
+    let peer_ephemeral = parse_key(&hello.ephemeral_key)?;
+
+    let mut reply = Hello {
+        static_key: hex::encode(transport.key.public.serialize()),
+        ephemeral_key: hex::encode(ephemeral_pub.serialize()),
+        network_id: transport.network_id.clone(),
+        server_addr: server_addr.to_string(),
+        signature: None,
+    };
+    let transcript = transcript(&hello, &reply);
+    reply.signature = Some(sign(&secp, &transport.key.secret, &transcript, b"responder"));
+    write_frame(&mut stream, &to_json(&reply)?).await?;
+
+    let finish: Finish = from_json(&read_handshake_frame(&mut stream).await?)?;
+    verify(&secp, &peer_static, &transcript, b"initiator", &finish.signature)?;
+
+    let shared = SharedSecret::new(&peer_ephemeral, &ephemeral).secret_bytes();
+    let send_key = sha256(&[&shared, &transcript, b"responder->initiator"]);
+    let recv_key = sha256(&[&shared, &transcript, b"initiator->responder"]);
+    session(stream, &peer_static, hello.server_addr, send_key, recv_key)
+}
+
+fn check_network(transport: &Transport, hello: &Hello) -> std::io::Result<()> {
+    if hello.network_id != transport.network_id {
+        return Err(invalid(&format!("peer is on network {}, we are on {}", hello.network_id, transport.network_id)));
+    }
+    Ok(())
+}
+
+fn session(stream: TcpStream, peer_static: &PublicKey, peer_server_addr: String, send_key: [u8; 32], recv_key: [u8; 32]) -> std::io::Result<PeerSession> {
+    let addr = stream.peer_addr()?;
+    let (read_half, write_half) = stream.into_split();
+    Ok(PeerSession {
+        peer_id: node_id_from_key(peer_static),
+        addr,
+        peer_server_addr,
+        reader: FrameReader { stream: read_half, cipher: Some(FrameCipher::new(recv_key)) },
+        writer: FrameWriter { stream: write_half, cipher: Some(FrameCipher::new(send_key)) },
+    })
+}
+
+// everything the hellos of the initiator and the responder say but the
+// signature. each field is hashed on its own, so no two hellos hash the same
+fn transcript(init: &Hello, resp: &Hello) -> [u8; 32] {
+    let fields: Vec<[u8; 32]> = [init, resp].into_iter()
+        .flat_map(|hello| [&hello.static_key, &hello.ephemeral_key, &hello.network_id, &hello.server_addr])
+        .map(|field| sha256(&[field.as_bytes()]))
+        .collect();
+    let mut parts: Vec<&[u8]> = vec![b"edblock-secure-handshake"];
+    parts.extend(fields.iter().map(|field| field.as_slice()));
+    sha256(&parts)
+}
+
+fn sign(secp: &Secp256k1<secp256k1::All>, secret: &SecretKey, transcript: &[u8; 32], role: &[u8]) -> String {
+    let digest = Message::from_digest(sha256(&[transcript, role]));
+    hex::encode(secp.sign_ecdsa(&digest, secret).serialize_compact())
+}
+
+fn verify(secp: &Secp256k1<secp256k1::All>, public: &PublicKey, transcript: &[u8; 32], role: &[u8], signature: &str) -> std::io::Result<()> {
+    let bytes = hex::decode(signature).map_err(|_| invalid("malformed handshake signature"))?;
+    let signature = Signature::from_compact(&bytes).map_err(|_| invalid("malformed handshake signature"))?;
+    let digest = Message::from_digest(sha256(&[transcript, role]));
+    secp.verify_ecdsa(&digest, &signature, public)
+        .map_err(|_| invalid("peer failed to prove possession of its key"))
+}
+
+fn parse_key(key: &str) -> std::io::Result<PublicKey> {
+    let bytes = hex::decode(key).map_err(|_| invalid("malformed public key"))?;
+    PublicKey::from_slice(&bytes).map_err(|_| invalid("malformed public key"))
+}
+
+fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> std::io::Result<Vec<u8>> {
+    serde_json::to_vec(value).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> std::io::Result<T> {
+    serde_json::from_slice(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn transport(network_id: &str) -> Transport {
+        Transport { key: NodeKey::generate(), secure: true, trusted_peers: None, network_id: network_id.to_string() }
+    }
+
+    // runs both sides of the handshake over a localhost connection
+    async fn handshake(initiator: &Transport, responder: &Transport) -> (std::io::Result<PeerSession>, std::io::Result<PeerSession>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        tokio::join!(
+            initiate(client.unwrap(), initiator, "10.0.0.5:5000"),
+            respond(server.unwrap().0, responder, "192.168.10.20:5000"),
+        )
+    }
+
+    async fn sessions() -> (PeerSession, PeerSession) {
+        let (a, b) = (transport("test"), transport("test"));
+        let (initiator, responder) = handshake(&a, &b).await;
+        (initiator.unwrap(), responder.unwrap())
+    }
+
+    #[tokio::test]
+    async fn authenticates_both_sides_and_encrypts_frames() {
+        let (a, b) = (transport("test"), transport("test"));
+        let (initiator, responder) = handshake(&a, &b).await;
+        let (mut initiator, mut responder) = (initiator.unwrap(), responder.unwrap());
+        assert_eq!(initiator.peer_id, b.key.node_id());
+        assert_eq!(responder.peer_id, a.key.node_id());
+        assert_eq!(initiator.peer_server_addr, "192.168.10.20:5000");
+        assert_eq!(responder.peer_server_addr, "10.0.0.5:5000");
+
+        initiator.writer.write_frame(b"ping").await.unwrap();
+        responder.writer.write_frame(b"pong").await.unwrap();
+        assert_eq!(responder.reader.read_frame().await.unwrap(), b"ping");
+        assert_eq!(initiator.reader.read_frame().await.unwrap(), b"pong");
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_frames() {
+        let (mut initiator, mut responder) = sessions().await;
+        let mut sealed = initiator.writer.cipher.as_mut().unwrap().seal(b"pay bob 1").unwrap();
+        sealed[0] ^= 1;
+        write_frame(&mut initiator.writer.stream, &sealed).await.unwrap();
+        assert!(responder.reader.read_frame().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_replayed_frames() {
+        let (mut initiator, mut responder) = sessions().await;
+        let sealed = initiator.writer.cipher.as_mut().unwrap().seal(b"pay bob 1").unwrap();
+        write_frame(&mut initiator.writer.stream, &sealed).await.unwrap();
+        write_frame(&mut initiator.writer.stream, &sealed).await.unwrap();
+        assert_eq!(responder.reader.read_frame().await.unwrap(), b"pay bob 1");
+        assert!(responder.reader.read_frame().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn caps_frames_before_authentication() {
+        let payload = vec![7u8; MAX_HANDSHAKE_FRAME_LEN + 1];
+        let mut framed = vec![];
+        write_frame(&mut framed, &payload).await.unwrap();
+        assert_eq!(read_frame(&mut framed.as_slice()).await.unwrap(), payload);
+        let e = read_handshake_frame(&mut framed.as_slice()).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn signs_the_network_and_server_address_of_both_sides() {
+        let hello = |network_id: &str, server_addr: &str| Hello {
+            static_key: "02aa".to_string(),
+            ephemeral_key: "03bb".to_string(),
+            network_id: network_id.to_string(),
+            server_addr: server_addr.to_string(),
+            signature: None,
+        };
+        let signed = transcript(&hello("test", "10.0.0.5:5000"), &hello("test", "192.168.10.20:5000"));
+        assert_ne!(transcript(&hello("test", "10.0.0.5:5000"), &hello("test", "203.0.113.9:5000")), signed);
+        assert_ne!(transcript(&hello("other", "10.0.0.5:5000"), &hello("test", "192.168.10.20:5000")), signed);
+        // moving bytes from one field to the next changes it as well
+        assert_ne!(transcript(&hello("tes", "t10.0.0.5:5000"), &hello("test", "192.168.10.20:5000")), signed);
+    }
+
+    #[tokio::test]
+    async fn refuses_peers_on_another_network() {
+        let (initiator, responder) = handshake(&transport("test"), &transport("other")).await;
+        assert!(initiator.is_err());
+        assert!(responder.is_err());
+    }
+
+    #[test]
+    fn trusts_only_the_listed_peers() {
+        let (listed, other) = (NodeKey::generate(), NodeKey::generate());
+        let path = std::env::temp_dir().join(format!("edblock-trusted-{}", Uuid::new_v4()));
+        std::fs::write(&path, format!("# peers\n\n{}\n", listed.node_id())).unwrap();
+        let trusted = load_trusted_peers(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let transport = Transport { trusted_peers: Some(trusted.unwrap()), ..transport("test") };
+        assert!(transport.is_trusted(&listed.node_id()));
+        assert!(!transport.is_trusted(&other.node_id()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_the_node_key_readable_by_the_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("edblock-{}.key", Uuid::new_v4()));
+        let key = NodeKey::load_or_generate(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let loaded = NodeKey::load_or_generate(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(loaded.node_id(), key.node_id());
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CAPACITY: usize = 10_000;
+pub const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Copy, serde_derive::Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub hit_rate: f64,
+}
+
+// bounded set of recently seen message hashes used for gossip deduplication.
+// entries expire `ttl` after they were first seen, and the oldest entry is
+// dropped once `capacity` is reached
+pub struct SeenCache {
+    capacity: usize,
+    ttl: Duration,
+    hashes: HashSet<String>,
+    order: VecDeque<(Instant, String)>,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl SeenCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        SeenCache {
+            capacity: capacity.max(1),
+            ttl,
+            hashes: HashSet::new(),
+            order: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    // returns true if the hash was not seen before (and records it)
+    pub fn insert(&mut self, hash: String) -> bool {
+        let now = Instant::now();
+        self.expire(now);
+
+        if self.hashes.contains(&hash) {
+            self.hits += 1;
+            return false;
+        }
+        self.misses += 1;
+        self.record(now, hash);
+        true
+    }
+
+    // records our own outgoing messages without touching the hit/miss counters
+    pub fn mark_seen(&mut self, hash: String) {
+        let now = Instant::now();
+        self.expire(now);
+        if !self.hashes.contains(&hash) {
+            self.record(now, hash);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lookups = self.hits + self.misses;
+        CacheStats {
+            entries: self.order.len(),
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            hit_rate: if lookups == 0 { 0.0 } else { self.hits as f64 / lookups as f64 },
+        }
+    }
+
+    fn record(&mut self, now: Instant, hash: String) {
+        while self.order.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+                self.evictions += 1;
+            }
+        }
+
+        self.hashes.insert(hash.clone());
+        self.order.push_back((now, hash));
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((seen_at, _)) = self.order.front() {
+            if now.duration_since(*seen_at) < self.ttl {
+                break;
+            }
+            if let Some((_, hash)) = self.order.pop_front() {
+                self.hashes.remove(&hash);
+            }
+        }
+    }
+}
+
+impl Default for SeenCache {
+    fn default() -> Self {
+        SeenCache::new(DEFAULT_CAPACITY, DEFAULT_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let mut cache = SeenCache::default();
+        assert!(cache.insert(String::from("a")));
+        assert!(!cache.insert(String::from("a")));
+        assert!(!cache.insert(String::from("a")));
+        assert!(cache.insert(String::from("b")));
+        // our own messages are not lookups
+        cache.mark_seen(String::from("c"));
+        assert!(!cache.insert(String::from("c")));
+
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (3, 3, 2));
+        assert_eq!(stats.hit_rate, 0.6);
+    }
+
+    #[test]
+    fn evicts_the_oldest_entry_when_full() {
+        let mut cache = SeenCache::new(2, DEFAULT_TTL);
+        for hash in ["a", "b", "c"] {
+            assert!(cache.insert(hash.to_string()));
+        }
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.stats().entries, 2);
+        assert!(!cache.insert(String::from("c")));
+        assert!(cache.insert(String::from("a")));
+    }
+
+    #[test]
+    fn forgets_hashes_after_the_ttl() {
+        let mut cache = SeenCache::new(DEFAULT_CAPACITY, Duration::from_millis(20));
+        assert!(cache.insert(String::from("a")));
+        assert!(!cache.insert(String::from("a")));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(cache.insert(String::from("a")));
+
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evictions), (1, 0));
+    }
+}
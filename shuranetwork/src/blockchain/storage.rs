@@ -0,0 +1,490 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, IteratorMode, Options, WriteBatch, DB};
+use tracing::{info, warn};
+
+use super::blockchain_core::{Block, Blockheader, Transaction, MINT_ADDRESS};
+use super::chain_error::ChainError;
+
+// where `Chain` keeps its blocks, indexes and balances. writes of a block are
+// all or nothing
+pub trait ChainStore: Send + Sync {
+    // number of blocks in the chain
+    fn height(&self) -> Result<u32, ChainError>;
+    fn get_hash(&self, height: u32) -> Result<Option<String>, ChainError>;
+    // none for blocks whose body was pruned
+    fn get_block(&self, hash: &str) -> Result<Option<Block>, ChainError>;
+    // headers are kept for every block, pruned or not
+    fn get_header(&self, hash: &str) -> Result<Option<Blockheader>, ChainError>;
+    fn balance(&self, address: &str) -> Result<f64, ChainError>;
+    // hash of the block holding a confirmed transaction
+    fn transaction_block(&self, transaction_id: &str) -> Result<Option<String>, ChainError>;
+    // ids of the confirmed transactions sending from or to `address`
+    fn address_transactions(&self, address: &str) -> Result<Vec<String>, ChainError>;
+    // append the block at `height`, which must be the current height. fails if
+    // one of its transactions is already indexed in another block
+    fn commit_block(&mut self, height: u32, hash: &str, block: &Block) -> Result<(), ChainError>;
+    // drop the blocks at `height` and above. fails below the pruned height
+    fn truncate(&mut self, height: u32) -> Result<(), ChainError>;
+    // like truncate, but the indexes and balances are recomputed from the
+    // blocks below `height` instead of undoing the dropped ones, which may be
+    // damaged. for repairs
+    fn reset(&mut self, height: u32) -> Result<(), ChainError>;
+    // lowest height that still has its block body, 0 when nothing was pruned
+    fn pruned_height(&self) -> Result<u32, ChainError>;
+    // delete the bodies of the blocks below `height`. headers, the height
+    // index and the balances stay. returns the number of bodies deleted
+    fn prune(&mut self, height: u32) -> Result<u32, ChainError>;
+    fn flush(&self) -> Result<(), ChainError>;
+}
+
+// the addresses a transaction sends from or to. minting has no sender
+pub fn transaction_addresses(transaction: &Transaction) -> Vec<&str> {
+    match transaction.sender() {
+        MINT_ADDRESS => vec![transaction.receiver()],
+        sender if sender == transaction.receiver() => vec![sender],
+        sender => vec![sender, transaction.receiver()],
+    }
+}
+
+// balance changes made by the transactions of a block
+pub fn balance_changes(block: &Block) -> Vec<(&str, f64)> {
+    let mut changes = vec![];
+    for transaction in block.transactions.values() {
+        let amount = transaction.amount() as f64;
+        if transaction.sender() != MINT_ADDRESS {
+            changes.push((transaction.sender(), -amount));
+        }
+        changes.push((transaction.receiver(), amount));
+    }
+    changes
+}
+
+// block hash -> block json, deleted when pruned
+pub const CF_BLOCKS: &str = "blocks";
+// block hash -> header json
+pub const CF_HEADERS: &str = "headers";
+// height (u32 big endian) -> block hash
+pub const CF_HEIGHTS: &str = "heights";
+// transaction id -> hash of the block holding it
+pub const CF_TXS: &str = "transactions";
+// address, 0, transaction id -> empty
+pub const CF_ADDRESSES: &str = "addresses";
+// address -> balance (f64 big endian)
+pub const CF_BALANCES: &str = "balances";
+// chain wide values, see the META_ keys
+pub const CF_META: &str = "metadata";
+
+const COLUMN_FAMILIES: [&str; 7] = [CF_BLOCKS, CF_HEADERS, CF_HEIGHTS, CF_TXS, CF_ADDRESSES, CF_BALANCES, CF_META];
+
+// number of blocks in the chain (u32 big endian)
+const META_HEIGHT: &str = "height";
+// hash of the last block
+const META_TIP: &str = "tip";
+// lowest height with a block body (u32 big endian), missing if never pruned
+const META_PRUNED: &str = "pruned_height";
+
+// the chain database. every block is committed with a single write batch so
+// the block, its indexes, the balances and the tip move together
+pub struct RocksStore {
+    db: DB,
+}
+
+impl RocksStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RocksStore, ChainError> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let descriptors = COLUMN_FAMILIES.iter()
+            .map(|name| ColumnFamilyDescriptor::new(*name, Options::default()));
+        let storage = RocksStore { db: DB::open_cf_descriptors(&opts, path, descriptors)? };
+
+        storage.migrate_legacy()?;
+        storage.check_consistency()?;
+        storage.index_headers()?;
+        storage.index_addresses()?;
+        Ok(storage)
+    }
+
+    // opens an existing database without writing to it: no migration, no
+    // repair of a half-written tip and no index backfill. for inspecting a
+    // chain as it is, also while a node has it open
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<RocksStore, ChainError> {
+        Ok(RocksStore { db: DB::open_cf_for_read_only(&Options::default(), path, COLUMN_FAMILIES, false)? })
+    }
+
+    fn cf(&self, name: &str) -> Result<&ColumnFamily, ChainError> {
+        self.db.cf_handle(name).ok_or_else(|| ChainError::Storage(format!("missing column family {name}")))
+    }
+
+    fn stage_block(
+        &self,
+        batch: &mut WriteBatch,
+        height: u32,
+        hash: &str,
+        block: &Block,
+        balances: &mut HashMap<String, f64>,
+    ) -> Result<(), ChainError> {
+        let txs = self.cf(CF_TXS)?;
+        let addresses = self.cf(CF_ADDRESSES)?;
+        batch.put_cf(&self.cf(CF_BLOCKS)?, hash.as_bytes(), serde_json::to_vec(block)?);
+        batch.put_cf(&self.cf(CF_HEADERS)?, hash.as_bytes(), serde_json::to_vec(&block.header)?);
+        batch.put_cf(&self.cf(CF_HEIGHTS)?, height.to_be_bytes(), hash.as_bytes());
+
+        for transaction in block.transactions.values() {
+            batch.put_cf(&txs, transaction.id().as_bytes(), hash.as_bytes());
+            for address in transaction_addresses(transaction) {
+                batch.put_cf(&addresses, address_key(address, transaction.id()), []);
+            }
+        }
+        for (address, delta) in balance_changes(block) {
+            self.adjust_balance(balances, address, delta)?;
+        }
+
+        let cf_balances = self.cf(CF_BALANCES)?;
+        for (address, balance) in balances.iter() {
+            batch.put_cf(&cf_balances, address.as_bytes(), balance.to_be_bytes());
+        }
+
+        let meta = self.cf(CF_META)?;
+        batch.put_cf(&meta, META_HEIGHT, (height + 1).to_be_bytes());
+        batch.put_cf(&meta, META_TIP, hash.as_bytes());
+        Ok(())
+    }
+
+    // balances touched by the batch being built are read from `balances`, not the db
+    fn adjust_balance(&self, balances: &mut HashMap<String, f64>, address: &str, delta: f64) -> Result<(), ChainError> {
+        if !balances.contains_key(address) {
+            balances.insert(address.to_string(), self.balance(address)?);
+        }
+        if let Some(balance) = balances.get_mut(address) {
+            *balance += delta;
+        }
+        Ok(())
+    }
+
+    // the recorded height must point at a stored block, and nothing may be
+    // indexed above it. a broken tip is rolled back to the last complete block
+    // and the derived indexes are rebuilt
+    fn check_consistency(&self) -> Result<(), ChainError> {
+        let recorded = self.height()?;
+        let mut height = recorded;
+        while height > 0 && !self.is_complete(height - 1)? {
+            height -= 1;
+        }
+
+        let heights = self.cf(CF_HEIGHTS)?;
+        let mut dangling = vec![];
+        for entry in self.db.iterator_cf(&heights, IteratorMode::From(&height.to_be_bytes(), rocksdb::Direction::Forward)) {
+            let (key, _) = entry?;
+            dangling.push(key);
+        }
+
+        if height == recorded && dangling.is_empty() {
+            return Ok(());
+        }
+
+        warn!(recorded, height, dangling = dangling.len(), "Repairing a half-written chain tip");
+        let mut batch = WriteBatch::default();
+        for key in dangling {
+            batch.delete_cf(&heights, key);
+        }
+        self.db.write(batch)?;
+        self.rebuild(height)?;
+        info!(height, "Chain tip repaired");
+        Ok(())
+    }
+
+    fn is_complete(&self, height: u32) -> Result<bool, ChainError> {
+        match self.get_hash(height)? {
+            Some(hash) => Ok(self.db.get_cf(&self.cf(CF_BLOCKS)?, hash.as_bytes())?.is_some()),
+            None => Ok(false),
+        }
+    }
+
+    // databases written before pruning have no header index
+    fn index_headers(&self) -> Result<(), ChainError> {
+        let height = self.height()?;
+        let headers = self.cf(CF_HEADERS)?;
+        if height == 0 || self.db.iterator_cf(&headers, IteratorMode::Start).next().is_some() {
+            return Ok(());
+        }
+        info!(height, "Indexing the block headers");
+        let mut batch = WriteBatch::default();
+        for h in 0..height {
+            let hash = self.get_hash(h)?.ok_or_else(|| ChainError::NotFound(format!("block at height {h}")))?;
+            let block = self.get_block(&hash)?.ok_or_else(|| ChainError::NotFound(format!("block {hash}")))?;
+            batch.put_cf(&headers, hash.as_bytes(), serde_json::to_vec(&block.header)?);
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    // databases written before the light client have no address index
+    fn index_addresses(&self) -> Result<(), ChainError> {
+        let height = self.height()?;
+        let addresses = self.cf(CF_ADDRESSES)?;
+        if height <= 1 || self.db.iterator_cf(&addresses, IteratorMode::Start).next().is_some() {
+            return Ok(());
+        }
+        if self.pruned_height()? > 0 {
+            warn!(height, "The pruned chain has no address index, address history is not served");
+            return Ok(());
+        }
+        info!(height, "Indexing the transaction addresses");
+        let mut batch = WriteBatch::default();
+        for h in 0..height {
+            let hash = self.get_hash(h)?.ok_or_else(|| ChainError::NotFound(format!("block at height {h}")))?;
+            let block = self.get_block(&hash)?.ok_or_else(|| ChainError::NotFound(format!("block {hash}")))?;
+            for transaction in block.transactions.values() {
+                for address in transaction_addresses(transaction) {
+                    batch.put_cf(&addresses, address_key(address, transaction.id()), []);
+                }
+            }
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    // recompute the transaction index, balances and tip from blocks 0..height.
+    // needs every body, so a pruned chain cannot be rebuilt
+    fn rebuild(&self, height: u32) -> Result<(), ChainError> {
+        if self.pruned_height()? > 0 {
+            return Err(ChainError::Storage("a pruned chain cannot be rebuilt, sync it again from a peer".to_string()));
+        }
+        let mut batch = WriteBatch::default();
+        for name in [CF_TXS, CF_ADDRESSES, CF_BALANCES, CF_META] {
+            let cf = self.cf(name)?;
+            for entry in self.db.iterator_cf(&cf, IteratorMode::Start) {
+                let (key, _) = entry?;
+                batch.delete_cf(&cf, key);
+            }
+        }
+        self.db.write(batch)?;
+
+        let mut batch = WriteBatch::default();
+        let mut balances = HashMap::new();
+        for h in 0..height {
+            let hash = self.get_hash(h)?.ok_or_else(|| ChainError::NotFound(format!("block at height {h}")))?;
+            let block = self.get_block(&hash)?.ok_or_else(|| ChainError::NotFound(format!("block {hash}")))?;
+            self.stage_block(&mut batch, h, &hash, &block, &mut balances)?;
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    // databases written before the column families kept blocks, the height
+    // index and the height in the default keyspace. move them over once
+    fn migrate_legacy(&self) -> Result<(), ChainError> {
+        let Some(height) = self.db.get(META_HEIGHT)? else {
+            return Ok(());
+        };
+        let height = decode_u32(height)?;
+        info!(height, "Migrating the chain to column families");
+
+        let mut batch = WriteBatch::default();
+        let mut balances = HashMap::new();
+        for h in 0..height {
+            let Some(hash) = self.db.get(h.to_be_bytes())? else {
+                break;
+            };
+            let Some(block) = self.db.get(&hash)? else {
+                break;
+            };
+            let hash = String::from_utf8_lossy(&hash).into_owned();
+            let block: Block = serde_json::from_slice(&block)?;
+            self.stage_block(&mut batch, h, &hash, &block, &mut balances)?;
+        }
+
+        for entry in self.db.iterator(IteratorMode::Start) {
+            let (key, _) = entry?;
+            batch.delete(key);
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+}
+
+impl ChainStore for RocksStore {
+    fn height(&self) -> Result<u32, ChainError> {
+        match self.db.get_cf(&self.cf(CF_META)?, META_HEIGHT)? {
+            Some(height) => decode_u32(height),
+            None => Ok(0),
+        }
+    }
+
+    fn get_hash(&self, height: u32) -> Result<Option<String>, ChainError> {
+        match self.db.get_cf(&self.cf(CF_HEIGHTS)?, height.to_be_bytes())? {
+            Some(hash) => String::from_utf8(hash)
+                .map(Some)
+                .map_err(|_| ChainError::Storage(format!("corrupt hash entry at height {height}"))),
+            None => Ok(None),
+        }
+    }
+
+    fn get_block(&self, hash: &str) -> Result<Option<Block>, ChainError> {
+        match self.db.get_cf(&self.cf(CF_BLOCKS)?, hash.as_bytes())? {
+            Some(block) => Ok(Some(serde_json::from_slice(&block)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_header(&self, hash: &str) -> Result<Option<Blockheader>, ChainError> {
+        match self.db.get_cf(&self.cf(CF_HEADERS)?, hash.as_bytes())? {
+            Some(header) => Ok(Some(serde_json::from_slice(&header)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn balance(&self, address: &str) -> Result<f64, ChainError> {
+        match self.db.get_cf(&self.cf(CF_BALANCES)?, address.as_bytes())? {
+            Some(balance) => decode_f64(balance),
+            None => Ok(0.0),
+        }
+    }
+
+    fn transaction_block(&self, transaction_id: &str) -> Result<Option<String>, ChainError> {
+        match self.db.get_cf(&self.cf(CF_TXS)?, transaction_id.as_bytes())? {
+            Some(hash) => String::from_utf8(hash)
+                .map(Some)
+                .map_err(|_| ChainError::Storage(format!("corrupt block hash of transaction {transaction_id}"))),
+            None => Ok(None),
+        }
+    }
+
+    fn address_transactions(&self, address: &str) -> Result<Vec<String>, ChainError> {
+        let prefix = address_key(address, "");
+        let mut ids = vec![];
+        for entry in self.db.iterator_cf(&self.cf(CF_ADDRESSES)?, IteratorMode::From(&prefix, rocksdb::Direction::Forward)) {
+            let (key, _) = entry?;
+            let Some(id) = key.strip_prefix(prefix.as_slice()) else {
+                break;
+            };
+            ids.push(String::from_utf8_lossy(id).into_owned());
+        }
+        Ok(ids)
+    }
+
+    fn commit_block(&mut self, height: u32, hash: &str, block: &Block) -> Result<(), ChainError> {
+        for id in block.transactions.keys() {
+            if let Some(indexed) = self.transaction_block(id)? {
+                return Err(ChainError::Storage(format!("transaction {id} is already in block {indexed}")));
+            }
+        }
+        let mut batch = WriteBatch::default();
+        self.stage_block(&mut batch, height, hash, block, &mut HashMap::new())?;
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    // the balance changes of the dropped blocks are undone, so only their
+    // bodies are needed
+    fn truncate(&mut self, height: u32) -> Result<(), ChainError> {
+        let current = self.height()?;
+        if height >= current {
+            return Ok(());
+        }
+        if height < self.pruned_height()? {
+            return Err(ChainError::Storage(format!("cannot drop blocks below the pruned height {}", self.pruned_height()?)));
+        }
+
+        let mut batch = WriteBatch::default();
+        let mut balances = HashMap::new();
+        for h in (height..current).rev() {
+            let hash = self.get_hash(h)?.ok_or_else(|| ChainError::NotFound(format!("block at height {h}")))?;
+            let block = self.get_block(&hash)?.ok_or_else(|| ChainError::NotFound(format!("block {hash}")))?;
+            for (address, delta) in balance_changes(&block) {
+                self.adjust_balance(&mut balances, address, -delta)?;
+            }
+            for transaction in block.transactions.values() {
+                batch.delete_cf(&self.cf(CF_TXS)?, transaction.id().as_bytes());
+                for address in transaction_addresses(transaction) {
+                    batch.delete_cf(&self.cf(CF_ADDRESSES)?, address_key(address, transaction.id()));
+                }
+            }
+            batch.delete_cf(&self.cf(CF_BLOCKS)?, hash.as_bytes());
+            batch.delete_cf(&self.cf(CF_HEADERS)?, hash.as_bytes());
+            batch.delete_cf(&self.cf(CF_HEIGHTS)?, h.to_be_bytes());
+        }
+
+        let cf_balances = self.cf(CF_BALANCES)?;
+        for (address, balance) in balances.iter() {
+            batch.put_cf(&cf_balances, address.as_bytes(), balance.to_be_bytes());
+        }
+        let meta = self.cf(CF_META)?;
+        batch.put_cf(&meta, META_HEIGHT, height.to_be_bytes());
+        match height.checked_sub(1) {
+            Some(tip) => {
+                let hash = self.get_hash(tip)?.ok_or_else(|| ChainError::NotFound(format!("block at height {tip}")))?;
+                batch.put_cf(&meta, META_TIP, hash.as_bytes());
+            }
+            None => batch.delete_cf(&meta, META_TIP),
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn reset(&mut self, height: u32) -> Result<(), ChainError> {
+        // a pruned chain cannot be rebuilt, the dropped blocks are undone
+        if self.pruned_height()? > 0 {
+            return self.truncate(height);
+        }
+        let heights = self.cf(CF_HEIGHTS)?;
+        let mut batch = WriteBatch::default();
+        for entry in self.db.iterator_cf(&heights, IteratorMode::From(&height.to_be_bytes(), rocksdb::Direction::Forward)) {
+            let (key, hash) = entry?;
+            batch.delete_cf(&self.cf(CF_BLOCKS)?, &hash);
+            batch.delete_cf(&self.cf(CF_HEADERS)?, &hash);
+            batch.delete_cf(&heights, key);
+        }
+        self.db.write(batch)?;
+        warn!(height, "Rebuilding the chain indexes");
+        self.rebuild(height)
+    }
+
+    fn pruned_height(&self) -> Result<u32, ChainError> {
+        match self.db.get_cf(&self.cf(CF_META)?, META_PRUNED)? {
+            Some(height) => decode_u32(height),
+            None => Ok(0),
+        }
+    }
+
+    fn prune(&mut self, height: u32) -> Result<u32, ChainError> {
+        let from = self.pruned_height()?;
+        let to = height.min(self.height()?);
+        if to <= from {
+            return Ok(0);
+        }
+        let blocks = self.cf(CF_BLOCKS)?;
+        let mut batch = WriteBatch::default();
+        for h in from..to {
+            if let Some(hash) = self.get_hash(h)? {
+                batch.delete_cf(&blocks, hash.as_bytes());
+            }
+        }
+        batch.put_cf(&self.cf(CF_META)?, META_PRUNED, to.to_be_bytes());
+        self.db.write(batch)?;
+        Ok(to - from)
+    }
+
+    fn flush(&self) -> Result<(), ChainError> {
+        Ok(self.db.flush()?)
+    }
+}
+
+// addresses never hold a 0 byte, so the key of one address is never a prefix
+// of another's
+fn address_key(address: &str, transaction_id: &str) -> Vec<u8> {
+    [address.as_bytes(), &[0], transaction_id.as_bytes()].concat()
+}
+
+fn decode_u32(bytes: Vec<u8>) -> Result<u32, ChainError> {
+    let digits: [u8; 4] = bytes.try_into().map_err(|_| ChainError::Storage("corrupt height entry".to_string()))?;
+    Ok(u32::from_be_bytes(digits))
+}
+
+fn decode_f64(bytes: Vec<u8>) -> Result<f64, ChainError> {
+    let digits: [u8; 8] = bytes.try_into().map_err(|_| ChainError::Storage("corrupt balance entry".to_string()))?;
+    Ok(f64::from_be_bytes(digits))
+}
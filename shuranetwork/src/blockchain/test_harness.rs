@@ -0,0 +1,446 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use super::SharedChain;
+use super::address::derive_address;
+use super::blockchain_app::run_gossip;
+use super::blockchain_core::{Chain, ChainConfig};
+use super::blockchain_rest;
+use super::chain_spec::ChainSpec;
+use super::memory_store::MemoryStore;
+use super::peer_network::{Node, NodeConfig};
+use super::peer_registry::PeerLimits;
+use super::storage::RocksStore;
+
+// how long the helpers wait for the network before failing the test
+const TIMEOUT: Duration = Duration::from_secs(15);
+const POLL: Duration = Duration::from_millis(50);
+
+// the key of a named test account, derived from the name so that every node
+// and test agrees on it
+pub fn test_key(name: &str) -> SecretKey {
+    SecretKey::from_slice(&Sha256::digest(name.as_bytes())).expect("a sha256 digest is a valid secret key")
+}
+
+pub fn test_address(name: &str) -> String {
+    derive_address(&PublicKey::from_secret_key(&Secp256k1::signing_only(), &test_key(name)))
+}
+
+// a node that never listens, with a throwaway key, for chains used without
+// the network
+pub async fn offline_node(spec: &ChainSpec) -> Node {
+    let config = NodeConfig { key_path: None, network_id: spec.network_id(), ..NodeConfig::default() };
+    Node::new(0, String::from("127.0.0.1:0"), config).await
+}
+
+// a chain kept in memory on an offline node, mining to the test account
+// `miner`. nothing is left on disk
+pub async fn memory_chain(miner: &str, spec: ChainSpec) -> Chain {
+    let node = offline_node(&spec).await;
+    let config = ChainConfig { spec, miner_addr: test_address(miner), prune: None };
+    Chain::open(node, Box::new(MemoryStore::default()), config).expect("Failed to open the chain")
+}
+
+// a full node running in the test process: peer listener, REST server and
+// gossip loop, with its key and database in a temporary directory
+pub struct TestNode {
+    pub chain: SharedChain,
+    pub node: Node,
+    pub rest_addr: SocketAddr,
+    data_dir: PathBuf,
+}
+
+// the spec of the nodes started by `TestNode::start`. the first miner starts
+// with a balance, so tests can send from it before a block was mined
+pub fn test_spec() -> ChainSpec {
+    ChainSpec { difficulty: 1, premine: [(test_address("miner-0"), 100.0)].into(), ..ChainSpec::default() }
+}
+
+impl TestNode {
+    pub async fn start(index: usize) -> TestNode {
+        Self::start_with_spec(index, test_spec()).await
+    }
+
+    pub async fn start_with_spec(index: usize, spec: ChainSpec) -> TestNode {
+        let data_dir = std::env::temp_dir().join(format!("edblock-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&data_dir).expect("Failed to create the node data directory");
+
+        // bind first so peers are told the port picked by the OS
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind the REST server");
+        let rest_addr = listener.local_addr().unwrap();
+
+        let config = NodeConfig {
+            key_path: Some(data_dir.join("node.key")),
+            limits: PeerLimits {
+                max_messages_per_sec: u32::MAX,
+                max_bytes_per_sec: u64::MAX,
+                ..PeerLimits::default()
+            },
+            network_id: spec.network_id(),
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(0, rest_addr.to_string(), config).await;
+        node.server_listen().await;
+
+        let store = RocksStore::open(data_dir.join("chain.db")).expect("Failed to open the chain database");
+        let config = ChainConfig { spec, miner_addr: test_address(&format!("miner-{index}")), prune: None };
+        let chain = Chain::open(node.clone(), Box::new(store), config).expect("Failed to open the chain");
+        let msg_incoming_rx = node.take_receiver().await.expect("Incoming messages already taken");
+        let chain = std::sync::Arc::new(Mutex::new(chain));
+
+        tokio::spawn(run_gossip(chain.clone(), msg_incoming_rx, node.msg_outgoing_tx.clone()));
+        tokio::spawn(blockchain_rest::serve(chain.clone(), listener));
+
+        TestNode { chain, node, rest_addr, data_dir }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.node.get_id()
+    }
+
+    // registered and ready to recieve our broadcasts
+    pub async fn is_connected(&self, peer: &TestNode) -> bool {
+        self.node.registry.lock().await.get(&peer.id()).is_some()
+            && self.node.write_streams.lock().await.contains_key(&peer.id())
+    }
+
+    // height and tip hash
+    pub async fn tip(&self) -> (u32, String) {
+        let mut chain = self.chain.lock().await;
+        let hash = chain.last_hash().await.unwrap_or_default();
+        (chain.height(), hash)
+    }
+
+    pub async fn balance(&self, address: &str) -> f64 {
+        let chain = self.chain.lock().await;
+        chain.db.as_ref().expect("Database is closed").balance(address).unwrap()
+    }
+}
+
+impl Drop for TestNode {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+// a set of nodes talking to each other over localhost
+pub struct Cluster {
+    pub nodes: Vec<TestNode>,
+}
+
+impl Cluster {
+    // starts `n` nodes and connects every pair of them
+    pub async fn start(n: usize) -> Cluster {
+        let mut nodes = Vec::with_capacity(n);
+        for i in 0..n {
+            nodes.push(TestNode::start(i).await);
+        }
+        let cluster = Cluster { nodes };
+        cluster.connect_all().await;
+        cluster
+    }
+
+    pub async fn connect(&self, a: usize, b: usize) {
+        let (from, to) = (&self.nodes[a], &self.nodes[b]);
+        from.node.add_peer(to.node.listen_addr().to_string()).await;
+        wait_until(&format!("node {a} to connect to node {b}"), || async {
+            from.is_connected(to).await && to.is_connected(from).await
+        }).await;
+    }
+
+    // connects every pair that is not connected yet
+    pub async fn connect_all(&self) {
+        for a in 0..self.nodes.len() {
+            for b in a + 1..self.nodes.len() {
+                if !self.nodes[a].is_connected(&self.nodes[b]).await {
+                    self.connect(a, b).await;
+                }
+            }
+        }
+    }
+
+    // cuts every link between `side` and the other nodes
+    pub async fn partition(&self, side: &[usize]) {
+        for &a in side {
+            for b in (0..self.nodes.len()).filter(|b| !side.contains(b)) {
+                let (this, other) = (&self.nodes[a], &self.nodes[b]);
+                this.node.disconnect_peer(other.id()).await;
+                other.node.disconnect_peer(this.id()).await;
+                wait_until(&format!("node {a} to disconnect from node {b}"), || async {
+                    !this.is_connected(other).await && !other.is_connected(this).await
+                }).await;
+            }
+        }
+    }
+
+    // restores the links cut by `partition`
+    pub async fn heal(&self) {
+        self.connect_all().await;
+    }
+
+    pub async fn mine(&self, i: usize) -> (u32, String) {
+        self.nodes[i].chain.lock().await.generate_new_block().await.expect("Failed to mine a block");
+        self.nodes[i].tip().await
+    }
+
+    // a transfer between the test accounts `sender` and `receiver`
+    pub async fn submit_transaction(&self, i: usize, sender: &str, receiver: &str, amount: f32) {
+        self.nodes[i].chain.lock().await.new_transaction(&test_key(sender), test_address(receiver), amount).await;
+    }
+
+    // waits until the mempool of every node listed holds `size` transactions
+    pub async fn wait_for_mempool(&self, nodes: &[usize], size: usize) {
+        for &i in nodes {
+            let chain = &self.nodes[i].chain;
+            wait_until(&format!("node {i} to hold {size} transactions"), || async {
+                chain.lock().await.mempool_size() == size
+            }).await;
+        }
+    }
+
+    // waits until every node listed has the same height and tip hash, and returns them
+    pub async fn assert_converged(&self, nodes: &[usize]) -> (u32, String) {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let mut tips = vec![];
+            for &i in nodes {
+                tips.push(self.nodes[i].tip().await);
+            }
+            if tips.windows(2).all(|pair| pair[0] == pair[1]) {
+                return tips.remove(0);
+            }
+            if Instant::now() > deadline {
+                panic!("nodes {nodes:?} did not converge: {tips:?}");
+            }
+            tokio::time::sleep(POLL).await;
+        }
+    }
+
+    pub async fn assert_all_converged(&self) -> (u32, String) {
+        let all: Vec<usize> = (0..self.nodes.len()).collect();
+        self.assert_converged(&all).await
+    }
+}
+
+async fn wait_until<F, Fut>(what: &str, mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    let deadline = Instant::now() + TIMEOUT;
+    while !condition().await {
+        if Instant::now() > deadline {
+            panic!("timed out waiting for {what}");
+        }
+        tokio::time::sleep(POLL).await;
+    }
+}
+
+mod tests {
+    use super::*;
+    use crate::blockchain::blockchain_core::Transaction;
+    use crate::blockchain::chain_error::ChainError;
+    use crate::blockchain::light_client::{self, LightClient, ProvenTransaction};
+    use crate::blockchain::peer_network::Message;
+    use crate::wallet::client::NodeClient;
+    use signing::KeyPair;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn gossips_blocks_and_transactions() {
+        let cluster = Cluster::start(3).await;
+        // every node starts from the genesis block of the spec
+        assert_eq!(cluster.assert_all_converged().await.0, 1);
+
+        cluster.submit_transaction(1, "miner-0", "alice", 30.0).await;
+        cluster.wait_for_mempool(&[0, 1, 2], 1).await;
+
+        let (height, hash) = cluster.mine(2).await;
+        assert_eq!(cluster.assert_all_converged().await, (height, hash));
+        assert_eq!(height, 2);
+        cluster.wait_for_mempool(&[0, 1, 2], 0).await;
+        for node in &cluster.nodes {
+            assert_eq!(node.balance(&test_address("alice")).await, 30.0);
+            assert_eq!(node.balance(&test_address("miner-2")).await, 100.0);
+
+            // peers sync from the advertised REST address
+            let len: serde_json::Value = reqwest::get(format!("http://{}/len", node.rest_addr)).await.unwrap().json().await.unwrap();
+            assert_eq!(len["len"], 2);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn relays_only_accepted_messages() {
+        // a line: 0 - 1 - 2
+        let mut nodes = vec![];
+        for i in 0..3 {
+            nodes.push(TestNode::start(i).await);
+        }
+        let cluster = Cluster { nodes };
+        cluster.connect(0, 1).await;
+        cluster.connect(1, 2).await;
+
+        // node 0 broadcasts a transfer from a key address without a signature
+        let forged = Transaction::new(KeyPair::generate().address().to_string(), test_address("mallory"), 1.0);
+        let sender = &cluster.nodes[0].node;
+        sender.msg_outgoing_tx.send(Message {
+            uuid: sender.get_id().to_string(),
+            block: None,
+            transaction: Some(forged),
+            message_hash: String::new(),
+        }).await.unwrap();
+
+        // a valid transfer sent after it reaches node 2, the forged one never
+        // left node 1
+        cluster.submit_transaction(0, "miner-0", "alice", 1.0).await;
+        cluster.wait_for_mempool(&[1, 2], 1).await;
+        assert_eq!(cluster.nodes[1].node.msg_hashes.lock().await.stats().misses, 2);
+        assert_eq!(cluster.nodes[2].node.msg_hashes.lock().await.stats().misses, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn converges_after_a_partition_heals() {
+        let cluster = Cluster::start(3).await;
+
+        cluster.partition(&[2]).await;
+
+        // both sides keep mining. the majority side builds the longer chain
+        cluster.submit_transaction(0, "miner-0", "alice", 10.0).await;
+        cluster.mine(0).await;
+        // mining before the block arrives would fork the majority too
+        cluster.assert_converged(&[0, 1]).await;
+        cluster.submit_transaction(1, "miner-0", "bob", 10.0).await;
+        cluster.mine(1).await;
+        let majority = cluster.assert_converged(&[0, 1]).await;
+
+        cluster.submit_transaction(2, "miner-0", "carol", 10.0).await;
+        let minority = cluster.mine(2).await;
+        assert_eq!(majority.0, 3);
+        assert_eq!(minority.0, 2);
+
+        cluster.heal().await;
+
+        // the next block on the longer chain makes the minority catch up
+        cluster.submit_transaction(1, "miner-0", "dave", 10.0).await;
+        cluster.wait_for_mempool(&[0, 1, 2], 1).await;
+        let (height, hash) = cluster.mine(1).await;
+        assert_eq!(cluster.assert_all_converged().await, (height, hash));
+        assert_eq!(height, 4);
+
+        // the orphaned transaction is back in the mempool of the minority node
+        let minority = &cluster.nodes[2];
+        assert_eq!(minority.balance(&test_address("carol")).await, 0.0);
+        assert_eq!(minority.chain.lock().await.mempool_size(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn refuses_peers_of_another_network() {
+        let node = TestNode::start(0).await;
+        let spec = ChainSpec { difficulty: 1, magic: 7, ..ChainSpec::default() };
+        let stranger = TestNode::start_with_spec(1, spec).await;
+
+        stranger.node.add_peer(node.node.listen_addr().to_string()).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!node.is_connected(&stranger).await);
+        assert!(!stranger.is_connected(&node).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn wallet_transfers_reach_every_node() {
+        let key = KeyPair::generate();
+        let address = key.address().to_string();
+        let spec = ChainSpec { difficulty: 1, premine: [(address.clone(), 50.0)].into(), ..ChainSpec::default() };
+        let mut nodes = vec![];
+        for i in 0..2 {
+            nodes.push(TestNode::start_with_spec(i, spec.clone()).await);
+        }
+        let cluster = Cluster { nodes };
+        cluster.connect_all().await;
+
+        let client = NodeClient::new(&cluster.nodes[0].rest_addr.to_string());
+        assert_eq!(client.balance(&address).await.unwrap(), 50.0);
+
+        let forged = Transaction::new(address.clone(), test_address("mallory"), 50.0);
+        assert!(matches!(client.submit(&forged).await, Err(ChainError::Validation(_))));
+
+        let transfer = Transaction::new_signed(&key.secret, test_address("bob"), 20.0);
+        assert_eq!(client.submit(&transfer).await.unwrap(), transfer.id());
+        cluster.wait_for_mempool(&[0, 1], 1).await;
+
+        cluster.mine(1).await;
+        cluster.assert_all_converged().await;
+        assert_eq!(client.balance(&address).await.unwrap(), 30.0);
+        assert_eq!(client.balance(&test_address("bob")).await.unwrap(), 20.0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn light_client_answers_with_proofs() {
+        let cluster = Cluster::start(2).await;
+        cluster.submit_transaction(0, "miner-0", "alice", 30.0).await;
+        cluster.wait_for_mempool(&[0, 1], 1).await;
+        cluster.mine(1).await;
+        cluster.assert_all_converged().await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let peers = cluster.nodes.iter().map(|node| node.rest_addr.to_string()).collect();
+        let light = std::sync::Arc::new(LightClient::new(test_spec(), peers));
+        tokio::spawn(light_client::run(light.clone(), listener, Duration::from_millis(100)));
+
+        // wallets query a light client like a full node
+        let client = NodeClient::new(&addr);
+        assert_eq!(client.balance(&test_address("alice")).await.unwrap(), 30.0);
+        assert_eq!(light.height().await, 2);
+
+        let id = cluster.nodes[0].chain.lock().await.address_history(&test_address("alice")).unwrap()[0].transaction.id().to_string();
+        let proven: ProvenTransaction = reqwest::get(format!("http://{addr}/transactions/{id}")).await.unwrap().json().await.unwrap();
+        assert_eq!((proven.height, proven.confirmations), (1, 1));
+
+        // transactions are relayed to the full nodes
+        client.submit(&Transaction::new_signed(&test_key("alice"), test_address("bob"), 10.0)).await.unwrap();
+        cluster.wait_for_mempool(&[0, 1], 1).await;
+        cluster.mine(0).await;
+        cluster.assert_all_converged().await;
+        assert_eq!(client.balance(&test_address("alice")).await.unwrap(), 20.0);
+        assert_eq!(client.balance(&test_address("bob")).await.unwrap(), 10.0);
+
+        // full nodes never download blocks from a light client
+        let len: serde_json::Value = reqwest::get(format!("http://{addr}/len")).await.unwrap().json().await.unwrap();
+        assert_eq!(len["len"], len["pruned_height"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn serves_the_explorer_and_its_api() {
+        let cluster = Cluster::start(1).await;
+        let base = format!("http://{}", cluster.nodes[0].rest_addr);
+        let get = |path: &str| reqwest::get(format!("{base}{path}"));
+
+        let page = get("/").await.unwrap();
+        assert!(page.headers()["content-type"].to_str().unwrap().starts_with("text/html"));
+        assert!(page.text().await.unwrap().contains("/explorer/explorer.js"));
+        assert!(get("/explorer/explorer.js").await.unwrap().status().is_success());
+
+        cluster.submit_transaction(0, "miner-0", "alice", 30.0).await;
+        let mempool: serde_json::Value = get("/mempool").await.unwrap().json().await.unwrap();
+        assert_eq!(mempool[0]["receiver"], test_address("alice"));
+        let (_, tip) = cluster.mine(0).await;
+
+        let recent: serde_json::Value = get("/blocks/recent?count=5").await.unwrap().json().await.unwrap();
+        assert_eq!(recent.as_array().unwrap().len(), 2);
+        assert_eq!((&recent[0]["height"], &recent[0]["hash"], &recent[0]["transactions"]), (&1.into(), &tip.clone().into(), &2.into()));
+
+        let block: serde_json::Value = get(&format!("/blocks/{tip}")).await.unwrap().json().await.unwrap();
+        assert_eq!(block["count"], 2);
+        assert_eq!(get("/blocks/missing").await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+
+        let address: serde_json::Value = get(&format!("/addresses/{}", test_address("alice"))).await.unwrap().json().await.unwrap();
+        assert_eq!(address["balance"], 30.0);
+        assert_eq!(address["transactions"][0]["block_hash"], tip.as_str());
+    }
+}
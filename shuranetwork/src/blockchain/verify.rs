@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::blockchain_core::{Block, Chain};
+use super::chain_error::ChainError;
+use super::chain_spec::ChainSpec;
+use super::merkle::merkle_root;
+use super::storage::{balance_changes, ChainStore, RocksStore};
+
+// the first problem found. every block below `height` checked out
+#[derive(Debug)]
+pub struct Fault {
+    pub height: u32,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub height: u32,
+    pub pruned_height: u32,
+    pub fault: Option<Fault>,
+}
+
+// walks the chain from the genesis block checking each header hash, pre_hash
+// link and proof of work, and for blocks with a body the merkle root, the
+// transaction count and index. the balances are recomputed unless the chain
+// is pruned. a fault at the tip height means only the balances are off
+pub fn verify_chain(db: &dyn ChainStore, spec: &ChainSpec) -> Result<Report, ChainError> {
+    let height = db.height()?;
+    let pruned_height = db.pruned_height()?;
+    let report = |fault| Ok(Report { height, pruned_height, fault });
+
+    let mut pre_hash = String::new();
+    let mut balances: HashMap<String, f64> = HashMap::new();
+    for h in 0..height {
+        match check_block(db, spec, h, &pre_hash, h >= pruned_height) {
+            Ok((hash, block)) => {
+                for (address, delta) in block.iter().flat_map(balance_changes) {
+                    *balances.entry(address.to_string()).or_default() += delta;
+                }
+                pre_hash = hash;
+            }
+            Err(reason) => return report(Some(Fault { height: h, reason })),
+        }
+    }
+    if db.get_hash(height)?.is_some() {
+        return report(Some(Fault { height, reason: "blocks are indexed above the recorded height".to_string() }));
+    }
+
+    if pruned_height == 0 {
+        for (address, expected) in balances {
+            let stored = db.balance(&address)?;
+            if (stored - expected).abs() > 1e-6 * expected.abs().max(1.0) {
+                let reason = format!("balance of {address} is {stored}, the blocks add up to {expected}");
+                return report(Some(Fault { height, reason }));
+            }
+        }
+    }
+    report(None)
+}
+
+// the hash of the block at `height`, and its body unless it was pruned
+fn check_block(db: &dyn ChainStore, spec: &ChainSpec, height: u32, pre_hash: &str, has_body: bool) -> Result<(String, Option<Block>), String> {
+    let hash = db.get_hash(height).map_err(|e| e.to_string())?
+        .ok_or("no block is indexed at this height")?;
+    let header = db.get_header(&hash).map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("header of {hash} is missing"))?;
+    let actual = Chain::hash(&header);
+    if actual != hash {
+        return Err(format!("header indexed as {hash} hashes to {actual}"));
+    }
+    if height == 0 {
+        if hash != Chain::hash(&Block::genesis(spec).header) {
+            return Err("genesis block does not match the chain spec".to_string());
+        }
+    } else {
+        Chain::validate_header(&header, &hash, pre_hash, spec.difficulty).map_err(|(_, e)| e.to_string())?;
+    }
+    if !has_body {
+        return Ok((hash, None));
+    }
+
+    let block = db.get_block(&hash).map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("body of {hash} is missing"))?;
+    if Chain::hash(&block.header) != hash {
+        return Err(format!("body of {hash} has another header"));
+    }
+    if block.count as usize != block.transactions.len() || (height > 0 && block.transactions.is_empty()) {
+        return Err(format!("block {hash} has a wrong transaction count"));
+    }
+    if merkle_root(block.sorted_transactions().iter().map(Chain::hash).collect()) != header.merkle() {
+        return Err(format!("block {hash} has a wrong merkle root"));
+    }
+    for id in block.transactions.keys() {
+        let indexed = db.transaction_block(id).map_err(|e| e.to_string())?;
+        if indexed.as_deref() != Some(hash.as_str()) {
+            return Err(format!("transaction {id} is indexed in block {indexed:?}"));
+        }
+    }
+    Ok((hash, Some(block)))
+}
+
+// checks the database at `path` and prints the result. with `repair` the
+// chain is cut back to the last valid block, otherwise it is only read.
+// returns false if the chain is still faulty
+pub fn run(path: &Path, spec: &ChainSpec, repair: bool) -> Result<bool, ChainError> {
+    let mut db = match repair {
+        true => RocksStore::open(path)?,
+        false => RocksStore::open_read_only(path)?,
+    };
+    let report = verify_chain(&db, spec)?;
+    println!("height: {}", report.height);
+    if report.pruned_height > 0 {
+        println!("bodies pruned below: {}", report.pruned_height);
+    }
+    let Some(fault) = report.fault else {
+        println!("ok: every block checked out");
+        return Ok(true);
+    };
+    println!("first bad height: {}", fault.height);
+    println!("reason: {}", fault.reason);
+    if !repair {
+        println!("run with --repair to truncate the chain to height {}", fault.height);
+        return Ok(false);
+    }
+
+    db.reset(fault.height)?;
+    let report = verify_chain(&db, spec)?;
+    match report.fault {
+        None => {
+            println!("repaired: the chain now ends at height {}", report.height);
+            Ok(true)
+        }
+        Some(fault) => {
+            println!("repair failed at height {}: {}", fault.height, fault.reason);
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::test_harness::{self, test_address, test_key};
+    use uuid::Uuid;
+
+    fn test_spec() -> ChainSpec {
+        ChainSpec { difficulty: 1, premine: [(test_address("alice"), 50.0)].into(), ..ChainSpec::default() }
+    }
+
+    // blocks of a valid chain with a transfer in each block after genesis
+    async fn valid_blocks(transfers: usize) -> Vec<(String, Block)> {
+        let mut chain = test_harness::memory_chain("miner", test_spec()).await;
+        for _ in 0..transfers {
+            chain.new_transaction(&test_key("alice"), test_address("bob"), 1.0).await;
+            chain.generate_new_block().await.unwrap();
+        }
+        let height = chain.height();
+        let blocks = chain.get_blocks(0, height).await.unwrap();
+        blocks.into_iter().map(|block| (Chain::hash(&block.header), block)).collect()
+    }
+
+    // removes the database directory when the test ends
+    struct TempDir(std::path::PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // the blocks are committed as they are, without validation
+    fn store(blocks: &[(String, Block)]) -> (RocksStore, TempDir) {
+        let path = std::env::temp_dir().join(format!("edblock-verify-{}", Uuid::new_v4()));
+        let mut db = RocksStore::open(&path).unwrap();
+        for (height, (hash, block)) in blocks.iter().enumerate() {
+            db.commit_block(height as u32, hash, block).unwrap();
+        }
+        (db, TempDir(path))
+    }
+
+    #[tokio::test]
+    async fn accepts_a_valid_chain() {
+        let (db, _dir) = store(&valid_blocks(3).await);
+        let report = verify_chain(&db, &test_spec()).unwrap();
+        assert_eq!(report.height, 4);
+        assert!(report.fault.is_none(), "{:?}", report.fault);
+
+        // another spec has another genesis block
+        let other = ChainSpec { magic: 7, ..test_spec() };
+        assert_eq!(verify_chain(&db, &other).unwrap().fault.unwrap().height, 0);
+    }
+
+    #[tokio::test]
+    async fn finds_the_first_bad_block_and_repairs_the_chain() {
+        let mut blocks = valid_blocks(3).await;
+        // a body that no longer matches its merkle root, stored under its old hash
+        let (_, block) = &mut blocks[2];
+        let id = block.transactions.keys().next().unwrap().clone();
+        block.transactions.remove(&id);
+        block.count -= 1;
+
+        let (mut db, _dir) = store(&blocks);
+        let fault = verify_chain(&db, &test_spec()).unwrap().fault.unwrap();
+        assert_eq!(fault.height, 2);
+        assert!(fault.reason.contains("merkle"), "{}", fault.reason);
+
+        db.reset(fault.height).unwrap();
+        let report = verify_chain(&db, &test_spec()).unwrap();
+        assert_eq!(report.height, 2);
+        assert!(report.fault.is_none(), "{:?}", report.fault);
+        assert_eq!(db.balance(&test_address("bob")).unwrap(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn reports_without_writing_unless_asked_to_repair() {
+        let mut blocks = valid_blocks(2).await;
+        blocks[2].1.count += 1;
+        let (db, dir) = store(&blocks);
+        drop(db);
+
+        assert!(!run(&dir.0, &test_spec(), false).unwrap());
+        let db = RocksStore::open_read_only(&dir.0).unwrap();
+        assert_eq!(db.height().unwrap(), 3);
+        drop(db);
+
+        assert!(run(&dir.0, &test_spec(), true).unwrap());
+        assert_eq!(RocksStore::open_read_only(&dir.0).unwrap().height().unwrap(), 2);
+
+        // a missing database is not created
+        let missing = std::env::temp_dir().join(format!("edblock-verify-{}", Uuid::new_v4()));
+        assert!(run(&missing, &test_spec(), false).is_err());
+        assert!(!missing.exists());
+    }
+
+    #[tokio::test]
+    async fn checks_the_links_between_blocks() {
+        let mut blocks = valid_blocks(3).await;
+        // block 3 of another chain does not extend block 2
+        blocks[3] = valid_blocks(3).await.remove(3);
+        let (db, _dir) = store(&blocks);
+        let fault = verify_chain(&db, &test_spec()).unwrap().fault.unwrap();
+        assert_eq!(fault.height, 3);
+    }
+}
@@ -0,0 +1,17 @@
+use tracing::Level;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+// installs the global subscriber. RUST_LOG overrides `level` when set.
+// logs go to stderr so they don't interleave with the menus on stdout
+pub fn init(level: Level, json: bool) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        format!("warn,{}={level},tower_http={level}", env!("CARGO_CRATE_NAME")).into()
+    });
+
+    let registry = tracing_subscriber::registry().with(filter);
+    if json {
+        registry.with(tracing_subscriber::fmt::layer().json().with_writer(std::io::stderr)).init();
+    } else {
+        registry.with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr)).init();
+    }
+}
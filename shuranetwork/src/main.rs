@@ -1,23 +1,278 @@
 mod template;
 mod blockchain;
+mod logging;
 mod utils;
+mod wallet;
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use clap::Parser;
+use blockchain::blockchain_app::AppConfig;
+use blockchain::blockchain_core::{Chain, ChainConfig, DB_PATH, MIN_KEPT_BLOCKS};
+use blockchain::chain_error::ChainError;
+use blockchain::chain_spec::ChainSpec;
+use blockchain::export::{Format, Records};
+use blockchain::light_client::{self, LightClient};
+use blockchain::peer_network::{Node, NodeConfig};
+use blockchain::peer_registry::PeerLimits;
+use blockchain::secure_transport::load_trusted_peers;
+use blockchain::storage::RocksStore;
+use wallet::client::NodeClient;
 
 #[derive(Parser, Debug)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(short, long, default_value_t = 8000, help = "port for running the node")]
     port: u32,
+    #[arg(long, default_value = "127.0.0.1", help = "address the node listens on, e.g. 0.0.0.0 or :: for all interfaces")]
+    bind: IpAddr,
+    #[arg(long, help = "address the REST server listens on [default: same as --bind]")]
+    rest_bind: Option<IpAddr>,
+    #[arg(long, help = "address advertised to peers for reaching the REST server")]
+    advertise: Option<IpAddr>,
+    #[arg(long, help = "encrypt and authenticate peer connections with the node key")]
+    secure: bool,
+    #[arg(long, default_value = "node.key", help = "file holding the persistent node key")]
+    node_key: PathBuf,
+    #[arg(long, requires = "secure", help = "file with the node ids allowed to connect, one per line")]
+    trusted_peers: Option<PathBuf>,
+    #[arg(long, default_value_t = PeerLimits::default().max_inbound, help = "maximum number of inbound peer connections")]
+    max_inbound: usize,
+    #[arg(long, default_value_t = PeerLimits::default().max_outbound, help = "maximum number of outbound peer connections")]
+    max_outbound: usize,
+    #[arg(long, default_value_t = PeerLimits::default().max_messages_per_sec, help = "messages per second accepted from a single peer")]
+    max_peer_msg_rate: u32,
+    #[arg(long, default_value_t = PeerLimits::default().max_bytes_per_sec, help = "bytes per second accepted from a single peer")]
+    max_peer_byte_rate: u64,
+    #[arg(long, default_value_t = PeerLimits::default().ban_secs, help = "seconds a misbehaving peer address stays banned")]
+    ban_secs: u64,
+    #[arg(long, default_value = "info", help = "log level: error, warn, info, debug or trace (RUST_LOG takes precedence)")]
+    log_level: tracing::Level,
+    #[arg(long, help = "write logs as JSON lines")]
+    log_json: bool,
+    #[arg(long, help = "JSON file with the genesis and chain parameters [default: built-in dev network]")]
+    chain_spec: Option<PathBuf>,
+    #[arg(long, value_parser = clap::value_parser!(u32).range(MIN_KEPT_BLOCKS as i64..), help = "keep the bodies of only the last N blocks, headers and balances are always kept")]
+    prune: Option<u32>,
+}
+
+// without a command the node is started
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    #[command(about = "manage keys and send signed transactions through a node")]
+    Wallet(wallet::WalletArgs),
+    #[command(about = "list, ban and unban the peers of a running node")]
+    Peers {
+        #[arg(long, help = "REST address of the node, e.g. 127.0.0.1:8001")]
+        node: String,
+        #[command(subcommand)]
+        command: PeersCommand,
+    },
+    #[command(about = "follow the chain through block headers only and answer queries with merkle proofs from full nodes")]
+    Light {
+        #[arg(long = "peer", required = true, help = "REST address of a full node, repeat for more")]
+        peers: Vec<String>,
+        #[arg(long, default_value_t = 8100, help = "port of the REST server")]
+        rest_port: u16,
+        #[arg(long, default_value_t = 10, help = "seconds between header syncs")]
+        sync_secs: u64,
+    },
+    #[command(about = "check the chain database block by block from the genesis block")]
+    Verify {
+        #[arg(long, default_value = DB_PATH, help = "chain database to check")]
+        db: PathBuf,
+        #[arg(long, help = "truncate the chain back to the last valid block")]
+        repair: bool,
+    },
+    #[command(about = "write blocks or transactions as json lines or csv")]
+    Export {
+        #[arg(long, default_value = DB_PATH, help = "chain database to read")]
+        db: PathBuf,
+        #[arg(long, default_value_t = 0, help = "first height exported")]
+        from: u32,
+        #[arg(long, help = "first height not exported [default: the chain height]")]
+        to: Option<u32>,
+        #[arg(long, value_enum, default_value_t = Format::Jsonl)]
+        format: Format,
+        #[arg(long, value_enum, default_value_t = Records::Blocks)]
+        records: Records,
+        #[arg(short, long, help = "file to write [default: stdout]")]
+        output: Option<PathBuf>,
+    },
+    #[command(about = "validate and append the blocks of a json lines file written by export")]
+    Import {
+        #[arg(help = "block file, - for stdin")]
+        path: PathBuf,
+        #[arg(long, default_value = DB_PATH, help = "chain database to append to")]
+        db: PathBuf,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum PeersCommand {
+    #[command(about = "print the connected peers and the banned addresses")]
+    List,
+    #[command(about = "ban an address for the configured ban time and disconnect its peers")]
+    Ban {
+        addr: IpAddr,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    #[command(about = "lift the ban of an address")]
+    Unban { addr: IpAddr },
 }
 
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+    logging::init(args.log_level, args.log_json);
+
+    if let Some(Command::Wallet(wallet)) = args.command {
+        if let Err(e) = wallet::run(wallet).await {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Peers { node, command }) = args.command {
+        if let Err(e) = run_peers(&node, command).await {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let spec = match &args.chain_spec {
+        Some(path) => ChainSpec::load(path).expect("Failed to load the chain spec"),
+        None => ChainSpec::default(),
+    };
+    tracing::info!(network = %spec.name, network_id = %spec.network_id(), "Chain spec loaded");
+
+    if let Some(Command::Verify { db, repair }) = &args.command {
+        match blockchain::verify::run(db, &spec, *repair) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if let Some(Command::Export { db, from, to, format, records, output }) = &args.command {
+        if let Err(e) = run_export(db, *from, *to, *format, *records, output.as_deref()) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Import { path, db }) = &args.command {
+        if let Err(e) = run_import(path, db, spec).await {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Light { peers, rest_port, sync_secs }) = args.command {
+        let addr = SocketAddr::new(args.rest_bind.unwrap_or(args.bind), rest_port);
+        let listener = tokio::net::TcpListener::bind(addr).await.expect("Failed to bind the REST server");
+        let client = std::sync::Arc::new(LightClient::new(spec, peers));
+        light_client::run(client, listener, Duration::from_secs(sync_secs)).await;
+        return;
+    }
+
+    let trusted_peers = args.trusted_peers.as_ref().map(|path| {
+        load_trusted_peers(path).expect("Failed to read the trusted peers file")
+    });
+    let config = AppConfig {
+        node: NodeConfig {
+            bind_addr: args.bind,
+            secure: args.secure,
+            key_path: Some(args.node_key),
+            trusted_peers,
+            limits: PeerLimits {
+                max_inbound: args.max_inbound,
+                max_outbound: args.max_outbound,
+                max_messages_per_sec: args.max_peer_msg_rate,
+                max_bytes_per_sec: args.max_peer_byte_rate,
+                ban_secs: args.ban_secs,
+            },
+            network_id: spec.network_id(),
+        },
+        spec,
+        prune: args.prune,
+        rest_bind_addr: args.rest_bind.unwrap_or(args.bind),
+        advertise_addr: args.advertise,
+    };
+
     if let Err(e) = std::fs::create_dir("static") {
-        eprintln!("Couldn't able to create the static dir: {e}")
+        tracing::debug!("Couldn't able to create the static dir: {e}")
     }
 
     if let Err(e) = std::fs::create_dir("backup") {
-        eprintln!("Couldn't able to create the backup dir: {e}")
+        tracing::debug!("Couldn't able to create the backup dir: {e}")
+    }
+    blockchain::blockchain_app::blockchain_app(config).await;
+}
+
+async fn run_peers(node: &str, command: PeersCommand) -> Result<(), ChainError> {
+    let client = NodeClient::new(node);
+    match command {
+        PeersCommand::List => {
+            let snapshot = client.peers().await?;
+            for peer in snapshot.peers {
+                println!("{} {} {:?} {} messages {} bytes", peer.id, peer.addr, peer.direction, peer.messages_in, peer.bytes_in);
+            }
+            for ban in snapshot.bans {
+                println!("banned {} for {}s: {}", ban.addr, ban.expires_in_secs, ban.reason);
+            }
+        }
+        PeersCommand::Ban { addr, reason } => {
+            client.ban(addr, reason).await?;
+            println!("banned {addr}");
+        }
+        PeersCommand::Unban { addr } => {
+            if !client.unban(addr).await? {
+                return Err(ChainError::NotFound(format!("ban of {addr}")));
+            }
+            println!("unbanned {addr}");
+        }
     }
-    blockchain::blockchain_app::blockchain_app().await;
-}
\ No newline at end of file
+    Ok(())
+}
+
+// the database is only read, so a running node's chain can be exported
+fn run_export(db: &Path, from: u32, to: Option<u32>, format: Format, records: Records, output: Option<&Path>) -> Result<(), ChainError> {
+    let db = RocksStore::open_read_only(db)?;
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout().lock())),
+    };
+    let written = blockchain::export::export(&db, from, to.unwrap_or(u32::MAX), records, format, &mut out)?;
+    tracing::info!(written, "Export finished");
+    Ok(())
+}
+
+// the blocks go through a chain without peers, so they are validated like
+// blocks from the network but never broadcast. the node never connects, so
+// it gets a throwaway key instead of reading or creating the node key file
+async fn run_import(path: &Path, db: &Path, spec: ChainSpec) -> Result<(), ChainError> {
+    let node_config = NodeConfig { key_path: None, network_id: spec.network_id(), ..NodeConfig::default() };
+    let node = Node::new(0, String::new(), node_config).await;
+    let config = ChainConfig { spec, ..ChainConfig::default() };
+    let mut chain = Chain::open(node, Box::new(RocksStore::open(db)?), config)?;
+    let report = match path.to_str() {
+        Some("-") => blockchain::export::import(&mut chain, std::io::stdin().lock()).await?,
+        _ => blockchain::export::import(&mut chain, BufReader::new(File::open(path)?)).await?,
+    };
+    println!("imported {} blocks, {} already in the chain, height {}", report.added, report.skipped, chain.height());
+    Ok(())
+}
@@ -0,0 +1,223 @@
+use std::path::{Path, PathBuf};
+
+use signing::keystore::{self, DEFAULT_LOG_N, KEY_EXTENSION};
+use signing::KeyPair;
+
+use crate::blockchain::address::{check_address, is_script_address};
+use crate::blockchain::blockchain_core::Transaction;
+use crate::blockchain::script::Condition;
+use crate::utils::get_value;
+
+use client::NodeClient;
+
+pub mod client;
+
+// where the keys are kept unless --dir says otherwise
+pub(crate) const DEFAULT_DIR: &str = "wallet";
+
+// read instead of prompting, for scripts
+const PASSPHRASE_ENV: &str = "EDBLOCK_WALLET_PASSPHRASE";
+
+#[derive(clap::Args, Debug)]
+pub struct WalletArgs {
+    #[arg(long, default_value = DEFAULT_DIR, help = "directory holding the encrypted keys")]
+    dir: PathBuf,
+    #[arg(long, help = "REST address of the node to query and submit to, e.g. 127.0.0.1:8001")]
+    node: Option<String>,
+    #[command(subcommand)]
+    command: WalletCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum WalletCommand {
+    #[command(about = "generate a new key and print its address")]
+    New,
+    #[command(about = "encrypt and add an unencrypted keys.bin written by the signing crate")]
+    Import { path: PathBuf },
+    #[command(about = "list the addresses, with their balances when --node is given")]
+    List,
+    #[command(about = "sign a transfer and submit it to the node")]
+    Send {
+        #[arg(long)]
+        from: String,
+        #[arg(long)]
+        to: String,
+        #[arg(long)]
+        amount: f32,
+    },
+    #[command(about = "print the script address of a spending condition written as json")]
+    Script { condition: PathBuf },
+    #[command(about = "write an unsigned transfer out of a script address to a file")]
+    Spend {
+        condition: PathBuf,
+        #[arg(long)]
+        to: String,
+        #[arg(long)]
+        amount: f32,
+        #[arg(long)]
+        out: PathBuf,
+    },
+    #[command(about = "add a signature and hash preimages to the witness of a script transfer")]
+    Witness {
+        transaction: PathBuf,
+        #[arg(long, help = "address of the key to sign with")]
+        sign: Option<String>,
+        #[arg(long, help = "hex encoded preimage of a hashlock")]
+        preimage: Vec<String>,
+    },
+    #[command(about = "submit a transaction written by spend to the node")]
+    Submit { transaction: PathBuf },
+}
+
+pub async fn run(args: WalletArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = args.dir.as_path();
+    let client = args.node.as_deref().map(NodeClient::new);
+
+    match args.command {
+        WalletCommand::New => {
+            let key = KeyPair::generate();
+            keystore::save(dir, &key, &new_passphrase()?, DEFAULT_LOG_N)?;
+            println!("{}", key.address());
+        }
+        WalletCommand::Import { path } => {
+            let key = KeyPair::from_keys_bin(&std::fs::read(&path)?)?;
+            if key_path(dir, &key.address().to_string()).exists() {
+                return Err(format!("{} is already in the wallet", key.address()).into());
+            }
+            keystore::save(dir, &key, &new_passphrase()?, DEFAULT_LOG_N)?;
+            println!("{}", key.address());
+        }
+        WalletCommand::List => {
+            for address in addresses(dir)? {
+                match &client {
+                    Some(client) => match client.balance(&address).await {
+                        Ok(balance) => println!("{address} {balance}"),
+                        Err(e) => println!("{address} ? ({e})"),
+                    },
+                    None => println!("{address}"),
+                }
+            }
+        }
+        WalletCommand::Send { from, to, amount } => {
+            let client = client.ok_or("--node is required to send")?;
+            check_amount(amount)?;
+            check_address(&to)?;
+            let key = load_key(dir, &from, &passphrase("Passphrase: "))?;
+
+            // the node refuses overspending too, this just gives a clearer error
+            let balance = client.balance(&from).await?;
+            if balance < amount as f64 {
+                return Err(format!("{from} holds {balance}, cannot send {amount}").into());
+            }
+
+            let transaction = Transaction::new_signed(&key.secret, to, amount);
+            println!("{}", client.submit(&transaction).await?);
+        }
+        WalletCommand::Script { condition } => {
+            println!("{}", read_condition(&condition)?.address());
+        }
+        WalletCommand::Spend { condition, to, amount, out } => {
+            check_amount(amount)?;
+            check_address(&to)?;
+            let transaction = Transaction::new_script_spend(read_condition(&condition)?, to, amount);
+            std::fs::write(&out, serde_json::to_vec_pretty(&transaction)?)?;
+            println!("{}", transaction.id());
+        }
+        WalletCommand::Witness { transaction: path, sign, preimage } => {
+            let mut transaction: Transaction = serde_json::from_slice(&std::fs::read(&path)?)?;
+            if !is_script_address(transaction.sender()) {
+                return Err(format!("{} does not spend from a script address", path.display()).into());
+            }
+            for preimage in preimage {
+                transaction.reveal_preimage(&hex::decode(preimage)?);
+            }
+            if let Some(address) = sign {
+                let key = load_key(dir, &address, &passphrase("Passphrase: "))?;
+                transaction.sign_witness(&key.secret);
+            }
+            std::fs::write(&path, serde_json::to_vec_pretty(&transaction)?)?;
+        }
+        WalletCommand::Submit { transaction } => {
+            let client = client.ok_or("--node is required to submit")?;
+            let transaction: Transaction = serde_json::from_slice(&std::fs::read(transaction)?)?;
+            println!("{}", client.submit(&transaction).await?);
+        }
+    }
+    Ok(())
+}
+
+// the wallet directory holds one `<address>.key` file per key, in the
+// format of the signing crate
+fn key_path(dir: &Path, address: &str) -> PathBuf {
+    dir.join(address).with_extension(KEY_EXTENSION)
+}
+
+fn addresses(dir: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut addresses = vec![];
+    if !dir.exists() {
+        return Ok(addresses);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == KEY_EXTENSION) {
+            addresses.push(keystore::address_of(&std::fs::read(&path)?)?);
+        }
+    }
+    addresses.sort();
+    Ok(addresses)
+}
+
+pub(crate) fn load_key(dir: &Path, address: &str, passphrase: &str) -> Result<KeyPair, Box<dyn std::error::Error>> {
+    let path = key_path(dir, address);
+    if !path.exists() {
+        return Err(format!("no key for address {address}").into());
+    }
+    Ok(keystore::load(&path, passphrase)?)
+}
+
+fn check_amount(amount: f32) -> Result<(), &'static str> {
+    if !amount.is_finite() || amount <= 0.0 {
+        return Err("the amount must be positive");
+    }
+    Ok(())
+}
+
+fn read_condition(path: &std::path::Path) -> Result<Condition, Box<dyn std::error::Error>> {
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+pub(crate) fn passphrase(prompt: &str) -> String {
+    std::env::var(PASSPHRASE_ENV).unwrap_or_else(|_| get_value(prompt))
+}
+
+fn new_passphrase() -> Result<String, String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let passphrase = get_value("New passphrase: ");
+    if passphrase != get_value("Repeat the passphrase: ") {
+        return Err("the passphrases do not match".to_string());
+    }
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_keys_by_address() {
+        let dir = std::env::temp_dir().join(format!("edblock-wallet-{}", uuid::Uuid::new_v4()));
+        assert!(addresses(&dir).unwrap().is_empty());
+
+        // cheap key derivation so the test stays fast
+        let key = KeyPair::generate();
+        keystore::save(&dir, &key, "correct horse", 4).unwrap();
+        let address = key.address().to_string();
+        assert_eq!(addresses(&dir).unwrap(), vec![address.clone()]);
+        assert_eq!(load_key(&dir, &address, "correct horse").unwrap(), key);
+        assert!(load_key(&dir, &address, "battery staple").is_err());
+        assert!(load_key(&dir, &KeyPair::generate().address().to_string(), "correct horse").is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
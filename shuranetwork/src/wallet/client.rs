@@ -0,0 +1,72 @@
+use reqwest::StatusCode;
+
+use crate::blockchain::blockchain_core::Transaction;
+use std::net::IpAddr;
+
+use crate::blockchain::blockchain_rest::{Balance, BanPeer, Submitted};
+use crate::blockchain::chain_error::ChainError;
+use crate::blockchain::peer_registry::PeersSnapshot;
+
+// talks to the REST API of a node
+pub struct NodeClient {
+    base: String,
+    http: reqwest::Client,
+}
+
+impl NodeClient {
+    // `addr` is the REST address of the node, with or without the scheme
+    pub fn new(addr: &str) -> NodeClient {
+        let base = if addr.starts_with("http://") || addr.starts_with("https://") {
+            addr.trim_end_matches('/').to_string()
+        } else {
+            format!("http://{addr}")
+        };
+        NodeClient { base, http: reqwest::Client::new() }
+    }
+
+    pub async fn balance(&self, address: &str) -> Result<f64, ChainError> {
+        let balance: Balance = self.http.get(format!("{}/balance/{address}", self.base))
+            .send().await?
+            .error_for_status()?
+            .json().await?;
+        Ok(balance.balance)
+    }
+
+    // returns the id of the accepted transaction
+    pub async fn submit(&self, transaction: &Transaction) -> Result<String, ChainError> {
+        let response = self.http.post(format!("{}/transactions", self.base))
+            .json(transaction)
+            .send().await?;
+        if response.status() == StatusCode::BAD_REQUEST {
+            return Err(ChainError::Validation(response.text().await?));
+        }
+        let submitted: Submitted = response.error_for_status()?.json().await?;
+        Ok(submitted.transaction_id)
+    }
+
+    pub async fn peers(&self) -> Result<PeersSnapshot, ChainError> {
+        Ok(self.http.get(format!("{}/peers", self.base))
+            .send().await?
+            .error_for_status()?
+            .json().await?)
+    }
+
+    pub async fn ban(&self, addr: IpAddr, reason: Option<String>) -> Result<(), ChainError> {
+        self.http.post(format!("{}/peers/bans", self.base))
+            .json(&BanPeer { addr, reason })
+            .send().await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    // false if the address was not banned
+    pub async fn unban(&self, addr: IpAddr) -> Result<bool, ChainError> {
+        let response = self.http.delete(format!("{}/peers/bans/{addr}", self.base))
+            .send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+}
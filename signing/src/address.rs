@@ -0,0 +1,114 @@
+// human readable addresses: bech32m of a version byte and a 20 byte hash,
+// e.g. `edb1qq...`. the checksum catches any typo of up to four characters
+use std::fmt;
+use std::str::FromStr;
+
+use bech32::primitives::decode::CheckedHrpstring;
+use bech32::{Bech32m, Hrp};
+use secp256k1::PublicKey;
+
+use crate::{derive_address, SigningError};
+
+pub const HRP: &str = "edb";
+// the hash of a public key
+pub const KEY_VERSION: u8 = 0;
+// the hash of a spending condition, which has to be revealed to spend
+pub const SCRIPT_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address {
+    version: u8,
+    hash: [u8; 20],
+}
+
+impl Address {
+    pub fn from_public_key(public_key: &PublicKey) -> Address {
+        Address::from_hash(derive_address(public_key))
+    }
+
+    pub fn from_hash(hash: [u8; 20]) -> Address {
+        Address { version: KEY_VERSION, hash }
+    }
+
+    pub fn from_script_hash(hash: [u8; 20]) -> Address {
+        Address { version: SCRIPT_VERSION, hash }
+    }
+
+    pub fn hash(&self) -> [u8; 20] {
+        self.hash
+    }
+
+    pub fn is_script(&self) -> bool {
+        self.version == SCRIPT_VERSION
+    }
+
+    // whether `text` is meant as an address, valid or not. anything else is
+    // not an address at all, rather than a mistyped one
+    pub fn looks_like_address(text: &str) -> bool {
+        text.get(..HRP.len() + 1).is_some_and(|prefix| prefix.eq_ignore_ascii_case(&format!("{HRP}1")))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut data = vec![self.version];
+        data.extend_from_slice(&self.hash);
+        let hrp = Hrp::parse(HRP).expect("valid hrp");
+        write!(f, "{}", bech32::encode::<Bech32m>(hrp, &data).expect("address fits in bech32"))
+    }
+}
+
+impl FromStr for Address {
+    type Err = SigningError;
+
+    fn from_str(text: &str) -> Result<Address, SigningError> {
+        let invalid = |reason: &str| SigningError::Address(format!("{text}: {reason}"));
+        let checked = CheckedHrpstring::new::<Bech32m>(text).map_err(|e| invalid(&e.to_string()))?;
+        if checked.hrp().to_lowercase() != HRP {
+            return Err(invalid("not an edblock address"));
+        }
+        let data: Vec<u8> = checked.byte_iter().collect();
+        let Some((&version, hash)) = data.split_first() else {
+            return Err(invalid("empty"));
+        };
+        if version != KEY_VERSION && version != SCRIPT_VERSION {
+            return Err(invalid(&format!("unknown version {version}")));
+        }
+        let hash = hash.try_into().map_err(|_| invalid("wrong length"))?;
+        Ok(Address { version, hash })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_catches_typos() {
+        let address = Address::from_hash([0x2a; 20]);
+        let text = address.to_string();
+        assert!(text.starts_with("edb1"));
+        assert!(Address::looks_like_address(&text));
+        assert_eq!(text.parse::<Address>().unwrap(), address);
+        assert_eq!(text.to_uppercase().parse::<Address>().unwrap(), address);
+
+        // any single substituted character fails the checksum
+        for i in 4..text.len() {
+            let mut typo = text.clone().into_bytes();
+            typo[i] = if typo[i] == b'q' { b'p' } else { b'q' };
+            assert!(String::from_utf8(typo).unwrap().parse::<Address>().is_err(), "typo at {i}");
+        }
+        assert!(text[..text.len() - 1].parse::<Address>().is_err());
+        assert!(text.replacen("edb", "btc", 1).parse::<Address>().is_err());
+        assert!(!Address::looks_like_address("alice"));
+    }
+
+    #[test]
+    fn script_addresses_differ_from_key_addresses() {
+        let key = Address::from_hash([0x2a; 20]);
+        let script = Address::from_script_hash([0x2a; 20]);
+        assert_ne!(key.to_string(), script.to_string());
+        assert!(!key.is_script());
+        assert!(script.to_string().parse::<Address>().unwrap().is_script());
+    }
+}
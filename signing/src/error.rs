@@ -0,0 +1,58 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum SigningError {
+    Io(std::io::Error),
+    // not a key file, or written by a newer version
+    Format(String),
+    // wrong passphrase or a tampered keystore
+    Decrypt,
+    Mnemonic(bip39::Error),
+    // a derivation path that does not parse
+    Path(String),
+    Key(secp256k1::Error),
+    Address(String),
+    // a multisig policy that can never be satisfied
+    Multisig(String),
+}
+
+impl fmt::Display for SigningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SigningError::Io(e) => write!(f, "io error: {e}"),
+            SigningError::Format(e) => write!(f, "invalid key file: {e}"),
+            SigningError::Decrypt => write!(f, "wrong passphrase or corrupted key file"),
+            SigningError::Mnemonic(e) => write!(f, "invalid mnemonic: {e}"),
+            SigningError::Path(path) => write!(f, "invalid derivation path: {path}"),
+            SigningError::Key(e) => write!(f, "invalid key: {e}"),
+            SigningError::Address(e) => write!(f, "invalid address {e}"),
+            SigningError::Multisig(e) => write!(f, "invalid multisig policy: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SigningError {}
+
+impl From<std::io::Error> for SigningError {
+    fn from(e: std::io::Error) -> Self {
+        SigningError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for SigningError {
+    fn from(e: bincode::Error) -> Self {
+        SigningError::Format(e.to_string())
+    }
+}
+
+impl From<bip39::Error> for SigningError {
+    fn from(e: bip39::Error) -> Self {
+        SigningError::Mnemonic(e)
+    }
+}
+
+impl From<secp256k1::Error> for SigningError {
+    fn from(e: secp256k1::Error) -> Self {
+        SigningError::Key(e)
+    }
+}
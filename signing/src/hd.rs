@@ -0,0 +1,145 @@
+// BIP-32 hierarchical deterministic keys. only private derivation, the
+// extended key serialization (xprv/xpub) is not needed here
+use std::fmt;
+use std::str::FromStr;
+
+use hmac::{Hmac, Mac};
+use secp256k1::{Scalar, SecretKey};
+use sha2::Sha512;
+
+use crate::{KeyPair, Result, SigningError};
+
+// indexes from here on are hardened: the child cannot be derived from the
+// parent public key
+pub const HARDENED: u32 = 1 << 31;
+
+// first account, first receiving key
+pub const DEFAULT_PATH: &str = "m/44'/0'/0'/0/0";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtendedKey {
+    pub secret: SecretKey,
+    pub chain_code: [u8; 32],
+    pub depth: u8,
+}
+
+impl ExtendedKey {
+    pub fn master(seed: &[u8]) -> Result<ExtendedKey> {
+        let (secret, chain_code) = hmac_split(b"Bitcoin seed", &[seed])?;
+        Ok(ExtendedKey { secret, chain_code, depth: 0 })
+    }
+
+    pub fn derive_child(&self, index: u32) -> Result<ExtendedKey> {
+        let index_bytes = index.to_be_bytes();
+        let (tweak, chain_code) = if index >= HARDENED {
+            hmac_split(&self.chain_code, &[&[0], &self.secret.secret_bytes(), &index_bytes])?
+        } else {
+            let public = KeyPair::from_secret(self.secret).public.serialize();
+            hmac_split(&self.chain_code, &[&public, &index_bytes])?
+        };
+        // fails with a chance below 2^-127, the spec says to skip to the next index
+        let secret = self.secret.add_tweak(&Scalar::from(tweak))?;
+        Ok(ExtendedKey { secret, chain_code, depth: self.depth.wrapping_add(1) })
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> Result<ExtendedKey> {
+        path.0.iter().try_fold(self.clone(), |key, index| key.derive_child(*index))
+    }
+
+    pub fn key_pair(&self) -> KeyPair {
+        KeyPair::from_secret(self.secret)
+    }
+}
+
+// hmac-sha512, split into a key and a chain code
+fn hmac_split(key: &[u8], data: &[&[u8]]) -> Result<(SecretKey, [u8; 32])> {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("hmac takes any key length");
+    for part in data {
+        mac.update(part);
+    }
+    let output = mac.finalize().into_bytes();
+    let mut chain_code = [0u8; 32];
+    chain_code.copy_from_slice(&output[32..]);
+    Ok((SecretKey::from_slice(&output[..32])?, chain_code))
+}
+
+// `m/44'/0'/0'/0/0`, `h` is accepted for hardened indexes too
+#[derive(Debug, Clone, PartialEq)]
+pub struct DerivationPath(pub Vec<u32>);
+
+impl FromStr for DerivationPath {
+    type Err = SigningError;
+
+    fn from_str(path: &str) -> Result<DerivationPath> {
+        let invalid = || SigningError::Path(path.to_string());
+        let mut parts = path.split('/');
+        if parts.next() != Some("m") {
+            return Err(invalid());
+        }
+        let mut indexes = vec![];
+        for part in parts {
+            let (number, hardened) = match part.strip_suffix(['\'', 'h']) {
+                Some(number) => (number, true),
+                None => (part, false),
+            };
+            let index: u32 = number.parse().map_err(|_| invalid())?;
+            if index >= HARDENED {
+                return Err(invalid());
+            }
+            indexes.push(if hardened { index + HARDENED } else { index });
+        }
+        Ok(DerivationPath(indexes))
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m")?;
+        for index in &self.0 {
+            match index.checked_sub(HARDENED) {
+                Some(index) => write!(f, "/{index}'")?,
+                None => write!(f, "/{index}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test vector 1 of BIP-32: path, chain code, private key
+    const VECTOR_1: [(&str, &str, &str); 6] = [
+        ("m", "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508", "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35"),
+        ("m/0'", "47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141", "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea"),
+        ("m/0'/1", "2a7857631386ba23dacac34180dd1983734e444fdbf774041578e9b6adb37c19", "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368"),
+        ("m/0'/1/2'", "04466b9cc8e161e966409ca52986c584f07e9dc81f735db683c3ff6ec7b1503f", "cbce0d719ecf7431d88e6a89fa1483e02e35092af60c042b1df2ff59fa424dca"),
+        ("m/0'/1/2'/2", "cfb71883f01676f587d023cc53a35bc7f88f724b1f8c2892ac1275ac822a3edd", "0f479245fb19a38a1954c5c7c0ebab2f9bdfd96a17563ef28a6a4b1a2a764ef4"),
+        ("m/0'/1/2'/2/1000000000", "c783e67b921d2beb8f6b389cc646d7263b4145701dadd2161548a8b078e65e9e", "471b76e389e528d6de6d816857e012c5455051cad6660850e58372a6c3e6e7c8"),
+    ];
+
+    #[test]
+    fn matches_the_reference_vectors() {
+        let master = ExtendedKey::master(&hex::decode("000102030405060708090a0b0c0d0e0f").unwrap()).unwrap();
+        for (path, chain_code, secret) in VECTOR_1 {
+            let path: DerivationPath = path.parse().unwrap();
+            let key = master.derive_path(&path).unwrap();
+            assert_eq!(hex::encode(key.chain_code), chain_code, "{path}");
+            assert_eq!(hex::encode(key.secret.secret_bytes()), secret, "{path}");
+            assert_eq!(key.depth as usize, path.0.len());
+        }
+    }
+
+    #[test]
+    fn parses_derivation_paths() {
+        let path: DerivationPath = "m/44h/0'/7".parse().unwrap();
+        assert_eq!(path.0, vec![44 + HARDENED, HARDENED, 7]);
+        assert_eq!(path.to_string(), "m/44'/0'/7");
+        assert_eq!(DEFAULT_PATH.parse::<DerivationPath>().unwrap().to_string(), DEFAULT_PATH);
+
+        for path in ["", "44'/0", "m/", "m/-1", "m/2147483648", "m/1''"] {
+            assert!(path.parse::<DerivationPath>().is_err(), "{path}");
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// passphrase encrypted key files. the layout is the one of the edblock
+// wallet, so a `<address>.key` written here can be copied into its directory
+use std::path::{Path, PathBuf};
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::{KeyPair, Result, SigningError};
+
+pub const KEY_EXTENSION: &str = "key";
+const FORMAT_VERSION: u8 = 1;
+// scrypt cost, 2^15 rounds take around 100ms and 32MB
+pub const DEFAULT_LOG_N: u8 = 15;
+
+#[derive(Serialize, Deserialize)]
+struct SealedKey {
+    version: u8,
+    // hex, readable without the passphrase
+    address: String,
+    log_n: u8,
+    salt: [u8; 16],
+    nonce: [u8; 12],
+    // `keys.bin` bytes encrypted with chacha20poly1305
+    ciphertext: Vec<u8>,
+}
+
+// encrypt `key` with a key derived from the passphrase by scrypt
+pub fn seal(key: &KeyPair, passphrase: &str, log_n: u8) -> Result<Vec<u8>> {
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+
+    let ciphertext = cipher(passphrase, &salt, log_n)?
+        .encrypt(Nonce::from_slice(&nonce), key.to_keys_bin().as_slice())
+        .map_err(|_| SigningError::Format("encryption failed".to_string()))?;
+    let sealed = SealedKey { version: FORMAT_VERSION, address: key.address().to_string(), log_n, salt, nonce, ciphertext };
+    Ok(bincode::serialize(&sealed)?)
+}
+
+pub fn unseal(bytes: &[u8], passphrase: &str) -> Result<KeyPair> {
+    let sealed = read_sealed(bytes)?;
+    let keys_bin = cipher(passphrase, &sealed.salt, sealed.log_n)?
+        .decrypt(Nonce::from_slice(&sealed.nonce), sealed.ciphertext.as_slice())
+        .map_err(|_| SigningError::Decrypt)?;
+    let key = KeyPair::from_keys_bin(&keys_bin)?;
+    if key.address().to_string() != sealed.address {
+        return Err(SigningError::Format(format!("holds the key of {} instead of {}", key.address(), sealed.address)));
+    }
+    Ok(key)
+}
+
+// the address a key file is for, without decrypting it
+pub fn address_of(bytes: &[u8]) -> Result<String> {
+    Ok(read_sealed(bytes)?.address)
+}
+
+// writes `<dir>/<address>.key` and returns its path
+pub fn save(dir: &Path, key: &KeyPair, passphrase: &str, log_n: u8) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(key.address().to_string()).with_extension(KEY_EXTENSION);
+    if path.exists() {
+        return Err(SigningError::Format(format!("{} already exists", path.display())));
+    }
+    // write to a temporary file first so a crash never leaves half a key
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, seal(key, passphrase, log_n)?)?;
+    std::fs::rename(tmp, &path)?;
+    Ok(path)
+}
+
+pub fn load(path: &Path, passphrase: &str) -> Result<KeyPair> {
+    unseal(&std::fs::read(path)?, passphrase)
+}
+
+fn read_sealed(bytes: &[u8]) -> Result<SealedKey> {
+    let sealed: SealedKey = bincode::deserialize(bytes)?;
+    if sealed.version != FORMAT_VERSION {
+        return Err(SigningError::Format(format!("unknown version {}", sealed.version)));
+    }
+    Ok(sealed)
+}
+
+fn cipher(passphrase: &str, salt: &[u8], log_n: u8) -> Result<ChaCha20Poly1305> {
+    let params = scrypt::Params::new(log_n, 8, 1, 32).map_err(|e| SigningError::Format(e.to_string()))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key).map_err(|e| SigningError::Format(e.to_string()))?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // cheap key derivation so the tests stay fast
+    const TEST_LOG_N: u8 = 4;
+
+    #[test]
+    fn keys_round_trip_with_the_passphrase() {
+        let key = KeyPair::generate();
+        let sealed = seal(&key, "correct horse", TEST_LOG_N).unwrap();
+
+        assert_eq!(address_of(&sealed).unwrap(), key.address().to_string());
+        assert_eq!(unseal(&sealed, "correct horse").unwrap(), key);
+        assert!(matches!(unseal(&sealed, "battery staple"), Err(SigningError::Decrypt)));
+        assert!(!sealed.windows(32).any(|w| w == key.secret.secret_bytes()));
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(unseal(&tampered, "correct horse"), Err(SigningError::Decrypt)));
+    }
+
+    #[test]
+    fn saves_one_file_per_address() {
+        let dir = std::env::temp_dir().join(format!("signing-keystore-{}", rand::random::<u64>()));
+        let key = KeyPair::generate();
+        let path = save(&dir, &key, "pass", TEST_LOG_N).unwrap();
+        assert_eq!(path.file_stem().unwrap().to_str().unwrap(), key.address().to_string());
+        assert_eq!(load(&path, "pass").unwrap(), key);
+        assert!(save(&dir, &key, "pass", TEST_LOG_N).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+
+pub mod address;
+pub mod error;
+pub mod hd;
+pub mod keystore;
+pub mod mnemonic;
+pub mod multisig;
+pub mod schnorr;
+pub mod signature;
+
+pub use address::Address;
+pub use error::SigningError;
+pub use signature::{create_message_hash, sign_message, verify_signature};
+
+pub type Result<T> = std::result::Result<T, SigningError>;
+
+// a secp256k1 key pair
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyPair {
+    pub secret: SecretKey,
+    pub public: PublicKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> KeyPair {
+        let (secret, public) = Secp256k1::new().generate_keypair(&mut rand::rngs::OsRng);
+        KeyPair { secret, public }
+    }
+
+    pub fn from_secret(secret: SecretKey) -> KeyPair {
+        let public = PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret);
+        KeyPair { secret, public }
+    }
+
+    pub fn address(&self) -> Address {
+        Address::from_public_key(&self.public)
+    }
+
+    // the `keys.bin` layout: bincode of the secret key, the compressed
+    // public key and the address
+    pub fn to_keys_bin(&self) -> Vec<u8> {
+        let data = (self.secret.secret_bytes().to_vec(), self.public.serialize().to_vec(), self.address().hash().to_vec());
+        bincode::serialize(&data).expect("key serializes")
+    }
+
+    pub fn from_keys_bin(bytes: &[u8]) -> Result<KeyPair> {
+        let (secret, public, address): (Vec<u8>, Vec<u8>, Vec<u8>) = bincode::deserialize(bytes)?;
+        let key = KeyPair::from_secret(SecretKey::from_slice(&secret)?);
+        if key.public.serialize()[..] != public[..] || key.address().hash()[..] != address[..] {
+            return Err(SigningError::Format("public key or address does not match the secret key".to_string()));
+        }
+        Ok(key)
+    }
+}
+
+// last 20 bytes of sha256 over the compressed public key, the hash an
+// `Address` encodes
+pub fn derive_address(public_key: &PublicKey) -> [u8; 20] {
+    let mut hasher = Sha256::new();
+    hasher.update(public_key.serialize());
+    let result = hasher.finalize();
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&result[12..32]);
+    address
+}
@@ -1,93 +1,170 @@
-use std::{fs::File, io::{Read, Write}};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+use secp256k1::PublicKey;
+
+use signing::hd::{DerivationPath, ExtendedKey, DEFAULT_PATH};
+use signing::signature::{self, Encoding};
+use signing::{keystore, mnemonic, KeyPair};
+
+// read instead of prompting, for scripts
+const PASSPHRASE_ENV: &str = "SIGNING_PASSPHRASE";
+
+#[derive(Parser, Debug)]
+#[command(about = "secp256k1 keys for edblock")]
+struct Args {
+    #[arg(long, default_value = ".", help = "directory of the encrypted <address>.key files")]
+    dir: PathBuf,
+    #[command(subcommand)]
+    command: Command,
+}
 
-use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
-use rand::rngs::OsRng;
-use sha2::{Sha256, Digest};
-use bincode::{serialize, deserialize};
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(about = "generate a random key and store it encrypted")]
+    Keygen,
+    #[command(about = "sign a file, or stdin without one, and write the signature to stdout")]
+    Sign {
+        #[arg(long, help = "encrypted key file to sign with")]
+        key: PathBuf,
+        #[arg(long, default_value_t = Encoding::Hex, help = "hex, base64 or raw")]
+        encoding: Encoding,
+        #[arg(long, help = "DER instead of the 64 byte compact signature")]
+        der: bool,
+        file: Option<PathBuf>,
+    },
+    #[command(about = "check the signature of a file, or of stdin without one")]
+    Verify {
+        #[arg(long, help = "compressed or uncompressed public key in hex")]
+        public_key: PublicKey,
+        #[arg(long, help = "file written by sign, in any encoding")]
+        signature: PathBuf,
+        file: Option<PathBuf>,
+    },
+    #[command(about = "print the address and public key of a key file, or the address of a public key")]
+    Address {
+        #[arg(long, conflicts_with = "key_file")]
+        public_key: Option<PublicKey>,
+        #[arg(required_unless_present = "public_key")]
+        key_file: Option<PathBuf>,
+    },
+    #[command(about = "generate a mnemonic, print it for backup and store the key derived from it")]
+    New {
+        #[arg(long, default_value_t = mnemonic::DEFAULT_WORD_COUNT, help = "12, 15, 18, 21 or 24")]
+        words: usize,
+        #[arg(long, default_value = DEFAULT_PATH)]
+        path: DerivationPath,
+    },
+    #[command(about = "store the key derived from a mnemonic read from stdin")]
+    Restore {
+        #[arg(long, default_value = DEFAULT_PATH)]
+        path: DerivationPath,
+    },
+    #[command(about = "encrypt an unencrypted keys.bin")]
+    Import { keys_bin: PathBuf },
+    #[command(about = "decrypt a key file into an unencrypted keys.bin")]
+    Export {
+        key_file: PathBuf,
+        #[arg(long, default_value = "keys.bin")]
+        out: PathBuf,
+    },
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let secp = Secp256k1::new();
-    let mut rng = OsRng::default();
-
-    // Generate key pair
-    let (secret_key, public_key) = secp.generate_keypair(&mut rng);
-
-    // Message to be signed (this could be your transaction data)
-    // let message = "Hello, Blockchain!";
-
-    // Sign the message
-    // let signature = sign_message(&secp, &secret_key, message)?;
-    // let sig_str = signature.to_string();
-    // let sig2 = Signature::from_str(&sig_str).unwrap();
-    // println!("Signature: \n{}\n{}",signature, sig2);
-
-    // Derive address from public key (example using a simple hash, actual derivation may vary)
-    let address = derive_address(&public_key);
-
-    // Serialize the keys and address
-    let private_key_bytes = secret_key[..].to_vec();  // Private key as bytes
-    let public_key_bytes = public_key.serialize().to_vec(); // Public key as bytes
-    let address_bytes = address.to_vec();  // Address as bytes
-
-    // Combine all bytes into a single structure (tuple)
-    let data = (private_key_bytes, public_key_bytes, address_bytes);
-
-    // Open a file to write the data
-    let mut file = File::create("keys.bin")?;
-    
-    // Serialize and write the data to the file
-    let encoded: Vec<u8> = serialize(&data).unwrap();
-    file.write_all(&encoded)?;
-
-    println!("Keys and address written to keys.bin");
-
-    // // Verify the signature
-    // let is_valid = verify_signature(&secp, &public_key, message, &signature)?;
-    // println!("Signature is valid: {}", is_valid);
-
-    // Open the file for reading
-    let mut file = File::open("keys.bin")?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-
-    // Deserialize the data
-    let (private_key_bytes, public_key_bytes, address_bytes): (Vec<u8>, Vec<u8>, Vec<u8>) = deserialize(&buffer).unwrap();
-
-    // Reconstruct the keys from bytes
-    let secret_key2 = SecretKey::from_slice(&private_key_bytes).expect("32 bytes, within curve order");
-    let public_key2 = PublicKey::from_slice(&public_key_bytes).expect("Compressed or uncompressed public key");
+    let args = Args::parse();
+
+    match args.command {
+        Command::Keygen => store(&args.dir, &KeyPair::generate())?,
+        Command::Sign { key, encoding, der, file } => {
+            if is_stdin(file.as_deref()) && std::env::var(PASSPHRASE_ENV).is_err() {
+                return Err(format!("set {PASSPHRASE_ENV} to sign data read from stdin").into());
+            }
+            let key = keystore::load(&key, &passphrase("Passphrase: ")?)?;
+            let signature = signature::sign_hash(&key.secret, &hash_input(file.as_deref())?);
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&signature::encode_signature(&signature, der, encoding))?;
+            if encoding != Encoding::Raw {
+                writeln!(stdout)?;
+            }
+        }
+        Command::Verify { public_key, signature, file } => {
+            let signature = signature::decode_signature(&std::fs::read(signature)?)?;
+            if !signature::verify_hash(&public_key, &hash_input(file.as_deref())?, &signature) {
+                return Err("invalid signature".into());
+            }
+            println!("valid signature of {}", signing::Address::from_public_key(&public_key));
+        }
+        Command::Address { public_key: Some(public_key), .. } => {
+            println!("{}", signing::Address::from_public_key(&public_key));
+        }
+        Command::Address { key_file, .. } => {
+            let key_file = key_file.expect("clap requires a key file without --public-key");
+            let key = keystore::load(&key_file, &passphrase("Passphrase: ")?)?;
+            println!("{}\n{}", key.address(), key.public);
+        }
+        Command::New { words, path } => {
+            let mnemonic = mnemonic::generate(words)?;
+            println!("Write these words down, they are the only backup of the key:\n\n{mnemonic}\n");
+            store_derived(&args.dir, &mnemonic, &path)?;
+        }
+        Command::Restore { path } => {
+            let mnemonic = mnemonic::parse(&prompt("Mnemonic: ")?)?;
+            store_derived(&args.dir, &mnemonic, &path)?;
+        }
+        Command::Import { keys_bin } => {
+            let key = KeyPair::from_keys_bin(&std::fs::read(keys_bin)?)?;
+            store(&args.dir, &key)?;
+        }
+        Command::Export { key_file, out } => {
+            let key = keystore::load(&key_file, &passphrase("Passphrase: ")?)?;
+            std::fs::write(&out, key.to_keys_bin())?;
+            println!("Unencrypted key of {} written to {}", key.address(), out.display());
+        }
+    }
+    Ok(())
+}
 
-    // Print out the data for verification
-    println!("Private Key: \n{:?}\n{:?}\n", secret_key.display_secret(), secret_key2.display_secret());
-    println!("Private Key: \n{:?}\n{:?}\n", public_key, public_key2);
-    println!("Address: \n{:?}\n{:?}\n", address, address_bytes);
+fn store_derived(dir: &Path, mnemonic: &mnemonic::Mnemonic, path: &DerivationPath) -> Result<(), Box<dyn std::error::Error>> {
+    let seed = mnemonic::to_seed(mnemonic, "");
+    let key = ExtendedKey::master(&seed)?.derive_path(path)?.key_pair();
+    store(dir, &key)
+}
 
+fn store(dir: &Path, key: &KeyPair) -> Result<(), Box<dyn std::error::Error>> {
+    let passphrase = passphrase("New passphrase: ")?;
+    if std::env::var(PASSPHRASE_ENV).is_err() && passphrase != prompt("Repeat the passphrase: ")? {
+        return Err("the passphrases do not match".into());
+    }
+    let file = keystore::save(dir, key, &passphrase, keystore::DEFAULT_LOG_N)?;
+    println!("Key of {} written to {}", key.address(), file.display());
     Ok(())
 }
 
-fn sign_message(secp: &Secp256k1<secp256k1::All>, secret_key: &SecretKey, message: &str) -> Result<secp256k1::ecdsa::Signature, Box<dyn std::error::Error>> {
-    let message = create_message_hash(message);
-    let signature = secp.sign_ecdsa(&message, secret_key);
-    Ok(signature)
+fn passphrase(message: &str) -> std::io::Result<String> {
+    match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => Ok(passphrase),
+        Err(_) => prompt(message),
+    }
 }
 
-fn verify_signature(secp: &Secp256k1<secp256k1::All>, public_key: &PublicKey, message: &str, signature: &secp256k1::ecdsa::Signature) -> Result<bool, Box<dyn std::error::Error>> {
-    let message = create_message_hash(message);
-    Ok(secp.verify_ecdsa(&message, signature, public_key).is_ok())
+// a missing file or `-` stands for stdin
+fn is_stdin(file: Option<&Path>) -> bool {
+    file.is_none_or(|file| file == Path::new("-"))
 }
 
-fn create_message_hash(message: &str) -> Message {
-    let mut hasher = Sha256::new();
-    hasher.update(message.as_bytes());
-    let result = hasher.finalize();
-    Message::from_digest_slice(&result).expect("32 bytes")
+fn hash_input(file: Option<&Path>) -> signing::Result<secp256k1::Message> {
+    match file {
+        Some(file) if !is_stdin(Some(file)) => signature::hash_reader(std::fs::File::open(file)?),
+        _ => signature::hash_reader(std::io::stdin().lock()),
+    }
 }
 
-fn derive_address(public_key: &PublicKey) -> [u8; 20] {
-    let mut hasher = Sha256::new();
-    hasher.update(&public_key.serialize());
-    let result = hasher.finalize();
-    let mut address = [0u8; 20];
-    address.copy_from_slice(&result[12..32]);
-    address
-}
\ No newline at end of file
+// prompts go to stderr so they never end up in piped output
+fn prompt(message: &str) -> std::io::Result<String> {
+    eprint!("{message}");
+    std::io::stderr().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
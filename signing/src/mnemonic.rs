@@ -0,0 +1,60 @@
+// BIP-39 backup phrases, english word list only
+pub use bip39::Mnemonic;
+use rand::RngCore;
+
+use crate::Result;
+
+pub const DEFAULT_WORD_COUNT: usize = 12;
+
+// a new phrase of 12, 15, 18, 21 or 24 words from os randomness
+pub fn generate(word_count: usize) -> Result<Mnemonic> {
+    if !word_count.is_multiple_of(3) {
+        return Err(bip39::Error::BadWordCount(word_count).into());
+    }
+    // every 3 words hold 32 bits of entropy, the rest is the checksum
+    let mut entropy = vec![0u8; word_count / 3 * 4];
+    rand::rngs::OsRng.fill_bytes(&mut entropy);
+    Ok(Mnemonic::from_entropy(&entropy)?)
+}
+
+// checks the words and the checksum. case and extra spaces do not matter
+pub fn parse(phrase: &str) -> Result<Mnemonic> {
+    let phrase = phrase.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    Ok(Mnemonic::parse_normalized(&phrase)?)
+}
+
+// the 64 byte seed the master key is derived from. the optional passphrase
+// gives a completely different wallet for the same words
+pub fn to_seed(mnemonic: &Mnemonic, passphrase: &str) -> [u8; 64] {
+    mnemonic.to_seed(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // from the BIP-39 reference vectors
+    #[test]
+    fn matches_the_reference_vectors() {
+        let mnemonic = Mnemonic::from_entropy(&[0u8; 16]).unwrap();
+        assert_eq!(mnemonic.to_string(), "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about");
+        assert_eq!(
+            hex::encode(to_seed(&mnemonic, "TREZOR")),
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+        );
+
+        let mnemonic = Mnemonic::from_entropy(&[0x7f; 16]).unwrap();
+        assert_eq!(mnemonic.to_string(), "legal winner thank year wave sausage worth useful legal winner thank yellow");
+    }
+
+    #[test]
+    fn rejects_typos() {
+        let phrase = generate(24).unwrap().to_string();
+        assert_eq!(phrase.split(' ').count(), 24);
+        assert_eq!(parse(&format!("  {}\n", phrase.to_uppercase())).unwrap().to_string(), phrase);
+
+        assert!(parse("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon").is_err());
+        assert!(parse("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abuot").is_err());
+        assert!(generate(13).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+// m-of-n ECDSA: a message is approved once `threshold` distinct keys of the
+// policy have signed it
+use std::collections::HashSet;
+
+use secp256k1::ecdsa::Signature;
+use secp256k1::{Message, PublicKey, Secp256k1};
+
+use crate::{Result, SigningError};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultisigPolicy {
+    threshold: usize,
+    keys: Vec<PublicKey>,
+}
+
+impl MultisigPolicy {
+    pub fn new(threshold: usize, keys: Vec<PublicKey>) -> Result<MultisigPolicy> {
+        if threshold == 0 || threshold > keys.len() {
+            return Err(SigningError::Multisig(format!("cannot require {threshold} of {} keys", keys.len())));
+        }
+        if keys.iter().collect::<HashSet<_>>().len() != keys.len() {
+            return Err(SigningError::Multisig("the same key is listed twice".to_string()));
+        }
+        Ok(MultisigPolicy { threshold, keys })
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn keys(&self) -> &[PublicKey] {
+        &self.keys
+    }
+
+    // number of distinct keys with a valid signature among `signatures`, in
+    // any order. a key is only counted once however often it signed
+    pub fn count_signers(&self, message: &Message, signatures: &[Signature]) -> usize {
+        let secp = Secp256k1::verification_only();
+        let mut signed = vec![false; self.keys.len()];
+        for signature in signatures {
+            let signer = (0..self.keys.len())
+                .find(|&i| !signed[i] && secp.verify_ecdsa(message, signature, &self.keys[i]).is_ok());
+            if let Some(i) = signer {
+                signed[i] = true;
+            }
+        }
+        signed.into_iter().filter(|signed| *signed).count()
+    }
+
+    pub fn verify(&self, message: &Message, signatures: &[Signature]) -> bool {
+        self.count_signers(message, signatures) >= self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::sign_hash;
+    use crate::{create_message_hash, KeyPair};
+
+    #[test]
+    fn requires_the_threshold_of_distinct_keys() {
+        let keys: Vec<KeyPair> = (0..3).map(|_| KeyPair::generate()).collect();
+        let policy = MultisigPolicy::new(2, keys.iter().map(|key| key.public).collect()).unwrap();
+        let message = create_message_hash(b"pay 10 to bob");
+        let sign = |i: usize| sign_hash(&keys[i].secret, &message);
+
+        assert!(policy.verify(&message, &[sign(2), sign(0)]));
+        assert!(policy.verify(&message, &[sign(0), sign(1), sign(2)]));
+        assert!(!policy.verify(&message, &[sign(1)]));
+        // one key signing twice is still one approval
+        assert!(!policy.verify(&message, &[sign(1), sign(1)]));
+
+        let outsider = sign_hash(&KeyPair::generate().secret, &message);
+        assert!(!policy.verify(&message, &[sign(0), outsider]));
+        assert!(!policy.verify(&create_message_hash(b"pay 99 to bob"), &[sign(0), sign(1)]));
+    }
+
+    #[test]
+    fn rejects_impossible_policies() {
+        let key = KeyPair::generate().public;
+        assert!(MultisigPolicy::new(0, vec![key]).is_err());
+        assert!(MultisigPolicy::new(2, vec![key]).is_err());
+        assert!(MultisigPolicy::new(1, vec![key, key]).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+// BIP-340 schnorr signatures over x-only public keys
+use k256::elliptic_curve::bigint::U256;
+use k256::elliptic_curve::ff::{Field, PrimeField};
+use k256::elliptic_curve::group::Group;
+use k256::elliptic_curve::ops::{LinearCombinationExt, Reduce};
+use k256::elliptic_curve::point::DecompactPoint;
+use k256::{AffinePoint, FieldBytes, ProjectivePoint, Scalar};
+use secp256k1::schnorr::Signature;
+use secp256k1::{Keypair, Message, Secp256k1, SecretKey, XOnlyPublicKey};
+use sha2::{Digest, Sha256};
+
+pub fn x_only_public_key(secret_key: &SecretKey) -> XOnlyPublicKey {
+    Keypair::from_secret_key(&Secp256k1::signing_only(), secret_key).x_only_public_key().0
+}
+
+// signs with fresh auxiliary randomness from the os, as BIP-340 recommends
+pub fn sign_schnorr(secret_key: &SecretKey, message: &Message) -> Signature {
+    let secp = Secp256k1::signing_only();
+    secp.sign_schnorr_with_rng(message, &Keypair::from_secret_key(&secp, secret_key), &mut rand::rngs::OsRng)
+}
+
+// deterministic for a given `aux`, for the reference vectors
+pub fn sign_schnorr_with_aux(secret_key: &SecretKey, message: &Message, aux: &[u8; 32]) -> Signature {
+    let secp = Secp256k1::signing_only();
+    secp.sign_schnorr_with_aux_rand(message, &Keypair::from_secret_key(&secp, secret_key), aux)
+}
+
+pub fn verify_schnorr(public_key: &XOnlyPublicKey, message: &Message, signature: &Signature) -> bool {
+    Secp256k1::verification_only().verify_schnorr(signature, message, public_key).is_ok()
+}
+
+// checks every (key, message, signature) and returns the indexes of the bad
+// ones. the whole batch is checked at once as a random linear combination of
+// the BIP-340 equations s*G = R + e*P, with one multi-scalar multiplication.
+// only a failing batch is verified one signature at a time, to find the bad ones
+pub fn verify_batch(batch: &[(XOnlyPublicKey, Message, Signature)]) -> Result<(), Vec<usize>> {
+    if batch_holds(batch) {
+        return Ok(());
+    }
+    let secp = Secp256k1::verification_only();
+    let invalid: Vec<usize> = batch.iter().enumerate()
+        .filter(|(_, (public_key, message, signature))| secp.verify_schnorr(signature, message, public_key).is_err())
+        .map(|(i, _)| i)
+        .collect();
+    match invalid.is_empty() {
+        true => Ok(()),
+        false => Err(invalid),
+    }
+}
+
+// sum of a_i * (R_i + e_i*P_i - s_i*G) is the identity. a_0 is 1 and the
+// others are random, so a forged signature cannot cancel out another one
+fn batch_holds(batch: &[(XOnlyPublicKey, Message, Signature)]) -> bool {
+    let mut terms = Vec::with_capacity(2 * batch.len() + 1);
+    let mut s_sum = Scalar::ZERO;
+    // the challenge is a BIP-340 tagged hash, the tag hashed twice in front
+    let tag = Sha256::digest(b"BIP0340/challenge");
+    for (i, (public_key, message, signature)) in batch.iter().enumerate() {
+        let signature = signature.serialize();
+        let (r, s) = signature.split_at(32);
+        // lift_x gives the point with an even y, as BIP-340 requires
+        let (Some(big_r), Some(p), Some(s)) = (
+            Option::<AffinePoint>::from(AffinePoint::decompact(FieldBytes::from_slice(r))),
+            Option::<AffinePoint>::from(AffinePoint::decompact(FieldBytes::from_slice(&public_key.serialize()))),
+            Option::<Scalar>::from(Scalar::from_repr(*FieldBytes::from_slice(s))),
+        ) else {
+            return false;
+        };
+        let challenge = Sha256::new()
+            .chain_update(tag)
+            .chain_update(tag)
+            .chain_update(r)
+            .chain_update(public_key.serialize())
+            .chain_update(message.as_ref())
+            .finalize();
+        let e = <Scalar as Reduce<U256>>::reduce_bytes(FieldBytes::from_slice(&challenge));
+        let a = if i == 0 { Scalar::ONE } else { Scalar::random(&mut rand::rngs::OsRng) };
+        terms.push((ProjectivePoint::from(big_r), a));
+        terms.push((ProjectivePoint::from(p), a * e));
+        s_sum += a * s;
+    }
+    terms.push((ProjectivePoint::GENERATOR, -s_sum));
+    ProjectivePoint::lincomb_ext(terms.as_slice()).is_identity().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_message_hash;
+    use crate::KeyPair;
+
+    fn bytes32(text: &str) -> [u8; 32] {
+        hex::decode(text).unwrap().try_into().unwrap()
+    }
+
+    // vectors 0 and 1 of BIP-340: secret key, public key, aux, message, signature
+    const VECTORS: [(&str, &str, &str, &str, &str); 2] = [
+        (
+            "0000000000000000000000000000000000000000000000000000000000000003",
+            "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "e907831f80848d1069a5371b402410364bdf1c5f8307b0084c55f1ce2dca821525f66a4a85ea8b71e482a74f382d2ce5ebeee8fdb2172f477df4900d310536c0",
+        ),
+        (
+            "b7e151628aed2a6abf7158809cf4f3c762e7160f38b4da56a784d9045190cfef",
+            "dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659",
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89",
+            "6896bd60eeae296db48a229ff71dfe071bde413e6d43f917dc8dcf8c78de33418906d11ac976abccb20b091292bff4ea897efcb639ea871cfa95f6de339e4b0a",
+        ),
+    ];
+
+    #[test]
+    fn matches_the_reference_vectors() {
+        for (secret, public, aux, message, signature) in VECTORS {
+            let secret = SecretKey::from_slice(&bytes32(secret)).unwrap();
+            let message = Message::from_digest(bytes32(message));
+            let signed = sign_schnorr_with_aux(&secret, &message, &bytes32(aux));
+            assert_eq!(hex::encode(x_only_public_key(&secret).serialize()), public);
+            assert_eq!(hex::encode(signed.serialize()), signature);
+            assert!(verify_schnorr(&x_only_public_key(&secret), &message, &signed));
+        }
+    }
+
+    #[test]
+    fn batch_reports_the_bad_signatures() {
+        let mut batch = vec![];
+        for i in 0..5u8 {
+            let key = KeyPair::generate();
+            let message = create_message_hash(&[i]);
+            batch.push((x_only_public_key(&key.secret), message, sign_schnorr(&key.secret, &message)));
+        }
+        assert_eq!(verify_batch(&batch), Ok(()));
+        assert_eq!(verify_batch(&[]), Ok(()));
+
+        assert!(batch_holds(&batch));
+
+        batch[1].1 = create_message_hash(b"something else");
+        batch[3].0 = x_only_public_key(&KeyPair::generate().secret);
+        assert_eq!(verify_batch(&batch), Err(vec![1, 3]));
+        assert!(!batch_holds(&batch));
+    }
+
+    #[test]
+    fn batch_agrees_with_the_reference_vectors() {
+        let batch: Vec<_> = VECTORS.iter().map(|(secret, _, aux, message, _)| {
+            let secret = SecretKey::from_slice(&bytes32(secret)).unwrap();
+            let message = Message::from_digest(bytes32(message));
+            (x_only_public_key(&secret), message, sign_schnorr_with_aux(&secret, &message, &bytes32(aux)))
+        }).collect();
+        assert!(batch_holds(&batch));
+
+        // a signature of another message under the same key
+        let mut swapped = batch.clone();
+        swapped[0].2 = batch[1].2;
+        assert!(!batch_holds(&swapped));
+        assert_eq!(verify_batch(&swapped), Err(vec![0]));
+    }
+}
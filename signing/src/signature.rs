@@ -0,0 +1,141 @@
+// ECDSA signatures over the sha256 of arbitrary data
+use std::fmt;
+use std::io::Read;
+use std::str::FromStr;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use secp256k1::ecdsa::Signature;
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+
+use crate::{Result, SigningError};
+
+pub fn create_message_hash(data: &[u8]) -> Message {
+    Message::from_digest(Sha256::digest(data).into())
+}
+
+// hashes everything `reader` yields, for files and stdin
+pub fn hash_reader<R: Read>(mut reader: R) -> Result<Message> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 8192];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(Message::from_digest(hasher.finalize().into()))
+}
+
+pub fn sign_hash(secret_key: &SecretKey, message: &Message) -> Signature {
+    Secp256k1::signing_only().sign_ecdsa(message, secret_key)
+}
+
+pub fn verify_hash(public_key: &PublicKey, message: &Message, signature: &Signature) -> bool {
+    Secp256k1::verification_only().verify_ecdsa(message, signature, public_key).is_ok()
+}
+
+pub fn sign_message(secret_key: &SecretKey, data: &[u8]) -> Signature {
+    sign_hash(secret_key, &create_message_hash(data))
+}
+
+pub fn verify_signature(public_key: &PublicKey, data: &[u8], signature: &Signature) -> bool {
+    verify_hash(public_key, &create_message_hash(data), signature)
+}
+
+// how signatures are written out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Hex,
+    Base64,
+    // the bytes as they are
+    Raw,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(encoding: &str) -> std::result::Result<Encoding, String> {
+        match encoding {
+            "hex" => Ok(Encoding::Hex),
+            "base64" => Ok(Encoding::Base64),
+            "raw" => Ok(Encoding::Raw),
+            _ => Err(format!("unknown encoding {encoding}, expected hex, base64 or raw")),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Encoding::Hex => "hex",
+            Encoding::Base64 => "base64",
+            Encoding::Raw => "raw",
+        };
+        write!(f, "{name}")
+    }
+}
+
+// the 64 byte compact form, or DER when `der` is set
+pub fn encode_signature(signature: &Signature, der: bool, encoding: Encoding) -> Vec<u8> {
+    let bytes = match der {
+        true => signature.serialize_der().to_vec(),
+        false => signature.serialize_compact().to_vec(),
+    };
+    match encoding {
+        Encoding::Hex => hex::encode(bytes).into_bytes(),
+        Encoding::Base64 => BASE64.encode(bytes).into_bytes(),
+        Encoding::Raw => bytes,
+    }
+}
+
+// reads anything `encode_signature` writes, whatever the options were
+pub fn decode_signature(bytes: &[u8]) -> Result<Signature> {
+    let text = std::str::from_utf8(bytes).map(str::trim).unwrap_or_default();
+    let candidates = [hex::decode(text).ok(), BASE64.decode(text).ok(), Some(bytes.to_vec())];
+    candidates.into_iter().flatten()
+        .find_map(|bytes| match bytes.len() {
+            64 => Signature::from_compact(&bytes).ok(),
+            _ => Signature::from_der(&bytes).ok(),
+        })
+        .ok_or_else(|| SigningError::Format("not a compact or DER signature".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KeyPair;
+
+    #[test]
+    fn signs_and_verifies_data() {
+        let key = KeyPair::generate();
+        let data = b"Hello, Blockchain!";
+        let signature = sign_message(&key.secret, data);
+
+        assert!(verify_signature(&key.public, data, &signature));
+        assert!(!verify_signature(&key.public, b"Hello, Blockchain?", &signature));
+        assert!(!verify_signature(&KeyPair::generate().public, data, &signature));
+
+        // streaming gives the same hash as hashing at once
+        let large = vec![7u8; 100_000];
+        assert_eq!(hash_reader(large.as_slice()).unwrap(), create_message_hash(&large));
+    }
+
+    #[test]
+    fn every_encoding_decodes() {
+        let signature = sign_message(&KeyPair::generate().secret, b"data");
+        for der in [false, true] {
+            for encoding in [Encoding::Hex, Encoding::Base64, Encoding::Raw] {
+                let encoded = encode_signature(&signature, der, encoding);
+                assert_eq!(decode_signature(&encoded).unwrap(), signature, "{encoding} der={der}");
+                assert_eq!(encoding.to_string().parse::<Encoding>().unwrap(), encoding);
+            }
+        }
+        let mut with_newline = encode_signature(&signature, false, Encoding::Hex);
+        with_newline.push(b'\n');
+        assert_eq!(decode_signature(&with_newline).unwrap(), signature);
+        assert!(decode_signature(b"not a signature").is_err());
+    }
+}